@@ -0,0 +1,670 @@
+//! Binary events emitted by the program through `sol_log_data`.
+//!
+//! Every event is encoded as `[version, kind, payload...]` with little-endian
+//! integers, and shows up in the transaction logs as a `Program data:` line.
+//! Off-chain clients decode them with `decode_logs`, behind the `client`
+//! feature.
+
+#[cfg(feature = "client")]
+use base64::{engine::general_purpose::STANDARD, Engine};
+use solana_program::{log::sol_log_data, program_error::ProgramError, pubkey::Pubkey};
+
+pub const EVENT_VERSION: u8 = 1;
+
+#[cfg(feature = "client")]
+const PROGRAM_DATA_PREFIX: &str = "Program data: ";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    StoreInitialized {
+        store: Pubkey,
+        mint: Pubkey,
+        owner: Pubkey,
+        price: u64,
+    },
+    PriceUpdated {
+        store: Pubkey,
+        old_price: u64,
+        new_price: u64,
+    },
+    InventoryDeposited {
+        store: Pubkey,
+        amount: u64,
+        supply: u64,
+    },
+    Sold {
+        store: Pubkey,
+        seller: Pubkey,
+        quantity: u64,
+        payment: u64,
+    },
+    Bought {
+        store: Pubkey,
+        buyer: Pubkey,
+        quantity: u64,
+        cost: u64,
+    },
+    RoleGranted {
+        store: Pubkey,
+        holder: Pubkey,
+        role: u8,
+    },
+    RoleRevoked {
+        store: Pubkey,
+        holder: Pubkey,
+        role: u8,
+    },
+    OwnershipProposed {
+        store: Pubkey,
+        owner: Pubkey,
+        pending_owner: Pubkey,
+    },
+    OwnershipTransferred {
+        store: Pubkey,
+        previous_owner: Pubkey,
+        new_owner: Pubkey,
+    },
+    OwnershipTransferCancelled {
+        store: Pubkey,
+    },
+    StorePauseChanged {
+        store: Pubkey,
+        paused: bool,
+    },
+    ConfigInitialized {
+        admin: Pubkey,
+    },
+    GlobalPauseChanged {
+        paused: bool,
+    },
+    StoreClosed {
+        store: Pubkey,
+        destination: Pubkey,
+        returned_inventory: u64,
+    },
+    ProceedsWithdrawn {
+        store: Pubkey,
+        destination: Pubkey,
+        amount: u64,
+    },
+    ConfigUpdated {
+        admin: Pubkey,
+        default_price: u64,
+        fee_bps: u16,
+        fee_recipient: Pubkey,
+        max_quantity: u64,
+        paused: bool,
+    },
+    PriceGuardrailsUpdated {
+        store: Pubkey,
+        min_price: u64,
+        max_price: u64,
+        max_change_bps: u16,
+        window_change_bps: u16,
+        window_seconds: u64,
+    },
+    PriceHistoryInitialized {
+        store: Pubkey,
+        history: Pubkey,
+        capacity: u16,
+    },
+    TransferFeeModeUpdated {
+        store: Pubkey,
+        mode: u8,
+    },
+    RegistryEntryUpdated {
+        store: Pubkey,
+        index: u64,
+        owner: Pubkey,
+        status: u8,
+    },
+    StoreMetadataUpdated {
+        store: Pubkey,
+        metadata: Pubkey,
+        size: u64,
+    },
+    ReceiptIssued {
+        store: Pubkey,
+        receipt: Pubkey,
+        buyer: Pubkey,
+        number: u64,
+    },
+    RefundWindowUpdated {
+        store: Pubkey,
+        slots: u64,
+    },
+    Refunded {
+        store: Pubkey,
+        receipt: Pubkey,
+        buyer: Pubkey,
+        quantity: u64,
+        amount: u64,
+    },
+    EscrowSettingsUpdated {
+        store: Pubkey,
+        timeout: u64,
+        arbiter: Pubkey,
+    },
+    EscrowUpdated {
+        store: Pubkey,
+        escrow: Pubkey,
+        status: u8,
+        amount: u64,
+    },
+    ConsignmentModeUpdated {
+        store: Pubkey,
+        enabled: bool,
+    },
+    Listed {
+        store: Pubkey,
+        seller: Pubkey,
+        price: u64,
+        quantity: u64,
+    },
+    Delisted {
+        store: Pubkey,
+        seller: Pubkey,
+        quantity: u64,
+    },
+    ListingFilled {
+        store: Pubkey,
+        seller: Pubkey,
+        buyer: Pubkey,
+        quantity: u64,
+        cost: u64,
+    },
+    SellerProceedsWithdrawn {
+        store: Pubkey,
+        seller: Pubkey,
+        amount: u64,
+    },
+    MultisigInitialized {
+        multisig: Pubkey,
+        m: u8,
+        n: u8,
+    },
+}
+
+impl Event {
+    pub fn pack(&self) -> Vec<u8> {
+        let mut buf = vec![EVENT_VERSION];
+        match self {
+            Event::StoreInitialized { store, mint, owner, price } => {
+                buf.push(0);
+                buf.extend_from_slice(store.as_ref());
+                buf.extend_from_slice(mint.as_ref());
+                buf.extend_from_slice(owner.as_ref());
+                buf.extend_from_slice(&price.to_le_bytes());
+            }
+            Event::PriceUpdated { store, old_price, new_price } => {
+                buf.push(1);
+                buf.extend_from_slice(store.as_ref());
+                buf.extend_from_slice(&old_price.to_le_bytes());
+                buf.extend_from_slice(&new_price.to_le_bytes());
+            }
+            Event::InventoryDeposited { store, amount, supply } => {
+                buf.push(2);
+                buf.extend_from_slice(store.as_ref());
+                buf.extend_from_slice(&amount.to_le_bytes());
+                buf.extend_from_slice(&supply.to_le_bytes());
+            }
+            Event::Sold { store, seller, quantity, payment } => {
+                buf.push(3);
+                buf.extend_from_slice(store.as_ref());
+                buf.extend_from_slice(seller.as_ref());
+                buf.extend_from_slice(&quantity.to_le_bytes());
+                buf.extend_from_slice(&payment.to_le_bytes());
+            }
+            Event::Bought { store, buyer, quantity, cost } => {
+                buf.push(4);
+                buf.extend_from_slice(store.as_ref());
+                buf.extend_from_slice(buyer.as_ref());
+                buf.extend_from_slice(&quantity.to_le_bytes());
+                buf.extend_from_slice(&cost.to_le_bytes());
+            }
+            Event::RoleGranted { store, holder, role } => {
+                buf.push(5);
+                buf.extend_from_slice(store.as_ref());
+                buf.extend_from_slice(holder.as_ref());
+                buf.push(*role);
+            }
+            Event::RoleRevoked { store, holder, role } => {
+                buf.push(6);
+                buf.extend_from_slice(store.as_ref());
+                buf.extend_from_slice(holder.as_ref());
+                buf.push(*role);
+            }
+            Event::OwnershipProposed { store, owner, pending_owner } => {
+                buf.push(7);
+                buf.extend_from_slice(store.as_ref());
+                buf.extend_from_slice(owner.as_ref());
+                buf.extend_from_slice(pending_owner.as_ref());
+            }
+            Event::OwnershipTransferred { store, previous_owner, new_owner } => {
+                buf.push(8);
+                buf.extend_from_slice(store.as_ref());
+                buf.extend_from_slice(previous_owner.as_ref());
+                buf.extend_from_slice(new_owner.as_ref());
+            }
+            Event::OwnershipTransferCancelled { store } => {
+                buf.push(9);
+                buf.extend_from_slice(store.as_ref());
+            }
+            Event::StorePauseChanged { store, paused } => {
+                buf.push(10);
+                buf.extend_from_slice(store.as_ref());
+                buf.push(*paused as u8);
+            }
+            Event::ConfigInitialized { admin } => {
+                buf.push(11);
+                buf.extend_from_slice(admin.as_ref());
+            }
+            Event::GlobalPauseChanged { paused } => {
+                buf.push(12);
+                buf.push(*paused as u8);
+            }
+            Event::StoreClosed { store, destination, returned_inventory } => {
+                buf.push(13);
+                buf.extend_from_slice(store.as_ref());
+                buf.extend_from_slice(destination.as_ref());
+                buf.extend_from_slice(&returned_inventory.to_le_bytes());
+            }
+            Event::ProceedsWithdrawn { store, destination, amount } => {
+                buf.push(14);
+                buf.extend_from_slice(store.as_ref());
+                buf.extend_from_slice(destination.as_ref());
+                buf.extend_from_slice(&amount.to_le_bytes());
+            }
+            Event::ConfigUpdated {
+                admin,
+                default_price,
+                fee_bps,
+                fee_recipient,
+                max_quantity,
+                paused,
+            } => {
+                buf.push(15);
+                buf.extend_from_slice(admin.as_ref());
+                buf.extend_from_slice(&default_price.to_le_bytes());
+                buf.extend_from_slice(&fee_bps.to_le_bytes());
+                buf.extend_from_slice(fee_recipient.as_ref());
+                buf.extend_from_slice(&max_quantity.to_le_bytes());
+                buf.push(*paused as u8);
+            }
+            Event::PriceGuardrailsUpdated {
+                store,
+                min_price,
+                max_price,
+                max_change_bps,
+                window_change_bps,
+                window_seconds,
+            } => {
+                buf.push(16);
+                buf.extend_from_slice(store.as_ref());
+                buf.extend_from_slice(&min_price.to_le_bytes());
+                buf.extend_from_slice(&max_price.to_le_bytes());
+                buf.extend_from_slice(&max_change_bps.to_le_bytes());
+                buf.extend_from_slice(&window_change_bps.to_le_bytes());
+                buf.extend_from_slice(&window_seconds.to_le_bytes());
+            }
+            Event::PriceHistoryInitialized { store, history, capacity } => {
+                buf.push(17);
+                buf.extend_from_slice(store.as_ref());
+                buf.extend_from_slice(history.as_ref());
+                buf.extend_from_slice(&capacity.to_le_bytes());
+            }
+            Event::TransferFeeModeUpdated { store, mode } => {
+                buf.push(18);
+                buf.extend_from_slice(store.as_ref());
+                buf.push(*mode);
+            }
+            Event::RegistryEntryUpdated { store, index, owner, status } => {
+                buf.push(19);
+                buf.extend_from_slice(store.as_ref());
+                buf.extend_from_slice(&index.to_le_bytes());
+                buf.extend_from_slice(owner.as_ref());
+                buf.push(*status);
+            }
+            Event::StoreMetadataUpdated { store, metadata, size } => {
+                buf.push(20);
+                buf.extend_from_slice(store.as_ref());
+                buf.extend_from_slice(metadata.as_ref());
+                buf.extend_from_slice(&size.to_le_bytes());
+            }
+            Event::ReceiptIssued { store, receipt, buyer, number } => {
+                buf.push(21);
+                buf.extend_from_slice(store.as_ref());
+                buf.extend_from_slice(receipt.as_ref());
+                buf.extend_from_slice(buyer.as_ref());
+                buf.extend_from_slice(&number.to_le_bytes());
+            }
+            Event::RefundWindowUpdated { store, slots } => {
+                buf.push(22);
+                buf.extend_from_slice(store.as_ref());
+                buf.extend_from_slice(&slots.to_le_bytes());
+            }
+            Event::Refunded { store, receipt, buyer, quantity, amount } => {
+                buf.push(23);
+                buf.extend_from_slice(store.as_ref());
+                buf.extend_from_slice(receipt.as_ref());
+                buf.extend_from_slice(buyer.as_ref());
+                buf.extend_from_slice(&quantity.to_le_bytes());
+                buf.extend_from_slice(&amount.to_le_bytes());
+            }
+            Event::EscrowSettingsUpdated { store, timeout, arbiter } => {
+                buf.push(24);
+                buf.extend_from_slice(store.as_ref());
+                buf.extend_from_slice(&timeout.to_le_bytes());
+                buf.extend_from_slice(arbiter.as_ref());
+            }
+            Event::EscrowUpdated { store, escrow, status, amount } => {
+                buf.push(25);
+                buf.extend_from_slice(store.as_ref());
+                buf.extend_from_slice(escrow.as_ref());
+                buf.push(*status);
+                buf.extend_from_slice(&amount.to_le_bytes());
+            }
+            Event::ConsignmentModeUpdated { store, enabled } => {
+                buf.push(26);
+                buf.extend_from_slice(store.as_ref());
+                buf.push(*enabled as u8);
+            }
+            Event::Listed { store, seller, price, quantity } => {
+                buf.push(27);
+                buf.extend_from_slice(store.as_ref());
+                buf.extend_from_slice(seller.as_ref());
+                buf.extend_from_slice(&price.to_le_bytes());
+                buf.extend_from_slice(&quantity.to_le_bytes());
+            }
+            Event::Delisted { store, seller, quantity } => {
+                buf.push(28);
+                buf.extend_from_slice(store.as_ref());
+                buf.extend_from_slice(seller.as_ref());
+                buf.extend_from_slice(&quantity.to_le_bytes());
+            }
+            Event::ListingFilled { store, seller, buyer, quantity, cost } => {
+                buf.push(29);
+                buf.extend_from_slice(store.as_ref());
+                buf.extend_from_slice(seller.as_ref());
+                buf.extend_from_slice(buyer.as_ref());
+                buf.extend_from_slice(&quantity.to_le_bytes());
+                buf.extend_from_slice(&cost.to_le_bytes());
+            }
+            Event::SellerProceedsWithdrawn { store, seller, amount } => {
+                buf.push(30);
+                buf.extend_from_slice(store.as_ref());
+                buf.extend_from_slice(seller.as_ref());
+                buf.extend_from_slice(&amount.to_le_bytes());
+            }
+            Event::MultisigInitialized { multisig, m, n } => {
+                buf.push(31);
+                buf.extend_from_slice(multisig.as_ref());
+                buf.push(*m);
+                buf.push(*n);
+            }
+        }
+        buf
+    }
+
+    pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
+        let (&version, rest) = input.split_first().ok_or(ProgramError::InvalidAccountData)?;
+        if version != EVENT_VERSION {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let (&kind, rest) = rest.split_first().ok_or(ProgramError::InvalidAccountData)?;
+        let mut reader = Reader(rest);
+
+        let event = match kind {
+            0 => Event::StoreInitialized {
+                store: reader.pubkey()?,
+                mint: reader.pubkey()?,
+                owner: reader.pubkey()?,
+                price: reader.u64()?,
+            },
+            1 => Event::PriceUpdated {
+                store: reader.pubkey()?,
+                old_price: reader.u64()?,
+                new_price: reader.u64()?,
+            },
+            2 => Event::InventoryDeposited {
+                store: reader.pubkey()?,
+                amount: reader.u64()?,
+                supply: reader.u64()?,
+            },
+            3 => Event::Sold {
+                store: reader.pubkey()?,
+                seller: reader.pubkey()?,
+                quantity: reader.u64()?,
+                payment: reader.u64()?,
+            },
+            4 => Event::Bought {
+                store: reader.pubkey()?,
+                buyer: reader.pubkey()?,
+                quantity: reader.u64()?,
+                cost: reader.u64()?,
+            },
+            5 => Event::RoleGranted {
+                store: reader.pubkey()?,
+                holder: reader.pubkey()?,
+                role: reader.u8()?,
+            },
+            6 => Event::RoleRevoked {
+                store: reader.pubkey()?,
+                holder: reader.pubkey()?,
+                role: reader.u8()?,
+            },
+            7 => Event::OwnershipProposed {
+                store: reader.pubkey()?,
+                owner: reader.pubkey()?,
+                pending_owner: reader.pubkey()?,
+            },
+            8 => Event::OwnershipTransferred {
+                store: reader.pubkey()?,
+                previous_owner: reader.pubkey()?,
+                new_owner: reader.pubkey()?,
+            },
+            9 => Event::OwnershipTransferCancelled {
+                store: reader.pubkey()?,
+            },
+            10 => Event::StorePauseChanged {
+                store: reader.pubkey()?,
+                paused: reader.bool()?,
+            },
+            11 => Event::ConfigInitialized {
+                admin: reader.pubkey()?,
+            },
+            12 => Event::GlobalPauseChanged {
+                paused: reader.bool()?,
+            },
+            13 => Event::StoreClosed {
+                store: reader.pubkey()?,
+                destination: reader.pubkey()?,
+                returned_inventory: reader.u64()?,
+            },
+            14 => Event::ProceedsWithdrawn {
+                store: reader.pubkey()?,
+                destination: reader.pubkey()?,
+                amount: reader.u64()?,
+            },
+            15 => Event::ConfigUpdated {
+                admin: reader.pubkey()?,
+                default_price: reader.u64()?,
+                fee_bps: reader.u16()?,
+                fee_recipient: reader.pubkey()?,
+                max_quantity: reader.u64()?,
+                paused: reader.bool()?,
+            },
+            16 => Event::PriceGuardrailsUpdated {
+                store: reader.pubkey()?,
+                min_price: reader.u64()?,
+                max_price: reader.u64()?,
+                max_change_bps: reader.u16()?,
+                window_change_bps: reader.u16()?,
+                window_seconds: reader.u64()?,
+            },
+            17 => Event::PriceHistoryInitialized {
+                store: reader.pubkey()?,
+                history: reader.pubkey()?,
+                capacity: reader.u16()?,
+            },
+            18 => Event::TransferFeeModeUpdated {
+                store: reader.pubkey()?,
+                mode: reader.u8()?,
+            },
+            19 => Event::RegistryEntryUpdated {
+                store: reader.pubkey()?,
+                index: reader.u64()?,
+                owner: reader.pubkey()?,
+                status: reader.u8()?,
+            },
+            20 => Event::StoreMetadataUpdated {
+                store: reader.pubkey()?,
+                metadata: reader.pubkey()?,
+                size: reader.u64()?,
+            },
+            21 => Event::ReceiptIssued {
+                store: reader.pubkey()?,
+                receipt: reader.pubkey()?,
+                buyer: reader.pubkey()?,
+                number: reader.u64()?,
+            },
+            22 => Event::RefundWindowUpdated {
+                store: reader.pubkey()?,
+                slots: reader.u64()?,
+            },
+            23 => Event::Refunded {
+                store: reader.pubkey()?,
+                receipt: reader.pubkey()?,
+                buyer: reader.pubkey()?,
+                quantity: reader.u64()?,
+                amount: reader.u64()?,
+            },
+            24 => Event::EscrowSettingsUpdated {
+                store: reader.pubkey()?,
+                timeout: reader.u64()?,
+                arbiter: reader.pubkey()?,
+            },
+            25 => Event::EscrowUpdated {
+                store: reader.pubkey()?,
+                escrow: reader.pubkey()?,
+                status: reader.u8()?,
+                amount: reader.u64()?,
+            },
+            26 => Event::ConsignmentModeUpdated {
+                store: reader.pubkey()?,
+                enabled: reader.bool()?,
+            },
+            27 => Event::Listed {
+                store: reader.pubkey()?,
+                seller: reader.pubkey()?,
+                price: reader.u64()?,
+                quantity: reader.u64()?,
+            },
+            28 => Event::Delisted {
+                store: reader.pubkey()?,
+                seller: reader.pubkey()?,
+                quantity: reader.u64()?,
+            },
+            29 => Event::ListingFilled {
+                store: reader.pubkey()?,
+                seller: reader.pubkey()?,
+                buyer: reader.pubkey()?,
+                quantity: reader.u64()?,
+                cost: reader.u64()?,
+            },
+            30 => Event::SellerProceedsWithdrawn {
+                store: reader.pubkey()?,
+                seller: reader.pubkey()?,
+                amount: reader.u64()?,
+            },
+            31 => Event::MultisigInitialized {
+                multisig: reader.pubkey()?,
+                m: reader.u8()?,
+                n: reader.u8()?,
+            },
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+
+        if !reader.0.is_empty() {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(event)
+    }
+
+    pub fn emit(&self) {
+        sol_log_data(&[&self.pack()]);
+    }
+}
+
+/// Decodes the events `program_id` logged in the log messages of a
+/// transaction. The `invoke` and `success`/`failed` lines are followed to know
+/// which program logged each `Program data:` entry, so data logged by other
+/// programs, including ones this program invokes, is skipped along with
+/// entries that do not decode.
+#[cfg(feature = "client")]
+pub fn decode_logs<S: AsRef<str>>(program_id: &Pubkey, logs: &[S]) -> Vec<Event> {
+    let program_id = program_id.to_string();
+    let mut invocations: Vec<&str> = vec![];
+    let mut events = vec![];
+    for line in logs.iter().map(AsRef::as_ref) {
+        if let Some(data) = line.strip_prefix(PROGRAM_DATA_PREFIX) {
+            if invocations.last() != Some(&program_id.as_str()) {
+                continue;
+            }
+            let event = data
+                .split(' ')
+                .next()
+                .and_then(|encoded| STANDARD.decode(encoded).ok())
+                .and_then(|bytes| Event::unpack(&bytes).ok());
+            events.extend(event);
+            continue;
+        }
+
+        let mut words = line.strip_prefix("Program ").unwrap_or_default().split(' ');
+        match (words.next(), words.next()) {
+            (Some(program), Some("invoke")) => invocations.push(program),
+            (Some(program), Some("success" | "failed:")) if invocations.last() == Some(&program) => {
+                invocations.pop();
+            }
+            _ => {}
+        }
+    }
+    events
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ProgramError> {
+        if self.0.len() < len {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, ProgramError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, ProgramError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(ProgramError::InvalidAccountData),
+        }
+    }
+
+    fn pubkey(&mut self) -> Result<Pubkey, ProgramError> {
+        Ok(Pubkey::new_from_array(self.take(32)?.try_into().unwrap()))
+    }
+
+    fn u16(&mut self) -> Result<u16, ProgramError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, ProgramError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
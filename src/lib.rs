@@ -1,66 +1,261 @@
 use solana_program::{
     account_info::{AccountInfo, next_account_info},
+    bpf_loader_upgradeable::{self, UpgradeableLoaderState},
     entrypoint,
     entrypoint::ProgramResult,
     pubkey::Pubkey,
     msg,
+    program::{invoke, invoke_signed},
     program_error::ProgramError,
     program_pack::Pack,
-    sysvar::Sysvar,
+    program_utils::limited_deserialize,
+    clock::Clock,
     rent::Rent,
+    system_instruction,
+    sysvar::Sysvar,
 };
 
 use solana_program::program_pack::IsInitialized;
 use spl_token::state::Account;
-use spl_token::state::AccountState::{Initialized, Uninitialized};
+
+pub mod events;
+pub mod signature;
+pub mod state;
+pub mod token;
+
+use events::Event;
+use signature::{verify_signed_message, Quote, Voucher};
+use state::{
+    find_config_address, find_escrow_address, find_history_address, find_listing_book_address, find_metadata_address, find_proceeds_address, find_receipt_address, find_registry_page_address, find_roles_address, find_seller_ledger_address,
+    find_vault_authority, find_wrap_address, Config, Escrow, Listing, ListingBook, Multisig, PriceHistory, PriceObservation, Receipt, RegistryEntry, RegistryPage, SellerLedger, Store, StoreMetadata, StoreRoles, ALL_ROLES,
+    CONFIG_SEED, ESCROW_DISPUTED, ESCROW_PENDING, ESCROW_REFUNDED, ESCROW_RELEASED, ESCROW_SEED, HISTORY_SEED, LEDGER_SEED, LISTINGS_SEED, LISTING_BOOK_CAPACITY, MAX_QUOTE_MINTS, METADATA_SEED, MAX_SIGNERS, PROCEEDS_SEED, QUOTE_SEED, RECEIPT_SEED, REGISTRY_SEED, ROLES_SEED,
+    ROLE_ADMIN, ROLE_INVENTORY_MANAGER, ROLE_PRICER, ROLE_WITHDRAWER, STORE_STATUS_ACTIVE, STORE_STATUS_CLOSED,
+    STORE_STATUS_PAUSED, TRANSFER_FEE_GROSS_UP, VAULT_SEED, VOUCHER_SEED, WRAP_SEED,
+};
 
 entrypoint!(process_instruction);
 
+/// `buy` option flag: create the buyer's associated token account if it does
+/// not exist yet.
+pub const BUY_CREATE_ATA: u8 = 1;
+/// `buy` option flag: pay a wSOL-quoted store with native lamports, wrapped
+/// into a temporary wSOL account passed in place of the buyer quote account
+/// and followed by the native mint.
+pub const BUY_WRAP_SOL: u8 = 2;
+/// `buy` option flag: apply a discount voucher signed by the store owner. The
+/// voucher terms follow the flags byte, the instructions sysvar and the
+/// voucher nonce account follow the optional accounts before them. Rejected
+/// for multisig-owned stores, which have no key to sign with.
+pub const BUY_VOUCHER: u8 = 4;
+/// `buy` option flag: buy at a price quoted off-chain by the store owner or a
+/// pricer instead of the store price. The quote terms follow the voucher
+/// terms, the quote nonce account follows the voucher nonce account and is
+/// followed by the roles account when a pricer signed the quote. Quotes of
+/// multisig-owned stores have to be signed by a pricer, quotes signed with
+/// the multisig key are rejected.
+pub const BUY_QUOTE: u8 = 8;
+
+// Quantity, maximum cost, flags and account count of a `buy_many` entry.
+const BUY_MANY_ENTRY_LEN: usize = 18;
+
 pub fn process_instruction(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
     instruction_data: &[u8],
 ) -> ProgramResult {
-    match instruction_data[0] {
-        0 => initialize_store(accounts, instruction_data),
-        1 => update_price(accounts, instruction_data),
-        2 => sell(accounts, instruction_data),
-        3 => buy(accounts, instruction_data),
+    let (tag, data) = instruction_data
+        .split_first()
+        .ok_or(ProgramError::InvalidInstructionData)?;
+
+    match tag {
+        0 => initialize_store(program_id, accounts, data),
+        1 => update_price(program_id, accounts, data),
+        2 => sell(program_id, accounts, data),
+        3 => buy(program_id, accounts, data),
+        4 => deposit_inventory(program_id, accounts, data),
+        5 => initialize_multisig(program_id, accounts, data),
+        6 => grant_role(program_id, accounts, data),
+        7 => revoke_role(program_id, accounts, data),
+        8 => propose_owner(program_id, accounts, data),
+        9 => accept_ownership(program_id, accounts),
+        10 => cancel_ownership_transfer(program_id, accounts),
+        11 => set_store_paused(program_id, accounts, true),
+        12 => set_store_paused(program_id, accounts, false),
+        13 => init_config(program_id, accounts, data),
+        14 => set_global_pause(program_id, accounts, data),
+        15 => close_store(program_id, accounts, data),
+        16 => withdraw_proceeds(program_id, accounts, data),
+        17 => update_config(program_id, accounts, data),
+        18 => set_price_guardrails(program_id, accounts, data),
+        19 => init_price_history(program_id, accounts, data),
+        20 => set_transfer_fee_mode(program_id, accounts, data),
+        21 => buy_many(program_id, accounts, data),
+        22 => sync_registry_entry(program_id, accounts, data),
+        23 => set_store_metadata(program_id, accounts, data),
+        24 => set_refund_window(program_id, accounts, data),
+        25 => refund(program_id, accounts, data),
+        26 => set_escrow_settings(program_id, accounts, data),
+        27 => confirm_delivery(program_id, accounts, data),
+        28 => claim_escrow(program_id, accounts, data),
+        29 => dispute_escrow(program_id, accounts, data),
+        30 => resolve_dispute(program_id, accounts, data),
+        31 => set_consignment(program_id, accounts, data),
+        32 => list_tokens(program_id, accounts, data),
+        33 => delist_tokens(program_id, accounts, data),
+        34 => buy_listings(program_id, accounts, data),
+        35 => withdraw_seller_proceeds(program_id, accounts, data),
         _ => Err(ProgramError::InvalidInstructionData),
     }
 }
-fn initialize_store(accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+// Stores are priced in SOL unless the instruction data carries a quote mint,
+// in which case the quote vault follows the registry page account. The owner
+// signs, a multisig owner through its signers passed last. Every store is
+// appended to the registry page its index falls on, the page is created by
+// the first store listed on it.
+fn initialize_store(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    if !data.is_empty() && data.len() != 32 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
     let accounts_iter = &mut accounts.iter();
 
     let store_account = next_account_info(accounts_iter)?;
-    let token_account = next_account_info(accounts_iter)?;
-    let owner_account = next_account_info(accounts_iter);
+    let config_account = next_account_info(accounts_iter)?;
+    let mint_account = next_account_info(accounts_iter)?;
+    let owner_account = next_account_info(accounts_iter)?;
+    let vault_account = next_account_info(accounts_iter)?;
+    let proceeds_account = next_account_info(accounts_iter)?;
+    let payer_account = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+    let registry_page_account = next_account_info(accounts_iter)?;
+
+    let mut config = load_config(program_id, config_account)?;
+
+    if store_account.owner != program_id {
+        return Err(ProgramError::IncorrectProgramId);
+    }
 
     let mut store_data = store_account.try_borrow_mut_data()?;
-    let mut token_data = token_account.try_borrow_mut_data()?;
-    let mut owner_data = owner_account.try_borrow_mut_data()?;
 
-    let store = &mut Account::unpack_unchecked(&mut store_data)?;
-    if store.state == Uninitialized {
+    let store = &mut Store::unpack_unchecked(&store_data)?;
+    if store.is_initialized() {
         return Err(ProgramError::AccountAlreadyInitialized);
     }
 
-    let owner_pubkey = owner_account.unwrap().key;
-    let price = 1000; // Set the initial price here
+    // The mint may belong to either token program, the vault must belong to
+    // the same one, hold the store mint and be controlled by the program
+    let mint = token::unpack_mint(mint_account)?;
+    if vault_account.owner != mint_account.owner {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    let vault = token::unpack_token_account(vault_account)?;
+    let (vault_authority, _) = find_vault_authority(program_id, store_account.key);
+    if vault.mint != *mint_account.key || vault.owner != vault_authority {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let quote_mint = if data.is_empty() {
+        Pubkey::default()
+    } else {
+        Pubkey::try_from(data).unwrap()
+    };
+    if !config.is_quote_mint_allowed(&quote_mint) {
+        return Err(ProgramError::Custom(12)); // Quote mint not allowed
+    }
+
+    // Proceeds are escrowed in a program-owned PDA until withdrawn
+    let (proceeds_key, proceeds_bump) = find_proceeds_address(program_id, store_account.key);
+    if proceeds_key != *proceeds_account.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+    create_pda_account(
+        program_id,
+        payer_account,
+        proceeds_account,
+        system_program,
+        0,
+        &[PROCEEDS_SEED, store_account.key.as_ref(), &[proceeds_bump]],
+    )?;
+
+    // Quote token proceeds are held in a token account of the proceeds PDA
+    if quote_mint != Pubkey::default() {
+        let quote_vault_account = next_account_info(accounts_iter)?;
+        if quote_vault_account.owner != &spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let quote_vault = Account::unpack(&quote_vault_account.try_borrow_data()?)?;
+        if quote_vault.mint != quote_mint || quote_vault.owner != proceeds_key {
+            return Err(ProgramError::InvalidArgument);
+        }
+        store.quote_vault = *quote_vault_account.key;
+    }
+
+    validate_owner(program_id, owner_account.key, owner_account, accounts_iter.as_slice())?;
 
-    store.state = Initialized;
-    store.mint = *token_account.key;
+    let owner_pubkey = owner_account.key;
+    let price = config.default_price;
+
+    store.is_initialized = true;
+    store.last_observed = Clock::get()?.unix_timestamp;
+    store.quote_mint = quote_mint;
+    store.mint = *mint_account.key;
+    store.token_program = *mint_account.owner;
+    store.decimals = mint.decimals;
     store.owner = *owner_pubkey;
-    store.amount = price;
+    store.owner_is_multisig = is_multisig(program_id, owner_account);
+    store.vault = *vault_account.key;
+    store.price = price;
+    store.registry_index = config.store_count;
+
+    Store::pack(*store, &mut store_data)?;
 
-    Account::pack(*store, &mut store_data)?;
+    let (page, slot) = RegistryPage::position(config.store_count);
+    let (page_key, page_bump) = find_registry_page_address(program_id, page);
+    if page_key != *registry_page_account.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+    if slot == 0 {
+        create_pda_account(
+            program_id,
+            payer_account,
+            registry_page_account,
+            system_program,
+            RegistryPage::account_len(),
+            &[REGISTRY_SEED, &page.to_le_bytes(), &[page_bump]],
+        )?;
+        RegistryPage {
+            is_initialized: true,
+            page,
+            len: 0,
+        }
+        .pack_into_slice(&mut registry_page_account.try_borrow_mut_data()?);
+    }
+    RegistryPage::push(
+        &mut registry_page_account.try_borrow_mut_data()?,
+        &RegistryEntry {
+            store: *store_account.key,
+            mint: store.mint,
+            owner: store.owner,
+            status: STORE_STATUS_ACTIVE,
+        },
+    )?;
 
+    config.store_count += 1;
+    Config::pack(config, &mut config_account.try_borrow_mut_data()?)?;
+
+    Event::StoreInitialized {
+        store: *store_account.key,
+        mint: store.mint,
+        owner: store.owner,
+        price,
+    }
+    .emit();
     msg!("Store initialized successfully!");
 
     Ok(())
 }
 
-fn update_price(accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+fn update_price(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
     if data.len() != 8 {
         return Err(ProgramError::InvalidInstructionData);
     }
@@ -68,80 +263,318 @@ fn update_price(accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
     let accounts_iter = &mut accounts.iter();
 
     let store_account = next_account_info(accounts_iter)?;
-    let owner_account = next_account_info(accounts_iter)?;
+    let authority_account = next_account_info(accounts_iter)?;
 
     let mut store_data = store_account.try_borrow_mut_data()?;
-    let store = &mut Account::unpack_unchecked(&mut store_data)?;
+    let store = &mut Store::unpack(&store_data)?;
 
-    if store.owner != *owner_account.key {
-        return Err(ProgramError::Custom(1)); // Unauthorized owner
-    }
+    // Stores keeping a price history take it right after the authority
+    let history_account = if store.history != Pubkey::default() {
+        Some(next_account_info(accounts_iter)?)
+    } else {
+        None
+    };
+
+    authorize(
+        program_id,
+        store_account.key,
+        store,
+        ROLE_PRICER,
+        authority_account,
+        accounts_iter.as_slice(),
+    )?;
 
     let new_price = u64::from_le_bytes(data.try_into().unwrap());
-    store.amount = new_price;
+    let old_price = store.price;
+    let now = Clock::get()?.unix_timestamp;
+    check_price_guardrails(store, new_price, now)?;
+    store.accumulate(now);
+    store.price = new_price;
 
-    Account::pack(* store, &mut store_data)?;
+    if let Some(history_account) = history_account {
+        record_observation(program_id, store, history_account, store.price, 0)?;
+    }
+
+    Store::pack(*store, &mut store_data)?;
 
+    Event::PriceUpdated {
+        store: *store_account.key,
+        old_price,
+        new_price,
+    }
+    .emit();
     msg!("Price updated successfully!");
 
     Ok(())
 }
 
-fn sell(accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
-    if data.len() != 8 {
+// Data: min price, max price, max change per update and per window in basis
+// points, and the window length in seconds.
+fn set_price_guardrails(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    if data.len() != 28 {
         return Err(ProgramError::InvalidInstructionData);
     }
 
     let accounts_iter = &mut accounts.iter();
 
     let store_account = next_account_info(accounts_iter)?;
-    let seller_token_account = next_account_info(accounts_iter)?;
-    let buyer_token_account = next_account_info(accounts_iter)?;
-    let seller_owner_account = next_account_info(accounts_iter)?;
+    let authority_account = next_account_info(accounts_iter)?;
 
     let mut store_data = store_account.try_borrow_mut_data()?;
-    let mut seller_token_data = seller_token_account.try_borrow_mut_data()?;
-    let mut buyer_token_data = buyer_token_account.try_borrow_mut_data()?;
+    let store = &mut Store::unpack(&store_data)?;
 
-    let store = &mut Account::unpack_unchecked(&mut store_data)?;
+    authorize(
+        program_id,
+        store_account.key,
+        store,
+        ROLE_ADMIN,
+        authority_account,
+        accounts_iter.as_slice(),
+    )?;
 
-    if store.amount == 0 {
-        return Err(ProgramError::Custom(2)); // Price not set
+    let min_price = u64::from_le_bytes(data[..8].try_into().unwrap());
+    let max_price = u64::from_le_bytes(data[8..16].try_into().unwrap());
+    if max_price != 0 && max_price < min_price {
+        return Err(ProgramError::InvalidArgument);
     }
 
-    if seller_token_account.owner != spl_token::id() {
-        return Err(ProgramError::IncorrectProgramId);
+    store.min_price = min_price;
+    store.max_price = max_price;
+    store.max_change_bps = u16::from_le_bytes(data[16..18].try_into().unwrap());
+    store.window_change_bps = u16::from_le_bytes(data[18..20].try_into().unwrap());
+    store.window_seconds = u64::from_le_bytes(data[20..].try_into().unwrap());
+    // Start a fresh window with the new limits
+    store.window_start = 0;
+    store.window_start_price = 0;
+
+    Store::pack(*store, &mut store_data)?;
+
+    Event::PriceGuardrailsUpdated {
+        store: *store_account.key,
+        min_price,
+        max_price,
+        max_change_bps: store.max_change_bps,
+        window_change_bps: store.window_change_bps,
+        window_seconds: store.window_seconds,
+    }
+    .emit();
+    msg!("Price guardrails updated successfully!");
+
+    Ok(())
+}
+
+fn init_price_history(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    if data.len() != 2 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let accounts_iter = &mut accounts.iter();
+
+    let store_account = next_account_info(accounts_iter)?;
+    let authority_account = next_account_info(accounts_iter)?;
+    let history_account = next_account_info(accounts_iter)?;
+    let payer_account = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    let mut store_data = store_account.try_borrow_mut_data()?;
+    let store = &mut Store::unpack(&store_data)?;
+
+    authorize(
+        program_id,
+        store_account.key,
+        store,
+        ROLE_ADMIN,
+        authority_account,
+        accounts_iter.as_slice(),
+    )?;
+
+    if store.history != Pubkey::default() {
+        return Err(ProgramError::AccountAlreadyInitialized);
     }
 
-    if store.mint != *seller_token_account.key {
+    let capacity = u16::from_le_bytes(data.try_into().unwrap());
+    if capacity == 0 {
         return Err(ProgramError::InvalidArgument);
     }
 
-    if *store_account.key != *seller_owner_account.key {
-        return Err(ProgramError::Custom(1)); // Unauthorized seller
+    let (history_key, bump) = find_history_address(program_id, store_account.key);
+    if history_key != *history_account.key {
+        return Err(ProgramError::InvalidArgument);
     }
+    create_pda_account(
+        program_id,
+        payer_account,
+        history_account,
+        system_program,
+        PriceHistory::account_len(capacity),
+        &[HISTORY_SEED, store_account.key.as_ref(), &[bump]],
+    )?;
 
-    let price = u64::from_le_bytes(data.try_into().unwrap());
-    if price < store.amount {
-        return Err(ProgramError::Custom(3)); // Insufficient payment
+    let history = PriceHistory {
+        is_initialized: true,
+        store: *store_account.key,
+        capacity,
+        head: 0,
+        len: 0,
+    };
+    history.pack_into_slice(&mut history_account.try_borrow_mut_data()?);
+
+    store.history = history_key;
+
+    Store::pack(*store, &mut store_data)?;
+
+    Event::PriceHistoryInitialized {
+        store: *store_account.key,
+        history: history_key,
+        capacity,
     }
+    .emit();
+    msg!("Price history initialized successfully!");
 
-    // Transfer tokens from the seller to the buyer
-    spl_token::instruction::transfer(
-        &spl_token::id(),
-        seller_token_account.key,
-        buyer_token_account.key,
-        seller_owner_account.key,
-        &[],
-        price,
+    Ok(())
+}
+
+// Permissionless, refreshes the owner and status a registry entry records
+// from the current store state. Data is the registry index of the store.
+fn sync_registry_entry(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    if data.len() != 8 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let accounts_iter = &mut accounts.iter();
+
+    let store_account = next_account_info(accounts_iter)?;
+    let registry_page_account = next_account_info(accounts_iter)?;
+
+    let index = u64::from_le_bytes(data.try_into().unwrap());
+
+    // Closed stores are zeroed and drained, they keep their last owner
+    let store = if store_account.owner == program_id && store_account.data_len() == Store::LEN {
+        Some(Store::unpack_unchecked(&store_account.try_borrow_data()?)?).filter(|store| store.is_initialized())
+    } else {
+        None
+    };
+    update_registry_entry(program_id, store_account.key, index, registry_page_account, |entry| match store {
+        Some(store) => {
+            entry.owner = store.owner;
+            entry.status = if store.paused { STORE_STATUS_PAUSED } else { STORE_STATUS_ACTIVE };
+        }
+        None => entry.status = STORE_STATUS_CLOSED,
+    })?;
+    msg!("Registry entry synced successfully!");
+
+    Ok(())
+}
+
+// Applies `update` to the registry entry of the store at `index`, the page
+// account has to be the registry page the index falls on.
+fn update_registry_entry(
+    program_id: &Pubkey,
+    store_key: &Pubkey,
+    index: u64,
+    registry_page_account: &AccountInfo,
+    update: impl FnOnce(&mut RegistryEntry),
+) -> ProgramResult {
+    let (page, slot) = RegistryPage::position(index);
+    if find_registry_page_address(program_id, page).0 != *registry_page_account.key
+        || registry_page_account.owner != program_id
+    {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let mut page_data = registry_page_account.try_borrow_mut_data()?;
+    let mut entry = RegistryPage::entry(&page_data, slot)?;
+    if entry.store != *store_key {
+        return Err(ProgramError::InvalidArgument);
+    }
+    update(&mut entry);
+    RegistryPage::set_entry(&mut page_data, slot, &entry)?;
+
+    Event::RegistryEntryUpdated {
+        store: *store_key,
+        index,
+        owner: entry.owner,
+        status: entry.status,
+    }
+    .emit();
+
+    Ok(())
+}
+
+// Creates or replaces the store metadata. The account is resized to the new
+// contents, the payer covers extra rent and receives the excess when the
+// metadata shrinks.
+fn set_store_metadata(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+
+    let store_account = next_account_info(accounts_iter)?;
+    let authority_account = next_account_info(accounts_iter)?;
+    let metadata_account = next_account_info(accounts_iter)?;
+    let payer_account = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    let store = Store::unpack(&store_account.try_borrow_data()?)?;
+
+    authorize(
+        program_id,
+        store_account.key,
+        &store,
+        ROLE_ADMIN,
+        authority_account,
+        accounts_iter.as_slice(),
     )?;
 
-    msg!("Tokens sold successfully!");
+    let metadata = StoreMetadata::unpack_fields(*store_account.key, data)?;
+    if !metadata.is_within_limits() {
+        return Err(ProgramError::Custom(17)); // Metadata too long
+    }
+
+    let (metadata_key, bump) = find_metadata_address(program_id, store_account.key);
+    if metadata_key != *metadata_account.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let packed = metadata.pack();
+    if metadata_account.owner != program_id {
+        create_pda_account(
+            program_id,
+            payer_account,
+            metadata_account,
+            system_program,
+            packed.len(),
+            &[METADATA_SEED, store_account.key.as_ref(), &[bump]],
+        )?;
+    } else {
+        let required = Rent::get()?.minimum_balance(packed.len());
+        let current = metadata_account.lamports();
+        if required > current {
+            invoke(
+                &system_instruction::transfer(payer_account.key, metadata_account.key, required - current),
+                &[payer_account.clone(), metadata_account.clone(), system_program.clone()],
+            )?;
+        } else {
+            **metadata_account.try_borrow_mut_lamports()? = required;
+            **payer_account.try_borrow_mut_lamports()? = payer_account
+                .lamports()
+                .checked_add(current - required)
+                .ok_or(ProgramError::ArithmeticOverflow)?;
+        }
+        metadata_account.realloc(packed.len(), false)?;
+    }
+    metadata_account.try_borrow_mut_data()?.copy_from_slice(&packed);
+
+    Event::StoreMetadataUpdated {
+        store: *store_account.key,
+        metadata: metadata_key,
+        size: packed.len() as u64,
+    }
+    .emit();
+    msg!("Store metadata updated successfully!");
 
     Ok(())
 }
 
-fn buy(accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+fn deposit_inventory(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
     if data.len() != 8 {
         return Err(ProgramError::InvalidInstructionData);
     }
@@ -149,57 +582,2889 @@ fn buy(accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
     let accounts_iter = &mut accounts.iter();
 
     let store_account = next_account_info(accounts_iter)?;
-    let seller_token_account = next_account_info(accounts_iter)?;
-    let buyer_token_account = next_account_info(accounts_iter)?;
-    let buyer_owner_account = next_account_info(accounts_iter);
+    let owner_account = next_account_info(accounts_iter)?;
+    let source_token_account = next_account_info(accounts_iter)?;
+    let vault_account = next_account_info(accounts_iter)?;
+    let mint_account = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
 
     let mut store_data = store_account.try_borrow_mut_data()?;
-    let mut seller_token_data = seller_token_account.try_borrow_mut_data()?;
-    let mut buyer_token_data = buyer_token_account.try_borrow_mut_data()?;
+    let store = &mut Store::unpack(&store_data)?;
 
-    let store = &mut Account::unpack_unchecked(&mut store_data)?;
+    authorize(
+        program_id,
+        store_account.key,
+        store,
+        ROLE_INVENTORY_MANAGER,
+        owner_account,
+        accounts_iter.as_slice(),
+    )?;
 
-    if store.amount == 0 {
-        return Err(ProgramError::Custom(2)); // Price not set
+    if store.consignment {
+        return Err(ProgramError::Custom(28)); // Consignment store
+    }
+
+    if store.vault != *vault_account.key || store.mint != *mint_account.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if *token_program.key != store.token_program {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let deposited = u64::from_le_bytes(data.try_into().unwrap());
+
+    // Move the tokens from the owner into the store vault, only what arrives
+    // after the mint transfer fee counts as inventory
+    token::transfer_checked(
+        token_program,
+        source_token_account,
+        mint_account,
+        vault_account,
+        owner_account,
+        deposited,
+        store.decimals,
+        &[],
+    )?;
+    let amount = deposited - token::transfer_fee(mint_account, deposited)?;
+
+    store.deposited = store.deposited.checked_add(amount).ok_or(ProgramError::ArithmeticOverflow)?;
+    store.supply = store.supply.checked_add(amount).ok_or(ProgramError::ArithmeticOverflow)?;
+
+    Store::pack(*store, &mut store_data)?;
+
+    Event::InventoryDeposited {
+        store: *store_account.key,
+        amount,
+        supply: store.supply,
+    }
+    .emit();
+    msg!("Inventory deposited successfully!");
+
+    Ok(())
+}
+
+fn initialize_multisig(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    if data.len() != 1 {
+        return Err(ProgramError::InvalidInstructionData);
     }
 
-    if seller_token_account.owner != spl_token::id() {
+    let accounts_iter = &mut accounts.iter();
+
+    let multisig_account = next_account_info(accounts_iter)?;
+    let signer_accounts = accounts_iter.as_slice();
+
+    if multisig_account.owner != program_id {
         return Err(ProgramError::IncorrectProgramId);
     }
 
-    if store.mint != *seller_token_account.key {
+    let mut multisig_data = multisig_account.try_borrow_mut_data()?;
+    let multisig = &mut Multisig::unpack_unchecked(&multisig_data)?;
+    if multisig.is_initialized() {
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    let m = data[0];
+    if signer_accounts.is_empty() || signer_accounts.len() > MAX_SIGNERS {
+        return Err(ProgramError::InvalidArgument);
+    }
+    if m == 0 || m as usize > signer_accounts.len() {
         return Err(ProgramError::InvalidArgument);
     }
+    // A signer listed twice would count twice towards the threshold
+    for (position, signer) in signer_accounts.iter().enumerate() {
+        if signer_accounts[..position].iter().any(|other| other.key == signer.key) {
+            return Err(ProgramError::InvalidArgument);
+        }
+    }
 
-    let buyer_owner_pubkey = buyer_owner_account.unwrap().key;
+    for (signer, key) in signer_accounts.iter().zip(multisig.signers.iter_mut()) {
+        *key = *signer.key;
+    }
+    multisig.m = m;
+    multisig.n = signer_accounts.len() as u8;
+    multisig.is_initialized = true;
+
+    Multisig::pack(*multisig, &mut multisig_data)?;
 
-    if *buyer_owner_pubkey != store.owner {
-        return Err(ProgramError::Custom(1)); // Unauthorized buyer
+    Event::MultisigInitialized {
+        multisig: *multisig_account.key,
+        m: multisig.m,
+        n: multisig.n,
     }
+    .emit();
+    msg!("Multisig initialized successfully!");
 
-    let price = u64::from_le_bytes(data.try_into().unwrap());
-    if price < store.amount {
-        return Err(ProgramError::Custom(3)); // Insufficient payment
+    Ok(())
+}
+
+fn grant_role(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    if data.len() != 33 {
+        return Err(ProgramError::InvalidInstructionData);
     }
 
-    // Transfer SOL from the buyer to the seller
+    let accounts_iter = &mut accounts.iter();
+
+    let store_account = next_account_info(accounts_iter)?;
+    let authority_account = next_account_info(accounts_iter)?;
+    let roles_account = next_account_info(accounts_iter)?;
+    let payer_account = next_account_info(accounts_iter)?;
     let system_program = next_account_info(accounts_iter)?;
-    let transfer_to_system_ix = solana_program::system_instruction::transfer(
-        buyer_owner_pubkey,
-        seller_token_account.key,
-        price,
-    );
-    let acc= [buyer_owner_account.clone(), seller_token_account.clone(), system_program.clone()].iter().map(|ac| ac.as_ref().unwrap()).collect();
-    solana_program::program::invoke(
-        &transfer_to_system_ix,
-        acc,
-    )?;
 
-    msg!("Tokens bought successfully!");
+    let role = data[0];
+    let holder = Pubkey::try_from(&data[1..]).unwrap();
+    if role == 0 || role & !ALL_ROLES != 0 {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let store = Store::unpack(&store_account.try_borrow_data()?)?;
+    if *authority_account.key == store.owner {
+        validate_owner(program_id, &store.owner, authority_account, accounts_iter.as_slice())?;
+    } else {
+        check_role(program_id, store_account.key, roles_account, authority_account, ROLE_ADMIN)?;
+    }
+
+    let (roles_key, bump) = find_roles_address(program_id, store_account.key);
+    if roles_key != *roles_account.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // The roles account is created on the first grant
+    if roles_account.data_is_empty() {
+        create_pda_account(
+            program_id,
+            payer_account,
+            roles_account,
+            system_program,
+            StoreRoles::LEN,
+            &[ROLES_SEED, store_account.key.as_ref(), &[bump]],
+        )?;
+    }
+
+    let mut roles_data = roles_account.try_borrow_mut_data()?;
+    let roles = &mut StoreRoles::unpack_unchecked(&roles_data)?;
+    if !roles.is_initialized() {
+        roles.is_initialized = true;
+        roles.store = *store_account.key;
+    }
+    roles.grant(&holder, role)?;
+
+    StoreRoles::pack(*roles, &mut roles_data)?;
+
+    Event::RoleGranted {
+        store: *store_account.key,
+        holder,
+        role,
+    }
+    .emit();
+    msg!("Role granted successfully!");
 
     Ok(())
 }
 
-entrypoint!(process_instruction);
+fn revoke_role(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    if data.len() != 33 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let accounts_iter = &mut accounts.iter();
 
+    let store_account = next_account_info(accounts_iter)?;
+    let authority_account = next_account_info(accounts_iter)?;
+    let roles_account = next_account_info(accounts_iter)?;
+
+    let role = data[0];
+    let holder = Pubkey::try_from(&data[1..]).unwrap();
+
+    let store = Store::unpack(&store_account.try_borrow_data()?)?;
+    if *authority_account.key == store.owner {
+        validate_owner(program_id, &store.owner, authority_account, accounts_iter.as_slice())?;
+    } else {
+        check_role(program_id, store_account.key, roles_account, authority_account, ROLE_ADMIN)?;
+    }
+
+    if roles_account.owner != program_id
+        || *roles_account.key != find_roles_address(program_id, store_account.key).0
+    {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let mut roles_data = roles_account.try_borrow_mut_data()?;
+    let roles = &mut StoreRoles::unpack(&roles_data)?;
+    roles.revoke(&holder, role);
+
+    StoreRoles::pack(*roles, &mut roles_data)?;
+
+    Event::RoleRevoked {
+        store: *store_account.key,
+        holder,
+        role,
+    }
+    .emit();
+    msg!("Role revoked successfully!");
+
+    Ok(())
+}
+
+fn propose_owner(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    if data.len() != 32 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let accounts_iter = &mut accounts.iter();
+
+    let store_account = next_account_info(accounts_iter)?;
+    let owner_account = next_account_info(accounts_iter)?;
+
+    let mut store_data = store_account.try_borrow_mut_data()?;
+    let store = &mut Store::unpack(&store_data)?;
+
+    validate_owner(program_id, &store.owner, owner_account, accounts_iter.as_slice())?;
+
+    let pending_owner = Pubkey::try_from(data).unwrap();
+    if pending_owner == Pubkey::default() || pending_owner == store.owner {
+        return Err(ProgramError::InvalidArgument);
+    }
+    store.pending_owner = pending_owner;
+
+    Store::pack(*store, &mut store_data)?;
+
+    Event::OwnershipProposed {
+        store: *store_account.key,
+        owner: store.owner,
+        pending_owner,
+    }
+    .emit();
+    msg!("Ownership transfer proposed successfully!");
+
+    Ok(())
+}
+
+// The pending owner takes over the store. Roles granted under the previous
+// owner are revoked, the roles account follows the pending owner account and
+// precedes the multisig signers.
+fn accept_ownership(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+
+    let store_account = next_account_info(accounts_iter)?;
+    let pending_owner_account = next_account_info(accounts_iter)?;
+    let roles_account = next_account_info(accounts_iter)?;
+
+    let mut store_data = store_account.try_borrow_mut_data()?;
+    let store = &mut Store::unpack(&store_data)?;
+
+    if store.pending_owner == Pubkey::default() {
+        return Err(ProgramError::Custom(8)); // No pending owner
+    }
+
+    // The new owner may itself be a multisig
+    validate_owner(program_id, &store.pending_owner, pending_owner_account, accounts_iter.as_slice())?;
+
+    let previous_owner = store.owner;
+    store.owner = store.pending_owner;
+    store.owner_is_multisig = is_multisig(program_id, pending_owner_account);
+    store.pending_owner = Pubkey::default();
+
+    Store::pack(*store, &mut store_data)?;
+
+    if *roles_account.key != find_roles_address(program_id, store_account.key).0 {
+        return Err(ProgramError::InvalidArgument);
+    }
+    if roles_account.owner == program_id {
+        let mut roles_data = roles_account.try_borrow_mut_data()?;
+        let roles = &mut StoreRoles::unpack(&roles_data)?;
+        roles.clear();
+        StoreRoles::pack(*roles, &mut roles_data)?;
+    }
+
+    Event::OwnershipTransferred {
+        store: *store_account.key,
+        previous_owner,
+        new_owner: store.owner,
+    }
+    .emit();
+    msg!("Ownership accepted successfully!");
+
+    Ok(())
+}
+
+fn cancel_ownership_transfer(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+
+    let store_account = next_account_info(accounts_iter)?;
+    let owner_account = next_account_info(accounts_iter)?;
+
+    let mut store_data = store_account.try_borrow_mut_data()?;
+    let store = &mut Store::unpack(&store_data)?;
+
+    validate_owner(program_id, &store.owner, owner_account, accounts_iter.as_slice())?;
+
+    if store.pending_owner == Pubkey::default() {
+        return Err(ProgramError::Custom(8)); // No pending owner
+    }
+    store.pending_owner = Pubkey::default();
 
+    Store::pack(*store, &mut store_data)?;
+
+    Event::OwnershipTransferCancelled {
+        store: *store_account.key,
+    }
+    .emit();
+    msg!("Ownership transfer cancelled successfully!");
+
+    Ok(())
+}
+
+// Accounts: store, authority, the registry page the store is listed on, then
+// the accounts `authorize` takes. The registry entry follows the new status.
+fn set_store_paused(program_id: &Pubkey, accounts: &[AccountInfo], paused: bool) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+
+    let store_account = next_account_info(accounts_iter)?;
+    let authority_account = next_account_info(accounts_iter)?;
+    let registry_page_account = next_account_info(accounts_iter)?;
+
+    let mut store_data = store_account.try_borrow_mut_data()?;
+    let store = &mut Store::unpack(&store_data)?;
+
+    authorize(
+        program_id,
+        store_account.key,
+        store,
+        ROLE_ADMIN,
+        authority_account,
+        accounts_iter.as_slice(),
+    )?;
+
+    store.paused = paused;
+
+    Store::pack(*store, &mut store_data)?;
+
+    update_registry_entry(
+        program_id,
+        store_account.key,
+        store.registry_index,
+        registry_page_account,
+        |entry| entry.status = if paused { STORE_STATUS_PAUSED } else { STORE_STATUS_ACTIVE },
+    )?;
+
+    Event::StorePauseChanged {
+        store: *store_account.key,
+        paused,
+    }
+    .emit();
+    if paused {
+        msg!("Store paused successfully!");
+    } else {
+        msg!("Store unpaused successfully!");
+    }
+
+    Ok(())
+}
+
+fn set_transfer_fee_mode(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    if data.len() != 1 || data[0] > TRANSFER_FEE_GROSS_UP {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let accounts_iter = &mut accounts.iter();
+
+    let store_account = next_account_info(accounts_iter)?;
+    let authority_account = next_account_info(accounts_iter)?;
+
+    let mut store_data = store_account.try_borrow_mut_data()?;
+    let store = &mut Store::unpack(&store_data)?;
+
+    authorize(
+        program_id,
+        store_account.key,
+        store,
+        ROLE_ADMIN,
+        authority_account,
+        accounts_iter.as_slice(),
+    )?;
+
+    store.transfer_fee_mode = data[0];
+
+    Store::pack(*store, &mut store_data)?;
+
+    Event::TransferFeeModeUpdated {
+        store: *store_account.key,
+        mode: store.transfer_fee_mode,
+    }
+    .emit();
+    msg!("Transfer fee mode updated successfully!");
+
+    Ok(())
+}
+
+// Data is the refund window in slots, 0 turns buyer refunds off.
+fn set_refund_window(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    if data.len() != 8 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let accounts_iter = &mut accounts.iter();
+
+    let store_account = next_account_info(accounts_iter)?;
+    let authority_account = next_account_info(accounts_iter)?;
+
+    let mut store_data = store_account.try_borrow_mut_data()?;
+    let store = &mut Store::unpack(&store_data)?;
+
+    authorize(
+        program_id,
+        store_account.key,
+        store,
+        ROLE_ADMIN,
+        authority_account,
+        accounts_iter.as_slice(),
+    )?;
+
+    store.refund_window = u64::from_le_bytes(data.try_into().unwrap());
+
+    Store::pack(*store, &mut store_data)?;
+
+    Event::RefundWindowUpdated {
+        store: *store_account.key,
+        slots: store.refund_window,
+    }
+    .emit();
+    msg!("Refund window updated successfully!");
+
+    Ok(())
+}
+
+// Data is the admin key. Only the upgrade authority of the program, proven by
+// its program data account passed last, may create the config, so the admin
+// cannot be taken over by whoever calls first after deployment.
+fn init_config(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let admin = Pubkey::try_from(data).map_err(|_| ProgramError::InvalidInstructionData)?;
+
+    let accounts_iter = &mut accounts.iter();
+
+    let config_account = next_account_info(accounts_iter)?;
+    let upgrade_authority_account = next_account_info(accounts_iter)?;
+    let payer_account = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+    let program_data_account = next_account_info(accounts_iter)?;
+
+    check_upgrade_authority(program_id, program_data_account, upgrade_authority_account)?;
+
+    let (config_key, bump) = find_config_address(program_id);
+    if config_key != *config_account.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+    if !config_account.data_is_empty() {
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    create_pda_account(
+        program_id,
+        payer_account,
+        config_account,
+        system_program,
+        Config::LEN,
+        &[CONFIG_SEED, &[bump]],
+    )?;
+
+    let config = Config {
+        is_initialized: true,
+        admin,
+        default_price: 1000,
+        fee_recipient: admin,
+        ..Config::default()
+    };
+    Config::pack(config, &mut config_account.try_borrow_mut_data()?)?;
+
+    Event::ConfigInitialized {
+        admin: config.admin,
+    }
+    .emit();
+    msg!("Config initialized successfully!");
+
+    Ok(())
+}
+
+fn set_global_pause(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    if data.len() != 1 || data[0] > 1 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let accounts_iter = &mut accounts.iter();
+
+    let config_account = next_account_info(accounts_iter)?;
+    let admin_account = next_account_info(accounts_iter)?;
+
+    let mut config = load_config(program_id, config_account)?;
+    validate_owner(program_id, &config.admin, admin_account, accounts_iter.as_slice())?;
+
+    config.paused = data[0] == 1;
+
+    Config::pack(config, &mut config_account.try_borrow_mut_data()?)?;
+
+    Event::GlobalPauseChanged {
+        paused: config.paused,
+    }
+    .emit();
+    msg!("Global pause updated successfully!");
+
+    Ok(())
+}
+
+// Data: new admin, default price, fee bps, fee recipient, max quantity,
+// paused flag, quote mint count and the quote mints.
+fn update_config(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    if data.len() < 84 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+    let quote_mint_count = data[83] as usize;
+    if quote_mint_count > MAX_QUOTE_MINTS || data.len() != 84 + 32 * quote_mint_count || data[82] > 1 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let accounts_iter = &mut accounts.iter();
+
+    let config_account = next_account_info(accounts_iter)?;
+    let admin_account = next_account_info(accounts_iter)?;
+
+    let mut config = load_config(program_id, config_account)?;
+    validate_owner(program_id, &config.admin, admin_account, accounts_iter.as_slice())?;
+
+    let fee_bps = u16::from_le_bytes(data[40..42].try_into().unwrap());
+    if fee_bps > 10_000 {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    config.admin = Pubkey::try_from(&data[..32]).unwrap();
+    config.default_price = u64::from_le_bytes(data[32..40].try_into().unwrap());
+    config.fee_bps = fee_bps;
+    config.fee_recipient = Pubkey::try_from(&data[42..74]).unwrap();
+    config.max_quantity = u64::from_le_bytes(data[74..82].try_into().unwrap());
+    config.paused = data[82] == 1;
+    config.quote_mint_count = quote_mint_count as u8;
+    config.quote_mints = [Pubkey::default(); MAX_QUOTE_MINTS];
+    for (mint, chunk) in config.quote_mints.iter_mut().zip(data[84..].chunks(32)) {
+        *mint = Pubkey::try_from(chunk).unwrap();
+    }
+
+    Config::pack(config, &mut config_account.try_borrow_mut_data()?)?;
+
+    Event::ConfigUpdated {
+        admin: config.admin,
+        default_price: config.default_price,
+        fee_bps: config.fee_bps,
+        fee_recipient: config.fee_recipient,
+        max_quantity: config.max_quantity,
+        paused: config.paused,
+    }
+    .emit();
+    msg!("Config updated successfully!");
+
+    Ok(())
+}
+
+// Accounts: store, owner, vault, mint, vault authority, proceeds PDA, owner
+// token account, destination, token program, for quote token stores the quote
+// vault, the owner quote token account and the quote token program, then the
+// roles, metadata and listing book PDAs of the store, the registry page it is
+// listed on and its price history account if it keeps one, followed by the
+// multisig signers. Those of the PDAs that exist are closed along with the
+// store and the registry entry is marked closed. Unwithdrawn quote token
+// proceeds are swept to the owner before the quote vault is closed.
+fn close_store(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    if data.len() != 1 || data[0] > 1 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let accounts_iter = &mut accounts.iter();
+
+    let store_account = next_account_info(accounts_iter)?;
+    let owner_account = next_account_info(accounts_iter)?;
+    let vault_account = next_account_info(accounts_iter)?;
+    let mint_account = next_account_info(accounts_iter)?;
+    let vault_authority = next_account_info(accounts_iter)?;
+    let proceeds_account = next_account_info(accounts_iter)?;
+    let owner_token_account = next_account_info(accounts_iter)?;
+    let destination_account = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+
+    let store = Store::unpack(&store_account.try_borrow_data()?)?;
+
+    let quote_accounts = if store.is_priced_in_sol() {
+        None
+    } else {
+        Some((
+            next_account_info(accounts_iter)?,
+            next_account_info(accounts_iter)?,
+            next_account_info(accounts_iter)?,
+        ))
+    };
+
+    let roles_account = next_account_info(accounts_iter)?;
+    let metadata_account = next_account_info(accounts_iter)?;
+    let book_account = next_account_info(accounts_iter)?;
+    let registry_page_account = next_account_info(accounts_iter)?;
+    let history_account = if store.history != Pubkey::default() {
+        Some(next_account_info(accounts_iter)?)
+    } else {
+        None
+    };
+
+    validate_owner(program_id, &store.owner, owner_account, accounts_iter.as_slice())?;
+
+    if *token_program.key != store.token_program {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    if store.vault != *vault_account.key || store.mint != *mint_account.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let (authority_key, bump) = find_vault_authority(program_id, store_account.key);
+    if authority_key != *vault_authority.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+    let vault_seeds: &[&[u8]] = &[VAULT_SEED, store_account.key.as_ref(), &[bump]];
+
+    // Escrowed payments still have to reach the proceeds or the buyer
+    if store.open_escrows > 0 {
+        return Err(ProgramError::Custom(30)); // Escrows open
+    }
+
+    // Leftover inventory is either returned to the owner or blocks the close,
+    // consigned tokens belong to their sellers and always block it
+    let remaining = token::unpack_token_account(vault_account)?.amount;
+    let return_inventory = data[0] == 1 && !store.consignment;
+    if remaining > 0 {
+        if !return_inventory {
+            return Err(ProgramError::Custom(11)); // Vault not empty
+        }
+        token::transfer_checked(
+            token_program,
+            vault_account,
+            mint_account,
+            owner_token_account,
+            vault_authority,
+            remaining,
+            store.decimals,
+            &[vault_seeds],
+        )?;
+    }
+
+    // Token-2022 vaults cannot be closed while they hold withheld transfer
+    // fees, harvesting them to the mint is permissionless
+    if token::withheld_fees(vault_account)? > 0 {
+        let harvest_ix = spl_token_2022::extension::transfer_fee::instruction::harvest_withheld_tokens_to_mint(
+            token_program.key,
+            mint_account.key,
+            &[vault_account.key],
+        )?;
+        invoke(&harvest_ix, &[mint_account.clone(), vault_account.clone(), token_program.clone()])?;
+    }
+
+    let close_vault_ix = spl_token_2022::instruction::close_account(
+        token_program.key,
+        vault_account.key,
+        destination_account.key,
+        vault_authority.key,
+        &[],
+    )?;
+    invoke_signed(
+        &close_vault_ix,
+        &[vault_account.clone(), destination_account.clone(), vault_authority.clone(), token_program.clone()],
+        &[vault_seeds],
+    )?;
+
+    let (proceeds_key, proceeds_bump) = find_proceeds_address(program_id, store_account.key);
+    if proceeds_key != *proceeds_account.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // Quote token proceeds left in the quote vault go to the owner, the vault
+    // rent to the destination
+    if let Some((quote_vault_account, owner_quote_account, quote_token_program)) = quote_accounts {
+        if *quote_token_program.key != spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        if store.quote_vault != *quote_vault_account.key {
+            return Err(ProgramError::InvalidArgument);
+        }
+        let proceeds_seeds: &[&[u8]] = &[PROCEEDS_SEED, store_account.key.as_ref(), &[proceeds_bump]];
+        let unwithdrawn = Account::unpack(&quote_vault_account.try_borrow_data()?)?.amount;
+        if unwithdrawn > 0 {
+            let owner_quote = Account::unpack(&owner_quote_account.try_borrow_data()?)?;
+            if owner_quote.owner != store.owner || owner_quote.mint != store.quote_mint {
+                return Err(ProgramError::InvalidArgument);
+            }
+            transfer_payment(
+                quote_vault_account,
+                owner_quote_account,
+                proceeds_account,
+                quote_token_program,
+                unwithdrawn,
+                &[proceeds_seeds],
+            )?;
+        }
+
+        let close_quote_vault_ix = spl_token::instruction::close_account(
+            quote_token_program.key,
+            quote_vault_account.key,
+            destination_account.key,
+            proceeds_account.key,
+            &[],
+        )?;
+        invoke_signed(
+            &close_quote_vault_ix,
+            &[
+                quote_vault_account.clone(),
+                destination_account.clone(),
+                proceeds_account.clone(),
+                quote_token_program.clone(),
+            ],
+            &[proceeds_seeds],
+        )?;
+    }
+
+    // The accounts kept for the store go with it
+    close_store_pda(program_id, roles_account, &find_roles_address(program_id, store_account.key).0, destination_account)?;
+    close_store_pda(
+        program_id,
+        metadata_account,
+        &find_metadata_address(program_id, store_account.key).0,
+        destination_account,
+    )?;
+    close_store_pda(
+        program_id,
+        book_account,
+        &find_listing_book_address(program_id, store_account.key).0,
+        destination_account,
+    )?;
+    if let Some(history_account) = history_account {
+        close_store_pda(program_id, history_account, &store.history, destination_account)?;
+    }
+
+    update_registry_entry(
+        program_id,
+        store_account.key,
+        store.registry_index,
+        registry_page_account,
+        |entry| entry.status = STORE_STATUS_CLOSED,
+    )?;
+
+    // Zero the store and hand its rent and any unclaimed proceeds over to
+    // the destination
+    store_account.try_borrow_mut_data()?.fill(0);
+    let released_lamports = store_account
+        .lamports()
+        .checked_add(proceeds_account.lamports())
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+    **store_account.try_borrow_mut_lamports()? = 0;
+    **proceeds_account.try_borrow_mut_lamports()? = 0;
+    **destination_account.try_borrow_mut_lamports()? = destination_account
+        .lamports()
+        .checked_add(released_lamports)
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+
+    Event::StoreClosed {
+        store: *store_account.key,
+        destination: *destination_account.key,
+        returned_inventory: remaining,
+    }
+    .emit();
+    msg!("Store closed successfully!");
+
+    Ok(())
+}
+
+// Closes an account kept for a store at `expected`, zeroing it and handing its
+// lamports over to `destination`. Accounts that were never created are left
+// alone.
+fn close_store_pda(program_id: &Pubkey, account: &AccountInfo, expected: &Pubkey, destination: &AccountInfo) -> ProgramResult {
+    if *account.key != *expected {
+        return Err(ProgramError::InvalidArgument);
+    }
+    if account.owner != program_id {
+        return Ok(());
+    }
+
+    account.try_borrow_mut_data()?.fill(0);
+    **destination.try_borrow_mut_lamports()? = destination
+        .lamports()
+        .checked_add(account.lamports())
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+    **account.try_borrow_mut_lamports()? = 0;
+    Ok(())
+}
+
+// Accounts: store, authority, proceeds PDA, destination, token program, then
+// for quote token withdrawals the proceeds token account, for unwrapped wSOL
+// withdrawals also the temporary wSOL account, a payer, the system program and
+// the native mint, followed by the multisig signers or the roles account of the authority.
+// The kind byte is 0 for SOL, 1 for quote tokens and 2 for wSOL unwrapped into
+// lamports.
+fn withdraw_proceeds(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    if data.len() != 9 || data[0] > 2 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let accounts_iter = &mut accounts.iter();
+
+    let store_account = next_account_info(accounts_iter)?;
+    let authority_account = next_account_info(accounts_iter)?;
+    let proceeds_account = next_account_info(accounts_iter)?;
+    let destination_account = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+
+    let withdraw_tokens = data[0] >= 1;
+    let proceeds_token_account = if withdraw_tokens {
+        Some(next_account_info(accounts_iter)?)
+    } else {
+        None
+    };
+
+    // Unwrapping wSOL proceeds goes through a temporary wSOL account funded by
+    // a signing payer
+    let unwrap_accounts = if data[0] == 2 {
+        Some((
+            next_account_info(accounts_iter)?,
+            next_account_info(accounts_iter)?,
+            next_account_info(accounts_iter)?,
+            next_account_info(accounts_iter)?,
+        ))
+    } else {
+        None
+    };
+
+    let mut store_data = store_account.try_borrow_mut_data()?;
+    let store = &mut Store::unpack(&store_data)?;
+
+    authorize(
+        program_id,
+        store_account.key,
+        store,
+        ROLE_WITHDRAWER,
+        authority_account,
+        accounts_iter.as_slice(),
+    )?;
+
+    let (proceeds_key, bump) = find_proceeds_address(program_id, store_account.key);
+    if proceeds_key != *proceeds_account.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let amount = u64::from_le_bytes(data[1..].try_into().unwrap());
+
+    if let Some(proceeds_token_account) = proceeds_token_account {
+        if *token_program.key != spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        if store.is_priced_in_sol() || store.quote_vault != *proceeds_token_account.key {
+            return Err(ProgramError::InvalidArgument);
+        }
+        let proceeds_seeds: &[&[u8]] = &[PROCEEDS_SEED, store_account.key.as_ref(), &[bump]];
+
+        if let Some((wrap_account, payer_account, system_program, native_mint)) = unwrap_accounts {
+            if store.quote_mint != spl_token::native_mint::id() {
+                return Err(ProgramError::InvalidArgument);
+            }
+            let wrap_bump = create_wrap_account(
+                program_id,
+                store_account.key,
+                payer_account,
+                wrap_account,
+                native_mint,
+                token_program,
+                system_program,
+            )?;
+            transfer_payment(
+                proceeds_token_account,
+                wrap_account,
+                proceeds_account,
+                token_program,
+                amount,
+                &[proceeds_seeds],
+            )?;
+
+            // Closing hands the unwrapped lamports and the rent to the payer,
+            // which forwards the withdrawn amount to the destination
+            let wrap_seeds: &[&[u8]] = &[WRAP_SEED, store_account.key.as_ref(), payer_account.key.as_ref(), &[wrap_bump]];
+            close_wrap_account(wrap_account, payer_account, token_program, wrap_seeds)?;
+            transfer_payment(payer_account, destination_account, payer_account, system_program, amount, &[])?;
+        } else {
+            transfer_payment(
+                proceeds_token_account,
+                destination_account,
+                proceeds_account,
+                token_program,
+                amount,
+                &[proceeds_seeds],
+            )?;
+        }
+    } else {
+        // The proceeds PDA is owned by the program, so lamports above its rent
+        // reserve can be moved directly
+        let reserve = Rent::get()?.minimum_balance(proceeds_account.data_len());
+        let available = proceeds_account.lamports().saturating_sub(reserve);
+        if amount > available {
+            return Err(ProgramError::InsufficientFunds);
+        }
+        **proceeds_account.try_borrow_mut_lamports()? -= amount;
+        **destination_account.try_borrow_mut_lamports()? = destination_account
+            .lamports()
+            .checked_add(amount)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+    }
+
+    store.withdrawn = store.withdrawn.checked_add(amount).ok_or(ProgramError::ArithmeticOverflow)?;
+
+    Store::pack(*store, &mut store_data)?;
+
+    Event::ProceedsWithdrawn {
+        store: *store_account.key,
+        destination: *destination_account.key,
+        amount,
+    }
+    .emit();
+    msg!("Proceeds withdrawn successfully!");
+
+    Ok(())
+}
+
+// The store buys tokens back at its price, paying out of its proceeds. The
+// store owner or a withdrawer approves as the authority. Accounts are the
+// store, the config, the seller token account, the vault, the mint, the
+// seller, the authority, the token program and the proceeds PDA, then for
+// quote token stores the quote token program, the quote vault and the seller
+// quote token account, the price history account if the store keeps one and
+// the accounts `authorize` takes.
+fn sell(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    if data.len() != 8 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let accounts_iter = &mut accounts.iter();
+
+    let store_account = next_account_info(accounts_iter)?;
+    let config_account = next_account_info(accounts_iter)?;
+    let seller_token_account = next_account_info(accounts_iter)?;
+    let vault_account = next_account_info(accounts_iter)?;
+    let mint_account = next_account_info(accounts_iter)?;
+    let seller_account = next_account_info(accounts_iter)?;
+    let authority_account = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let proceeds_account = next_account_info(accounts_iter)?;
+
+    let mut store_data = store_account.try_borrow_mut_data()?;
+    let store = &mut Store::unpack(&store_data)?;
+
+    let quote_accounts = if store.is_priced_in_sol() {
+        None
+    } else {
+        Some((
+            next_account_info(accounts_iter)?,
+            next_account_info(accounts_iter)?,
+            next_account_info(accounts_iter)?,
+        ))
+    };
+    let history_account = if store.history != Pubkey::default() {
+        Some(next_account_info(accounts_iter)?)
+    } else {
+        None
+    };
+
+    let config = load_config(program_id, config_account)?;
+    check_trading_allowed(store, &config)?;
+
+    if store.consignment {
+        return Err(ProgramError::Custom(28)); // Consignment store
+    }
+
+    if store.price == 0 {
+        return Err(ProgramError::Custom(2)); // Price not set
+    }
+
+    if *token_program.key != store.token_program {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    if store.vault != *vault_account.key || store.mint != *mint_account.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // Buybacks spend the store proceeds, so the owner or a withdrawer has to
+    // approve them
+    authorize(
+        program_id,
+        store_account.key,
+        store,
+        ROLE_WITHDRAWER,
+        authority_account,
+        accounts_iter.as_slice(),
+    )?;
+
+    let (proceeds_key, bump) = find_proceeds_address(program_id, store_account.key);
+    if proceeds_key != *proceeds_account.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let quantity = u64::from_le_bytes(data.try_into().unwrap());
+    let (sent, received) = settle_quantity(store, mint_account, quantity)?;
+    let payment = received.checked_mul(store.price).ok_or(ProgramError::ArithmeticOverflow)?;
+
+    // Transfer tokens from the seller back into the store vault
+    token::transfer_checked(
+        token_program,
+        seller_token_account,
+        mint_account,
+        vault_account,
+        seller_account,
+        sent,
+        store.decimals,
+        &[],
+    )?;
+
+    // Pay the seller out of the proceeds
+    if let Some((quote_token_program, quote_vault, seller_quote_account)) = quote_accounts {
+        if *quote_token_program.key != spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        if store.quote_vault != *quote_vault.key {
+            return Err(ProgramError::InvalidArgument);
+        }
+        check_quote_account(store, seller_quote_account)?;
+        transfer_payment(
+            quote_vault,
+            seller_quote_account,
+            proceeds_account,
+            quote_token_program,
+            payment,
+            &[&[PROCEEDS_SEED, store_account.key.as_ref(), &[bump]]],
+        )?;
+    } else {
+        move_lamports(proceeds_account, seller_account, payment)?;
+    }
+
+    store.bought_back = store.bought_back.checked_add(received).ok_or(ProgramError::ArithmeticOverflow)?;
+    store.supply = store.supply.checked_add(received).ok_or(ProgramError::ArithmeticOverflow)?;
+    store.accumulate(Clock::get()?.unix_timestamp);
+
+    if let Some(history_account) = history_account {
+        record_observation(program_id, store, history_account, store.price, received)?;
+    }
+
+    Store::pack(*store, &mut store_data)?;
+
+    Event::Sold {
+        store: *store_account.key,
+        seller: *seller_account.key,
+        quantity: received,
+        payment,
+    }
+    .emit();
+    msg!("Tokens sold successfully!");
+
+    Ok(())
+}
+
+// Data is the quantity and the maximum cost, optionally followed by a byte of
+// `BUY_*` option flags and the terms the flags call for.
+fn buy(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    if data.len() < 16 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+    let (flags, terms) = match &data[16..] {
+        [flags, terms @ ..] => (*flags, terms),
+        [] => (0, &[][..]),
+    };
+    if flags & !(BUY_CREATE_ATA | BUY_WRAP_SOL | BUY_VOUCHER | BUY_QUOTE) != 0 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+    let voucher_len = if flags & BUY_VOUCHER != 0 { Voucher::TERMS_LEN } else { 0 };
+    let quote_len = if flags & BUY_QUOTE != 0 { Quote::TERMS_LEN } else { 0 };
+    if terms.len() != voucher_len + quote_len {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+    let (voucher_terms, quote_terms) = terms.split_at(voucher_len);
+
+    let accounts_iter = &mut accounts.iter();
+
+    let store_account = next_account_info(accounts_iter)?;
+    let config_account = next_account_info(accounts_iter)?;
+    let vault_account = next_account_info(accounts_iter)?;
+    let mint_account = next_account_info(accounts_iter)?;
+    let vault_authority = next_account_info(accounts_iter)?;
+    let buyer_token_account = next_account_info(accounts_iter)?;
+    let buyer_account = next_account_info(accounts_iter)?;
+    let proceeds_account = next_account_info(accounts_iter)?;
+    let fee_account = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+    let receipt_account = next_account_info(accounts_iter)?;
+
+    if !buyer_account.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let associated_token_program = if flags & BUY_CREATE_ATA != 0 {
+        Some(next_account_info(accounts_iter)?)
+    } else {
+        None
+    };
+
+    // Vouchers and quotes share the instructions sysvar
+    let instructions_sysvar = if flags & (BUY_VOUCHER | BUY_QUOTE) != 0 {
+        Some(next_account_info(accounts_iter)?)
+    } else {
+        None
+    };
+    let voucher_nonce_account = if flags & BUY_VOUCHER != 0 {
+        Some(next_account_info(accounts_iter)?)
+    } else {
+        None
+    };
+    let quote = if flags & BUY_QUOTE != 0 {
+        Some((Quote::unpack_terms(*store_account.key, quote_terms)?, next_account_info(accounts_iter)?))
+    } else {
+        None
+    };
+
+    let mut store_data = store_account.try_borrow_mut_data()?;
+    let store = &mut Store::unpack(&store_data)?;
+
+    let config = load_config(program_id, config_account)?;
+    check_trading_allowed(store, &config)?;
+
+    if store.consignment {
+        return Err(ProgramError::Custom(28)); // Consignment store
+    }
+
+    // A multisig has no key of its own to sign vouchers or quotes with, the
+    // key of the multisig account would skip the signer threshold. Its
+    // stores quote through pricers.
+    let owner_signed_quote = matches!(&quote, Some((quote, _)) if quote.pricer == store.owner);
+    if store.owner_is_multisig && (voucher_nonce_account.is_some() || owner_signed_quote) {
+        return Err(ProgramError::Custom(31)); // Multisig owner
+    }
+
+    if store.price == 0 && quote.is_none() {
+        return Err(ProgramError::Custom(2)); // Price not set
+    }
+
+    if flags & BUY_WRAP_SOL != 0 && store.quote_mint != spl_token::native_mint::id() {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if *token_program.key != store.token_program {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    if store.vault != *vault_account.key || store.mint != *mint_account.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // SOL proceeds go to the proceeds PDA, quote tokens to the quote vault
+    let expected_proceeds = if store.is_priced_in_sol() {
+        find_proceeds_address(program_id, store_account.key).0
+    } else {
+        store.quote_vault
+    };
+    if expected_proceeds != *proceeds_account.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if store.supply == 0 {
+        return Err(ProgramError::Custom(4)); // Sold out
+    }
+
+    let quantity = u64::from_le_bytes(data[..8].try_into().unwrap());
+    let max_cost = u64::from_le_bytes(data[8..16].try_into().unwrap());
+    let (sent, received) = settle_quantity(store, mint_account, quantity)?;
+
+    if sent > store.supply {
+        return Err(ProgramError::Custom(5)); // Not enough inventory
+    }
+
+    if config.max_quantity != 0 && quantity > config.max_quantity {
+        return Err(ProgramError::Custom(13)); // Quantity above limit
+    }
+
+    // Quotes are signed off-chain by the store owner or a pricer and are
+    // filled at most once, up to the quoted quantity
+    let mut price = store.price;
+    if let Some((quote, nonce_account)) = quote {
+        if Clock::get()?.unix_timestamp > quote.expiry {
+            return Err(ProgramError::Custom(19)); // Signature expired
+        }
+        if quote.pricer != store.owner {
+            let roles_account = next_account_info(accounts_iter)?;
+            check_holder_role(program_id, store_account.key, roles_account, &quote.pricer, ROLE_PRICER)?;
+        }
+        if quantity > quote.quantity {
+            return Err(ProgramError::Custom(21)); // Quantity above quote
+        }
+        if quote.price == 0 || quote.price < store.min_price || (store.max_price != 0 && quote.price > store.max_price) {
+            return Err(ProgramError::Custom(15)); // Price out of bounds
+        }
+        verify_signed_message(instructions_sysvar.unwrap(), &quote.pricer, &quote.message())?;
+        consume_nonce(
+            program_id,
+            QUOTE_SEED,
+            store_account.key,
+            quote.nonce,
+            nonce_account,
+            buyer_account,
+            system_program,
+        )?;
+        price = quote.price;
+    }
+
+    let mut cost = received.checked_mul(price).ok_or(ProgramError::ArithmeticOverflow)?;
+
+    // Vouchers are signed off-chain by the store owner for one buyer, their
+    // nonce can only be used once
+    if let Some(nonce_account) = voucher_nonce_account {
+        let instructions_sysvar = instructions_sysvar.unwrap();
+        let voucher = Voucher::unpack_terms(*store_account.key, *buyer_account.key, voucher_terms)?;
+        if voucher.discount_bps >= 10_000 {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+        if Clock::get()?.unix_timestamp > voucher.expiry {
+            return Err(ProgramError::Custom(19)); // Signature expired
+        }
+        // The discounted tokens go to the buyer named in the voucher, a new
+        // associated token account is derived from the buyer key anyway
+        if associated_token_program.is_none()
+            && token::unpack_token_account(buyer_token_account)?.owner != *buyer_account.key
+        {
+            return Err(ProgramError::InvalidArgument);
+        }
+        verify_signed_message(instructions_sysvar, &store.owner, &voucher.message())?;
+        consume_nonce(
+            program_id,
+            VOUCHER_SEED,
+            store_account.key,
+            voucher.nonce,
+            nonce_account,
+            buyer_account,
+            system_program,
+        )?;
+        cost -= (cost as u128 * voucher.discount_bps as u128 / 10_000) as u64;
+    }
+
+    if cost > max_cost {
+        return Err(ProgramError::Custom(3)); // Insufficient payment
+    }
+
+    // Pay the store proceeds and the protocol fee, quote token stores are
+    // paid through the quote token program from the buyer quote token account
+    // passed next. SOL stores in escrow mode pay into the escrow account of
+    // the purchase passed next instead of the proceeds.
+    let fee = config.fee_for(cost);
+    let escrowed = store.is_priced_in_sol() && store.escrow_timeout != 0;
+    if store.is_priced_in_sol() {
+        if *system_program.key != solana_program::system_program::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        if fee > 0 && *fee_account.key != config.fee_recipient {
+            return Err(ProgramError::InvalidArgument);
+        }
+        if escrowed {
+            let escrow_account = next_account_info(accounts_iter)?;
+            open_escrow(
+                program_id,
+                store_account.key,
+                store,
+                buyer_account,
+                escrow_account,
+                system_program,
+                cost - fee,
+            )?;
+        } else {
+            transfer_payment(buyer_account, proceeds_account, buyer_account, system_program, cost - fee, &[])?;
+        }
+        transfer_payment(buyer_account, fee_account, buyer_account, system_program, fee, &[])?;
+    } else {
+        let quote_token_program = next_account_info(accounts_iter)?;
+        let buyer_quote_account = next_account_info(accounts_iter)?;
+        if *quote_token_program.key != spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        if fee > 0 {
+            if Account::unpack(&fee_account.try_borrow_data()?)?.owner != config.fee_recipient {
+                return Err(ProgramError::InvalidArgument);
+            }
+            check_quote_account(store, fee_account)?;
+        }
+        if flags & BUY_WRAP_SOL != 0 {
+            // Native lamports are wrapped into a temporary wSOL account that
+            // pays the store like a regular quote account and is closed again
+            let native_mint = next_account_info(accounts_iter)?;
+            let wrap_bump = create_wrap_account(
+                program_id,
+                store_account.key,
+                buyer_account,
+                buyer_quote_account,
+                native_mint,
+                quote_token_program,
+                system_program,
+            )?;
+            invoke(
+                &system_instruction::transfer(buyer_account.key, buyer_quote_account.key, cost),
+                &[buyer_account.clone(), buyer_quote_account.clone(), system_program.clone()],
+            )?;
+            invoke(
+                &spl_token::instruction::sync_native(quote_token_program.key, buyer_quote_account.key)?,
+                &[buyer_quote_account.clone(), quote_token_program.clone()],
+            )?;
+
+            let wrap_seeds: &[&[u8]] = &[WRAP_SEED, store_account.key.as_ref(), buyer_account.key.as_ref(), &[wrap_bump]];
+            transfer_payment(
+                buyer_quote_account,
+                proceeds_account,
+                buyer_quote_account,
+                quote_token_program,
+                cost - fee,
+                &[wrap_seeds],
+            )?;
+            transfer_payment(buyer_quote_account, fee_account, buyer_quote_account, quote_token_program, fee, &[wrap_seeds])?;
+            close_wrap_account(buyer_quote_account, buyer_account, quote_token_program, wrap_seeds)?;
+        } else {
+            check_quote_account(store, buyer_quote_account)?;
+            transfer_payment(buyer_quote_account, proceeds_account, buyer_account, quote_token_program, cost - fee, &[])?;
+            transfer_payment(buyer_quote_account, fee_account, buyer_account, quote_token_program, fee, &[])?;
+        }
+    }
+
+    // First-time buyers get their associated token account created, paid by
+    // the buyer, an existing account is left as is
+    if let Some(associated_token_program) = associated_token_program {
+        if *associated_token_program.key != spl_associated_token_account::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let buyer_ata = spl_associated_token_account::get_associated_token_address_with_program_id(
+            buyer_account.key,
+            &store.mint,
+            &store.token_program,
+        );
+        if buyer_ata != *buyer_token_account.key {
+            return Err(ProgramError::InvalidArgument);
+        }
+        let create_ata_ix = spl_associated_token_account::instruction::create_associated_token_account_idempotent(
+            buyer_account.key,
+            buyer_account.key,
+            &store.mint,
+            &store.token_program,
+        );
+        invoke(
+            &create_ata_ix,
+            &[
+                buyer_account.clone(),
+                buyer_token_account.clone(),
+                mint_account.clone(),
+                system_program.clone(),
+                token_program.clone(),
+                associated_token_program.clone(),
+            ],
+        )?;
+    }
+
+    // Release the tokens from the vault to the buyer
+    let (authority_key, bump) = find_vault_authority(program_id, store_account.key);
+    if authority_key != *vault_authority.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+    token::transfer_checked(
+        token_program,
+        vault_account,
+        mint_account,
+        buyer_token_account,
+        vault_authority,
+        sent,
+        store.decimals,
+        &[&[VAULT_SEED, store_account.key.as_ref(), &[bump]]],
+    )?;
+
+    // The price history account, if kept, comes last
+    let history_account = if store.history != Pubkey::default() {
+        Some(next_account_info(accounts_iter)?)
+    } else {
+        None
+    };
+    record_purchase(
+        program_id,
+        store_account.key,
+        store,
+        buyer_account,
+        receipt_account,
+        system_program,
+        history_account,
+        &Purchase {
+            sent,
+            received,
+            price,
+            cost,
+            fee,
+            escrowed,
+            consigned: false,
+        },
+    )?;
+
+    Store::pack(*store, &mut store_data)?;
+
+    msg!("Tokens bought successfully!");
+
+    Ok(())
+}
+
+// Reverses the purchase on a receipt: the buyer returns the tokens to the
+// vault out of their token account, and the price net of the protocol fee
+// goes back to them out of the proceeds. The buyer always signs; within the
+// store refund window that is enough, otherwise the store owner or a
+// withdrawer has to approve as the authority. Accounts are the store, the
+// receipt, the authority, the buyer, the buyer token account, the vault, the
+// mint, the token program, the proceeds PDA and the destination, then for
+// quote token stores the quote token program and the quote vault. A buyer
+// refunding on their own passes their key as the authority as well.
+fn refund(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    if !data.is_empty() {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let accounts_iter = &mut accounts.iter();
+
+    let store_account = next_account_info(accounts_iter)?;
+    let receipt_account = next_account_info(accounts_iter)?;
+    let authority_account = next_account_info(accounts_iter)?;
+    let buyer_account = next_account_info(accounts_iter)?;
+    let buyer_token_account = next_account_info(accounts_iter)?;
+    let vault_account = next_account_info(accounts_iter)?;
+    let mint_account = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let proceeds_account = next_account_info(accounts_iter)?;
+    let destination_account = next_account_info(accounts_iter)?;
+
+    let mut store_data = store_account.try_borrow_mut_data()?;
+    let store = &mut Store::unpack(&store_data)?;
+
+    let quote_accounts = if store.is_priced_in_sol() {
+        None
+    } else {
+        Some((next_account_info(accounts_iter)?, next_account_info(accounts_iter)?))
+    };
+
+    if receipt_account.owner != program_id {
+        return Err(ProgramError::InvalidArgument);
+    }
+    let mut receipt_data = receipt_account.try_borrow_mut_data()?;
+    let receipt = &mut Receipt::unpack(&receipt_data)?;
+    if receipt.store != *store_account.key
+        || *receipt_account.key != find_receipt_address(program_id, store_account.key, receipt.number).0
+    {
+        return Err(ProgramError::InvalidArgument);
+    }
+    if receipt.refunded {
+        return Err(ProgramError::Custom(22)); // Already refunded
+    }
+    if receipt.escrowed {
+        return Err(ProgramError::Custom(26)); // Escrowed purchase, settled through the escrow
+    }
+    if receipt.consigned {
+        return Err(ProgramError::Custom(28)); // Consignment purchase, paid to the sellers
+    }
+
+    if *buyer_account.key != receipt.buyer {
+        return Err(ProgramError::InvalidArgument);
+    }
+    if !buyer_account.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    if *authority_account.key == receipt.buyer && *authority_account.key != store.owner {
+        if store.refund_window == 0 || Clock::get()?.slot > receipt.slot.saturating_add(store.refund_window) {
+            return Err(ProgramError::Custom(23)); // Refund window closed
+        }
+    } else {
+        authorize(
+            program_id,
+            store_account.key,
+            store,
+            ROLE_WITHDRAWER,
+            authority_account,
+            accounts_iter.as_slice(),
+        )?;
+    }
+
+    let (proceeds_key, bump) = find_proceeds_address(program_id, store_account.key);
+    if proceeds_key != *proceeds_account.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    return_purchase(store, receipt, buyer_account, buyer_token_account, vault_account, mint_account, token_program)?;
+
+    // Pay back what reached the proceeds, the protocol fee stays with the fee
+    // recipient
+    let amount = receipt.price_paid - receipt.fee;
+    if let Some((quote_token_program, quote_vault)) = quote_accounts {
+        if *quote_token_program.key != spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        if store.quote_vault != *quote_vault.key
+            || Account::unpack(&destination_account.try_borrow_data()?)?.owner != receipt.buyer
+        {
+            return Err(ProgramError::InvalidArgument);
+        }
+        transfer_payment(
+            quote_vault,
+            destination_account,
+            proceeds_account,
+            quote_token_program,
+            amount,
+            &[&[PROCEEDS_SEED, store_account.key.as_ref(), &[bump]]],
+        )?;
+    } else {
+        if *destination_account.key != receipt.buyer {
+            return Err(ProgramError::InvalidArgument);
+        }
+        move_lamports(proceeds_account, destination_account, amount)?;
+    }
+
+    Receipt::pack(*receipt, &mut receipt_data)?;
+    Store::pack(*store, &mut store_data)?;
+
+    Event::Refunded {
+        store: *store_account.key,
+        receipt: *receipt_account.key,
+        buyer: receipt.buyer,
+        quantity: receipt.quantity,
+        amount,
+    }
+    .emit();
+    msg!("Purchase refunded successfully!");
+
+    Ok(())
+}
+
+// The buyer of a refunded purchase returns the tokens to the vault, what
+// arrives after the mint transfer fee is back on sale. Marks the receipt
+// refunded, the caller packs the store and the receipt.
+fn return_purchase<'a>(
+    store: &mut Store,
+    receipt: &mut Receipt,
+    buyer_account: &AccountInfo<'a>,
+    buyer_token_account: &AccountInfo<'a>,
+    vault_account: &AccountInfo<'a>,
+    mint_account: &AccountInfo<'a>,
+    token_program: &AccountInfo<'a>,
+) -> ProgramResult {
+    if *token_program.key != store.token_program {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    if store.vault != *vault_account.key || store.mint != *mint_account.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let returned = receipt.quantity - token::transfer_fee(mint_account, receipt.quantity)?;
+    token::transfer_checked(
+        token_program,
+        buyer_token_account,
+        mint_account,
+        vault_account,
+        buyer_account,
+        receipt.quantity,
+        store.decimals,
+        &[],
+    )?;
+
+    store.supply = store.supply.checked_add(returned).ok_or(ProgramError::ArithmeticOverflow)?;
+    store.sold = store.sold.saturating_sub(receipt.sent);
+    receipt.refunded = true;
+
+    Ok(())
+}
+
+// Data is the escrow timeout in seconds, 0 turns escrow off, followed by the
+// arbiter key, the default key for none. Only SOL-priced stores can escrow.
+fn set_escrow_settings(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    if data.len() != 40 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let accounts_iter = &mut accounts.iter();
+
+    let store_account = next_account_info(accounts_iter)?;
+    let authority_account = next_account_info(accounts_iter)?;
+
+    let mut store_data = store_account.try_borrow_mut_data()?;
+    let store = &mut Store::unpack(&store_data)?;
+
+    authorize(
+        program_id,
+        store_account.key,
+        store,
+        ROLE_ADMIN,
+        authority_account,
+        accounts_iter.as_slice(),
+    )?;
+
+    let timeout = u64::from_le_bytes(data[..8].try_into().unwrap());
+    if timeout > i64::MAX as u64 || (timeout != 0 && !store.is_priced_in_sol()) {
+        return Err(ProgramError::InvalidArgument);
+    }
+    store.escrow_timeout = timeout;
+    store.arbiter = Pubkey::new_from_array(data[8..].try_into().unwrap());
+
+    Store::pack(*store, &mut store_data)?;
+
+    Event::EscrowSettingsUpdated {
+        store: *store_account.key,
+        timeout: store.escrow_timeout,
+        arbiter: store.arbiter,
+    }
+    .emit();
+    msg!("Escrow settings updated successfully!");
+
+    Ok(())
+}
+
+// Creates the escrow account of the purchase about to get the next receipt
+// and moves the payment into it, both paid by the buyer.
+fn open_escrow<'a>(
+    program_id: &Pubkey,
+    store_key: &Pubkey,
+    store: &mut Store,
+    buyer_account: &AccountInfo<'a>,
+    escrow_account: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    amount: u64,
+) -> ProgramResult {
+    let receipt_bytes = store.receipt_count.to_le_bytes();
+    let (escrow_key, bump) = find_escrow_address(program_id, store_key, store.receipt_count);
+    if escrow_key != *escrow_account.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+    create_pda_account(
+        program_id,
+        buyer_account,
+        escrow_account,
+        system_program,
+        Escrow::LEN,
+        &[ESCROW_SEED, store_key.as_ref(), &receipt_bytes, &[bump]],
+    )?;
+    transfer_payment(buyer_account, escrow_account, buyer_account, system_program, amount, &[])?;
+
+    let escrow = Escrow {
+        is_initialized: true,
+        store: *store_key,
+        buyer: *buyer_account.key,
+        receipt: store.receipt_count,
+        amount,
+        deadline: Clock::get()?.unix_timestamp.saturating_add(store.escrow_timeout as i64),
+        status: ESCROW_PENDING,
+    };
+    Escrow::pack(escrow, &mut escrow_account.try_borrow_mut_data()?)?;
+    store.open_escrows = store.open_escrows.checked_add(1).ok_or(ProgramError::ArithmeticOverflow)?;
+
+    Event::EscrowUpdated {
+        store: *store_key,
+        escrow: escrow_key,
+        status: escrow.status,
+        amount,
+    }
+    .emit();
+
+    Ok(())
+}
+
+// The buyer confirms delivery and releases the payment to the proceeds, also
+// while disputed.
+fn confirm_delivery(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    if !data.is_empty() {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let accounts_iter = &mut accounts.iter();
+
+    let store_account = next_account_info(accounts_iter)?;
+    let escrow_account = next_account_info(accounts_iter)?;
+    let buyer_account = next_account_info(accounts_iter)?;
+    let proceeds_account = next_account_info(accounts_iter)?;
+
+    let escrow = &mut load_escrow(program_id, store_account.key, escrow_account)?;
+    if escrow.buyer != *buyer_account.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+    if !buyer_account.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    if escrow.status != ESCROW_PENDING && escrow.status != ESCROW_DISPUTED {
+        return Err(ProgramError::Custom(27)); // Escrow already settled
+    }
+
+    settle_escrow(program_id, store_account, escrow_account, escrow, proceeds_account, ESCROW_RELEASED)?;
+    msg!("Delivery confirmed successfully!");
+
+    Ok(())
+}
+
+// The seller claims an undisputed payment the buyer did not confirm before the
+// deadline. Takes the owner or a withdrawer as authority.
+fn claim_escrow(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    if !data.is_empty() {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let accounts_iter = &mut accounts.iter();
+
+    let store_account = next_account_info(accounts_iter)?;
+    let escrow_account = next_account_info(accounts_iter)?;
+    let authority_account = next_account_info(accounts_iter)?;
+    let proceeds_account = next_account_info(accounts_iter)?;
+
+    let store = Store::unpack(&store_account.try_borrow_data()?)?;
+    authorize(
+        program_id,
+        store_account.key,
+        &store,
+        ROLE_WITHDRAWER,
+        authority_account,
+        accounts_iter.as_slice(),
+    )?;
+
+    let escrow = &mut load_escrow(program_id, store_account.key, escrow_account)?;
+    if escrow.status != ESCROW_PENDING {
+        return Err(ProgramError::Custom(27)); // Escrow already settled
+    }
+    if Clock::get()?.unix_timestamp < escrow.deadline {
+        return Err(ProgramError::Custom(24)); // Escrow not expired
+    }
+
+    settle_escrow(program_id, store_account, escrow_account, escrow, proceeds_account, ESCROW_RELEASED)?;
+    msg!("Escrow claimed successfully!");
+
+    Ok(())
+}
+
+// The buyer disputes a pending payment, which stops the seller from claiming
+// it. Only stores with an arbiter accept disputes.
+fn dispute_escrow(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    if !data.is_empty() {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let accounts_iter = &mut accounts.iter();
+
+    let store_account = next_account_info(accounts_iter)?;
+    let escrow_account = next_account_info(accounts_iter)?;
+    let buyer_account = next_account_info(accounts_iter)?;
+
+    let store = Store::unpack(&store_account.try_borrow_data()?)?;
+    if store.arbiter == Pubkey::default() {
+        return Err(ProgramError::Custom(25)); // No arbiter
+    }
+
+    let escrow = &mut load_escrow(program_id, store_account.key, escrow_account)?;
+    if escrow.buyer != *buyer_account.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+    if !buyer_account.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    if escrow.status != ESCROW_PENDING {
+        return Err(ProgramError::Custom(27)); // Escrow already settled
+    }
+
+    escrow.status = ESCROW_DISPUTED;
+    Escrow::pack(*escrow, &mut escrow_account.try_borrow_mut_data()?)?;
+
+    Event::EscrowUpdated {
+        store: *store_account.key,
+        escrow: *escrow_account.key,
+        status: escrow.status,
+        amount: escrow.amount,
+    }
+    .emit();
+    msg!("Escrow disputed successfully!");
+
+    Ok(())
+}
+
+// The store arbiter settles a disputed payment, data is 0 to release it to the
+// proceeds or 1 to return it to the buyer. A refund reverses the purchase: the
+// buyer co-signs and returns the tokens, passing the receipt, their token
+// account, the vault, the mint and the token program after their own account.
+fn resolve_dispute(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    if data.len() != 1 || data[0] > 1 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let accounts_iter = &mut accounts.iter();
+
+    let store_account = next_account_info(accounts_iter)?;
+    let escrow_account = next_account_info(accounts_iter)?;
+    let arbiter_account = next_account_info(accounts_iter)?;
+    let proceeds_account = next_account_info(accounts_iter)?;
+    let buyer_account = next_account_info(accounts_iter)?;
+
+    let mut store = Store::unpack(&store_account.try_borrow_data()?)?;
+    if store.arbiter == Pubkey::default() || store.arbiter != *arbiter_account.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+    if !arbiter_account.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let escrow = &mut load_escrow(program_id, store_account.key, escrow_account)?;
+    if escrow.status != ESCROW_DISPUTED {
+        return Err(ProgramError::Custom(27)); // Escrow already settled
+    }
+
+    if data[0] == 0 {
+        settle_escrow(program_id, store_account, escrow_account, escrow, proceeds_account, ESCROW_RELEASED)?;
+    } else {
+        if escrow.buyer != *buyer_account.key {
+            return Err(ProgramError::InvalidArgument);
+        }
+        if !buyer_account.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let receipt_account = next_account_info(accounts_iter)?;
+        let buyer_token_account = next_account_info(accounts_iter)?;
+        let vault_account = next_account_info(accounts_iter)?;
+        let mint_account = next_account_info(accounts_iter)?;
+        let token_program = next_account_info(accounts_iter)?;
+
+        if receipt_account.owner != program_id
+            || *receipt_account.key != find_receipt_address(program_id, store_account.key, escrow.receipt).0
+        {
+            return Err(ProgramError::InvalidArgument);
+        }
+        let receipt = &mut Receipt::unpack(&receipt_account.try_borrow_data()?)?;
+        return_purchase(&mut store, receipt, buyer_account, buyer_token_account, vault_account, mint_account, token_program)?;
+        Receipt::pack(*receipt, &mut receipt_account.try_borrow_mut_data()?)?;
+        Store::pack(store, &mut store_account.try_borrow_mut_data()?)?;
+
+        settle_escrow(program_id, store_account, escrow_account, escrow, buyer_account, ESCROW_REFUNDED)?;
+
+        Event::Refunded {
+            store: *store_account.key,
+            receipt: *receipt_account.key,
+            buyer: receipt.buyer,
+            quantity: receipt.quantity,
+            amount: escrow.amount,
+        }
+        .emit();
+    }
+    msg!("Dispute resolved successfully!");
+
+    Ok(())
+}
+
+fn load_escrow(program_id: &Pubkey, store_key: &Pubkey, escrow_account: &AccountInfo) -> Result<Escrow, ProgramError> {
+    if escrow_account.owner != program_id {
+        return Err(ProgramError::InvalidArgument);
+    }
+    let escrow = Escrow::unpack(&escrow_account.try_borrow_data()?)?;
+    if escrow.store != *store_key || *escrow_account.key != find_escrow_address(program_id, store_key, escrow.receipt).0 {
+        return Err(ProgramError::InvalidArgument);
+    }
+    Ok(escrow)
+}
+
+// Pays the escrowed amount out to the proceeds PDA when released or to the
+// buyer when refunded, the escrow account stays as a record.
+fn settle_escrow(
+    program_id: &Pubkey,
+    store_account: &AccountInfo,
+    escrow_account: &AccountInfo,
+    escrow: &mut Escrow,
+    destination_account: &AccountInfo,
+    status: u8,
+) -> ProgramResult {
+    if status == ESCROW_RELEASED && *destination_account.key != find_proceeds_address(program_id, store_account.key).0 {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    move_lamports(escrow_account, destination_account, escrow.amount)?;
+    escrow.status = status;
+    Escrow::pack(*escrow, &mut escrow_account.try_borrow_mut_data()?)?;
+
+    let mut store_data = store_account.try_borrow_mut_data()?;
+    let mut store = Store::unpack(&store_data)?;
+    store.open_escrows = store.open_escrows.saturating_sub(1);
+    Store::pack(store, &mut store_data)?;
+
+    Event::EscrowUpdated {
+        store: *store_account.key,
+        escrow: *escrow_account.key,
+        status,
+        amount: escrow.amount,
+    }
+    .emit();
+
+    Ok(())
+}
+
+// Data is 1 to turn consignment mode on or 0 to turn it off. The listing book
+// is created, paid by the payer, the first time the mode is turned on. Only
+// SOL-priced stores without inventory can switch, and only with an empty book.
+fn set_consignment(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    if data.len() != 1 || data[0] > 1 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let accounts_iter = &mut accounts.iter();
+
+    let store_account = next_account_info(accounts_iter)?;
+    let authority_account = next_account_info(accounts_iter)?;
+    let book_account = next_account_info(accounts_iter)?;
+    let payer_account = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    let mut store_data = store_account.try_borrow_mut_data()?;
+    let store = &mut Store::unpack(&store_data)?;
+
+    authorize(
+        program_id,
+        store_account.key,
+        store,
+        ROLE_ADMIN,
+        authority_account,
+        accounts_iter.as_slice(),
+    )?;
+
+    if !store.is_priced_in_sol() {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let (book_key, bump) = find_listing_book_address(program_id, store_account.key);
+    if book_key != *book_account.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if book_account.owner != program_id {
+        create_pda_account(
+            program_id,
+            payer_account,
+            book_account,
+            system_program,
+            ListingBook::account_len(),
+            &[LISTINGS_SEED, store_account.key.as_ref(), &[bump]],
+        )?;
+        let book = ListingBook {
+            is_initialized: true,
+            store: *store_account.key,
+            len: 0,
+        };
+        book.pack_into_slice(&mut book_account.try_borrow_mut_data()?);
+    }
+
+    let enabled = data[0] == 1;
+    if enabled != store.consignment {
+        let listed = ListingBook::entries(&book_account.try_borrow_data()?)?.len();
+        if store.supply > 0 || listed > 0 {
+            return Err(ProgramError::Custom(11)); // Vault not empty
+        }
+    }
+    store.consignment = enabled;
+
+    Store::pack(*store, &mut store_data)?;
+
+    Event::ConsignmentModeUpdated {
+        store: *store_account.key,
+        enabled,
+    }
+    .emit();
+    msg!("Consignment mode updated successfully!");
+
+    Ok(())
+}
+
+// A seller consigns tokens to the vault of a consignment store at their own
+// price. Data is the price and the quantity, what arrives after the mint
+// transfer fee is listed. The seller ledger is created, paid by the seller,
+// with their first listing.
+fn list_tokens(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    if data.len() != 16 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let accounts_iter = &mut accounts.iter();
+
+    let store_account = next_account_info(accounts_iter)?;
+    let book_account = next_account_info(accounts_iter)?;
+    let seller_account = next_account_info(accounts_iter)?;
+    let seller_token_account = next_account_info(accounts_iter)?;
+    let vault_account = next_account_info(accounts_iter)?;
+    let mint_account = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let ledger_account = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    let mut store_data = store_account.try_borrow_mut_data()?;
+    let store = &mut Store::unpack(&store_data)?;
+
+    if !store.consignment {
+        return Err(ProgramError::Custom(28)); // Not a consignment store
+    }
+    if store.vault != *vault_account.key || store.mint != *mint_account.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+    if *token_program.key != store.token_program {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let price = u64::from_le_bytes(data[..8].try_into().unwrap());
+    let quantity = u64::from_le_bytes(data[8..].try_into().unwrap());
+    if price == 0 || price < store.min_price || (store.max_price != 0 && price > store.max_price) {
+        return Err(ProgramError::Custom(15)); // Price out of bounds
+    }
+
+    let mut entries = load_listings(program_id, store_account.key, book_account)?;
+    if entries.len() >= LISTING_BOOK_CAPACITY as usize {
+        return Err(ProgramError::Custom(29)); // Listing book full
+    }
+
+    let (ledger_key, ledger_bump) = find_seller_ledger_address(program_id, store_account.key, seller_account.key);
+    if ledger_key != *ledger_account.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+    if ledger_account.owner != program_id {
+        create_pda_account(
+            program_id,
+            seller_account,
+            ledger_account,
+            system_program,
+            SellerLedger::LEN,
+            &[LEDGER_SEED, store_account.key.as_ref(), seller_account.key.as_ref(), &[ledger_bump]],
+        )?;
+        let ledger = SellerLedger {
+            is_initialized: true,
+            store: *store_account.key,
+            seller: *seller_account.key,
+            accrued: 0,
+            withdrawn: 0,
+        };
+        SellerLedger::pack(ledger, &mut ledger_account.try_borrow_mut_data()?)?;
+    } else {
+        load_seller_ledger(program_id, store_account.key, seller_account.key, ledger_account)?;
+    }
+
+    token::transfer_checked(
+        token_program,
+        seller_token_account,
+        mint_account,
+        vault_account,
+        seller_account,
+        quantity,
+        store.decimals,
+        &[],
+    )?;
+    let listed = quantity - token::transfer_fee(mint_account, quantity)?;
+    if listed == 0 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let listing = Listing {
+        seller: *seller_account.key,
+        price,
+        quantity: listed,
+    };
+    entries.insert(ListingBook::insert_position(&entries, price), listing);
+    ListingBook::set_entries(&mut book_account.try_borrow_mut_data()?, &entries)?;
+
+    store.supply = store.supply.checked_add(listed).ok_or(ProgramError::ArithmeticOverflow)?;
+
+    Store::pack(*store, &mut store_data)?;
+
+    Event::Listed {
+        store: *store_account.key,
+        seller: *seller_account.key,
+        price,
+        quantity: listed,
+    }
+    .emit();
+    msg!("Tokens listed successfully!");
+
+    Ok(())
+}
+
+// A seller takes back what is left of one of their listings, data is the slot
+// of the listing in the book.
+fn delist_tokens(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    if data.len() != 2 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let accounts_iter = &mut accounts.iter();
+
+    let store_account = next_account_info(accounts_iter)?;
+    let book_account = next_account_info(accounts_iter)?;
+    let seller_account = next_account_info(accounts_iter)?;
+    let seller_token_account = next_account_info(accounts_iter)?;
+    let vault_account = next_account_info(accounts_iter)?;
+    let mint_account = next_account_info(accounts_iter)?;
+    let vault_authority = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+
+    let mut store_data = store_account.try_borrow_mut_data()?;
+    let store = &mut Store::unpack(&store_data)?;
+
+    if !seller_account.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    if store.vault != *vault_account.key || store.mint != *mint_account.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+    if *token_program.key != store.token_program {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mut entries = load_listings(program_id, store_account.key, book_account)?;
+    let slot = u16::from_le_bytes(data.try_into().unwrap()) as usize;
+    if entries.get(slot).map(|listing| listing.seller) != Some(*seller_account.key) {
+        return Err(ProgramError::InvalidArgument);
+    }
+    let listing = entries.remove(slot);
+
+    let (authority_key, bump) = find_vault_authority(program_id, store_account.key);
+    if authority_key != *vault_authority.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+    token::transfer_checked(
+        token_program,
+        vault_account,
+        mint_account,
+        seller_token_account,
+        vault_authority,
+        listing.quantity,
+        store.decimals,
+        &[&[VAULT_SEED, store_account.key.as_ref(), &[bump]]],
+    )?;
+
+    ListingBook::set_entries(&mut book_account.try_borrow_mut_data()?, &entries)?;
+    store.supply -= listing.quantity;
+
+    Store::pack(*store, &mut store_data)?;
+
+    Event::Delisted {
+        store: *store_account.key,
+        seller: listing.seller,
+        quantity: listing.quantity,
+    }
+    .emit();
+    msg!("Tokens delisted successfully!");
+
+    Ok(())
+}
+
+// Buys from a consignment store, filling the cheapest listings first. Data is
+// the quantity and the maximum total cost. The ledger of the seller of every
+// listing filled follows the receipt account, in fill order, then the price
+// history account if the store keeps one. Each seller is paid their price net
+// of the protocol fee into their ledger. The store transfer fee mode decides
+// how many listed tokens leave the vault, the buyer pays for the ones that
+// arrive and the sellers bear the mint transfer fee in proportion to what they
+// sold.
+fn buy_listings(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    if data.len() != 16 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let accounts_iter = &mut accounts.iter();
+
+    let store_account = next_account_info(accounts_iter)?;
+    let config_account = next_account_info(accounts_iter)?;
+    let book_account = next_account_info(accounts_iter)?;
+    let vault_account = next_account_info(accounts_iter)?;
+    let mint_account = next_account_info(accounts_iter)?;
+    let vault_authority = next_account_info(accounts_iter)?;
+    let buyer_token_account = next_account_info(accounts_iter)?;
+    let buyer_account = next_account_info(accounts_iter)?;
+    let fee_account = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+    let receipt_account = next_account_info(accounts_iter)?;
+
+    if !buyer_account.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let mut store_data = store_account.try_borrow_mut_data()?;
+    let store = &mut Store::unpack(&store_data)?;
+
+    let config = load_config(program_id, config_account)?;
+    check_trading_allowed(store, &config)?;
+
+    if !store.consignment {
+        return Err(ProgramError::Custom(28)); // Not a consignment store
+    }
+    if store.vault != *vault_account.key || store.mint != *mint_account.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+    if *token_program.key != store.token_program || *system_program.key != solana_program::system_program::id() {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let quantity = u64::from_le_bytes(data[..8].try_into().unwrap());
+    let max_cost = u64::from_le_bytes(data[8..].try_into().unwrap());
+    if config.max_quantity != 0 && quantity > config.max_quantity {
+        return Err(ProgramError::Custom(13)); // Quantity above limit
+    }
+    let (sent, received) = settle_quantity(store, mint_account, quantity)?;
+
+    // Work out the fills before moving anything, so the cost limit is checked
+    // against the whole purchase. The withheld transfer fee is spread over
+    // the fills by rounding its running share, so the shares add up to it
+    // and none exceeds its fill.
+    let mut entries = load_listings(program_id, store_account.key, book_account)?;
+    let withheld = (sent - received) as u128;
+    let mut fills = vec![];
+    let mut remaining = sent;
+    let mut cost: u64 = 0;
+    for listing in entries.iter_mut() {
+        if remaining == 0 {
+            break;
+        }
+        let filled = remaining.min(listing.quantity);
+        let withheld_before = withheld * (sent - remaining) as u128 / sent as u128;
+        listing.quantity -= filled;
+        remaining -= filled;
+        let fill_withheld = (withheld * (sent - remaining) as u128 / sent as u128 - withheld_before) as u64;
+        let fill_cost = (filled - fill_withheld).checked_mul(listing.price).ok_or(ProgramError::ArithmeticOverflow)?;
+        cost = cost.checked_add(fill_cost).ok_or(ProgramError::ArithmeticOverflow)?;
+        fills.push((listing.seller, filled, fill_cost));
+    }
+    if remaining > 0 {
+        return Err(ProgramError::Custom(5)); // Not enough inventory
+    }
+    if cost > max_cost {
+        return Err(ProgramError::Custom(3)); // Insufficient payment
+    }
+
+    let mut total_fee = 0;
+    for (seller, filled, fill_cost) in fills {
+        let ledger_account = next_account_info(accounts_iter)?;
+        let ledger = &mut load_seller_ledger(program_id, store_account.key, &seller, ledger_account)?;
+        let fee = config.fee_for(fill_cost);
+        transfer_payment(buyer_account, ledger_account, buyer_account, system_program, fill_cost - fee, &[])?;
+        ledger.accrued = ledger.accrued.checked_add(fill_cost - fee).ok_or(ProgramError::ArithmeticOverflow)?;
+        SellerLedger::pack(*ledger, &mut ledger_account.try_borrow_mut_data()?)?;
+        total_fee += fee;
+
+        Event::ListingFilled {
+            store: *store_account.key,
+            seller,
+            buyer: *buyer_account.key,
+            quantity: filled,
+            cost: fill_cost,
+        }
+        .emit();
+    }
+
+    if total_fee > 0 && *fee_account.key != config.fee_recipient {
+        return Err(ProgramError::InvalidArgument);
+    }
+    transfer_payment(buyer_account, fee_account, buyer_account, system_program, total_fee, &[])?;
+
+    entries.retain(|listing| listing.quantity > 0);
+    ListingBook::set_entries(&mut book_account.try_borrow_mut_data()?, &entries)?;
+
+    // Release the tokens from the vault to the buyer
+    let (authority_key, bump) = find_vault_authority(program_id, store_account.key);
+    if authority_key != *vault_authority.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+    token::transfer_checked(
+        token_program,
+        vault_account,
+        mint_account,
+        buyer_token_account,
+        vault_authority,
+        sent,
+        store.decimals,
+        &[&[VAULT_SEED, store_account.key.as_ref(), &[bump]]],
+    )?;
+
+    // Listings have their own prices, the history records the average one
+    let history_account = if store.history != Pubkey::default() {
+        Some(next_account_info(accounts_iter)?)
+    } else {
+        None
+    };
+    record_purchase(
+        program_id,
+        store_account.key,
+        store,
+        buyer_account,
+        receipt_account,
+        system_program,
+        history_account,
+        &Purchase {
+            sent,
+            received,
+            price: cost / received.max(1),
+            cost,
+            fee: total_fee,
+            escrowed: false,
+            consigned: true,
+        },
+    )?;
+
+    Store::pack(*store, &mut store_data)?;
+
+    msg!("Listings bought successfully!");
+
+    Ok(())
+}
+
+// Pays out accrued proceeds of a consignment seller, data is the amount.
+fn withdraw_seller_proceeds(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    if data.len() != 8 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let accounts_iter = &mut accounts.iter();
+
+    let store_account = next_account_info(accounts_iter)?;
+    let ledger_account = next_account_info(accounts_iter)?;
+    let seller_account = next_account_info(accounts_iter)?;
+    let destination_account = next_account_info(accounts_iter)?;
+
+    if !seller_account.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let ledger = &mut load_seller_ledger(program_id, store_account.key, seller_account.key, ledger_account)?;
+    let amount = u64::from_le_bytes(data.try_into().unwrap());
+    if amount > ledger.balance() {
+        return Err(ProgramError::InsufficientFunds);
+    }
+
+    move_lamports(ledger_account, destination_account, amount)?;
+    ledger.withdrawn += amount;
+    SellerLedger::pack(*ledger, &mut ledger_account.try_borrow_mut_data()?)?;
+
+    Event::SellerProceedsWithdrawn {
+        store: *store_account.key,
+        seller: *seller_account.key,
+        amount,
+    }
+    .emit();
+    msg!("Seller proceeds withdrawn successfully!");
+
+    Ok(())
+}
+
+fn load_listings(program_id: &Pubkey, store_key: &Pubkey, book_account: &AccountInfo) -> Result<Vec<Listing>, ProgramError> {
+    if book_account.owner != program_id || *book_account.key != find_listing_book_address(program_id, store_key).0 {
+        return Err(ProgramError::InvalidArgument);
+    }
+    ListingBook::entries(&book_account.try_borrow_data()?)
+}
+
+fn load_seller_ledger(
+    program_id: &Pubkey,
+    store_key: &Pubkey,
+    seller: &Pubkey,
+    ledger_account: &AccountInfo,
+) -> Result<SellerLedger, ProgramError> {
+    if ledger_account.owner != program_id || *ledger_account.key != find_seller_ledger_address(program_id, store_key, seller).0 {
+        return Err(ProgramError::InvalidArgument);
+    }
+    SellerLedger::unpack(&ledger_account.try_borrow_data()?)
+}
+
+// Runs several buys in one instruction, all of them succeed or none does.
+// Data is the entry count followed by one entry per buy: quantity, maximum
+// cost, `BUY_*` flags and the number of accounts of the entry. The accounts of
+// each entry are laid out exactly as for `buy`, one group after another.
+fn buy_many(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let (&count, entries) = data.split_first().ok_or(ProgramError::InvalidInstructionData)?;
+    if count == 0 || entries.len() != count as usize * BUY_MANY_ENTRY_LEN {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let mut remaining = accounts;
+    for entry in entries.chunks_exact(BUY_MANY_ENTRY_LEN) {
+        let account_count = entry[17] as usize;
+        if account_count > remaining.len() {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+        let (entry_accounts, rest) = remaining.split_at(account_count);
+        buy(program_id, entry_accounts, &entry[..17])?;
+        remaining = rest;
+    }
+
+    msg!("Batch buy completed successfully!");
+
+    Ok(())
+}
+
+// Checks that the store owner authorized the instruction, either by signing
+// directly or, for a multisig owner, through M of its N signers.
+fn validate_owner(
+    program_id: &Pubkey,
+    expected_owner: &Pubkey,
+    owner_account: &AccountInfo,
+    signers: &[AccountInfo],
+) -> ProgramResult {
+    if expected_owner != owner_account.key {
+        return Err(ProgramError::Custom(1)); // Unauthorized owner
+    }
+
+    if is_multisig(program_id, owner_account) {
+        let multisig = Multisig::unpack(&owner_account.try_borrow_data()?)?;
+        let mut num_signers = 0;
+        let mut matched = [false; MAX_SIGNERS];
+        for signer in signers.iter() {
+            for (position, key) in multisig.signers[..multisig.n as usize].iter().enumerate() {
+                if key == signer.key && !matched[position] {
+                    if !signer.is_signer {
+                        return Err(ProgramError::MissingRequiredSignature);
+                    }
+                    matched[position] = true;
+                    num_signers += 1;
+                }
+            }
+        }
+        if num_signers < multisig.m {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        return Ok(());
+    }
+
+    if !owner_account.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    Ok(())
+}
+
+fn is_multisig(program_id: &Pubkey, account: &AccountInfo) -> bool {
+    account.owner == program_id && account.data_len() == Multisig::LEN
+}
+
+// Lets either the store owner or a holder of `role` through. For anyone but
+// the owner, the first remaining account has to be the store roles account.
+fn authorize(
+    program_id: &Pubkey,
+    store_key: &Pubkey,
+    store: &Store,
+    role: u8,
+    authority_account: &AccountInfo,
+    remaining: &[AccountInfo],
+) -> ProgramResult {
+    if *authority_account.key == store.owner {
+        return validate_owner(program_id, &store.owner, authority_account, remaining);
+    }
+
+    let roles_account = remaining.first().ok_or(ProgramError::Custom(1))?; // Unauthorized owner
+    check_role(program_id, store_key, roles_account, authority_account, role)
+}
+
+fn check_role(
+    program_id: &Pubkey,
+    store_key: &Pubkey,
+    roles_account: &AccountInfo,
+    authority_account: &AccountInfo,
+    role: u8,
+) -> ProgramResult {
+    if !authority_account.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    check_holder_role(program_id, store_key, roles_account, authority_account.key, role)
+}
+
+// Role check for a holder that proves itself by other means than signing the
+// transaction, such as an off-chain signature.
+fn check_holder_role(
+    program_id: &Pubkey,
+    store_key: &Pubkey,
+    roles_account: &AccountInfo,
+    holder: &Pubkey,
+    role: u8,
+) -> ProgramResult {
+    if roles_account.owner != program_id
+        || *roles_account.key != find_roles_address(program_id, store_key).0
+    {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let roles = StoreRoles::unpack(&roles_account.try_borrow_data()?)?;
+    if roles.roles_of(holder) & role != role {
+        return Err(ProgramError::Custom(6)); // Missing role
+    }
+
+    Ok(())
+}
+
+// Amounts of a purchase booked by `record_purchase`: `sent` tokens left the
+// vault, `received` reached the buyer, who paid `cost` at `price` per token,
+// `fee` of it to the protocol.
+struct Purchase {
+    sent: u64,
+    received: u64,
+    price: u64,
+    cost: u64,
+    fee: u64,
+    escrowed: bool,
+    consigned: bool,
+}
+
+// Books a purchase whose tokens and payment were already moved: updates the
+// store counters and price accumulator, appends to the price history when the
+// store keeps one and issues the receipt, paid by the buyer. The caller packs
+// the store afterwards.
+#[allow(clippy::too_many_arguments)]
+fn record_purchase<'a>(
+    program_id: &Pubkey,
+    store_key: &Pubkey,
+    store: &mut Store,
+    buyer_account: &AccountInfo<'a>,
+    receipt_account: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    history_account: Option<&AccountInfo<'a>>,
+    purchase: &Purchase,
+) -> ProgramResult {
+    store.sold = store.sold.checked_add(purchase.sent).ok_or(ProgramError::ArithmeticOverflow)?;
+    store.supply -= purchase.sent;
+    store.accumulate(Clock::get()?.unix_timestamp);
+
+    if let Some(history_account) = history_account {
+        record_observation(program_id, store, history_account, purchase.price, purchase.received)?;
+    }
+
+    // Receipts are numbered in store order
+    let receipt_number = store.receipt_count;
+    let receipt_number_bytes = receipt_number.to_le_bytes();
+    let (receipt_key, receipt_bump) = find_receipt_address(program_id, store_key, receipt_number);
+    if receipt_key != *receipt_account.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+    create_pda_account(
+        program_id,
+        buyer_account,
+        receipt_account,
+        system_program,
+        Receipt::LEN,
+        &[RECEIPT_SEED, store_key.as_ref(), &receipt_number_bytes, &[receipt_bump]],
+    )?;
+    let receipt = Receipt {
+        is_initialized: true,
+        store: *store_key,
+        buyer: *buyer_account.key,
+        quantity: purchase.received,
+        sent: purchase.sent,
+        price_paid: purchase.cost,
+        fee: purchase.fee,
+        slot: Clock::get()?.slot,
+        number: receipt_number,
+        refunded: false,
+        escrowed: purchase.escrowed,
+        consigned: purchase.consigned,
+    };
+    Receipt::pack(receipt, &mut receipt_account.try_borrow_mut_data()?)?;
+    store.receipt_count += 1;
+
+    Event::Bought {
+        store: *store_key,
+        buyer: *buyer_account.key,
+        quantity: purchase.received,
+        cost: purchase.cost,
+    }
+    .emit();
+    Event::ReceiptIssued {
+        store: *store_key,
+        receipt: receipt_key,
+        buyer: *buyer_account.key,
+        number: receipt_number,
+    }
+    .emit();
+
+    Ok(())
+}
+
+// Splits a traded quantity into what leaves the sender and what arrives at
+// the receiver once the mint transfer fee is withheld, according to the store
+// transfer fee mode.
+fn settle_quantity(store: &Store, mint_account: &AccountInfo, quantity: u64) -> Result<(u64, u64), ProgramError> {
+    if store.transfer_fee_mode == TRANSFER_FEE_GROSS_UP {
+        let fee = token::inverse_transfer_fee(mint_account, quantity)?;
+        Ok((quantity.checked_add(fee).ok_or(ProgramError::ArithmeticOverflow)?, quantity))
+    } else {
+        let fee = token::transfer_fee(mint_account, quantity)?;
+        Ok((quantity, quantity - fee))
+    }
+}
+
+// Moves lamports out of a program-owned account, keeping its rent reserve.
+fn move_lamports(from: &AccountInfo, to: &AccountInfo, amount: u64) -> ProgramResult {
+    let reserve = Rent::get()?.minimum_balance(from.data_len());
+    if amount > from.lamports().saturating_sub(reserve) {
+        return Err(ProgramError::InsufficientFunds);
+    }
+    **from.try_borrow_mut_lamports()? -= amount;
+    **to.try_borrow_mut_lamports()? = to.lamports().checked_add(amount).ok_or(ProgramError::ArithmeticOverflow)?;
+    Ok(())
+}
+
+// Moves a payment in the store currency. `program` decides how: the system
+// program moves lamports out of `from`, the token program moves quote tokens
+// out of `from` on behalf of `authority`. Quote mints are always classic
+// Token mints.
+fn transfer_payment<'a>(
+    from: &AccountInfo<'a>,
+    to: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    program: &AccountInfo<'a>,
+    amount: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    if amount == 0 {
+        return Ok(());
+    }
+
+    if *program.key == spl_token::id() {
+        let transfer_ix = spl_token::instruction::transfer(program.key, from.key, to.key, authority.key, &[], amount)?;
+        invoke_signed(&transfer_ix, &[from.clone(), to.clone(), authority.clone(), program.clone()], signer_seeds)
+    } else if *program.key == solana_program::system_program::id() {
+        let transfer_ix = system_instruction::transfer(from.key, to.key, amount);
+        invoke(&transfer_ix, &[from.clone(), to.clone(), program.clone()])
+    } else {
+        Err(ProgramError::IncorrectProgramId)
+    }
+}
+
+// Checks that a token account taking part in a quote token payment holds the
+// store quote mint.
+fn check_quote_account(store: &Store, account: &AccountInfo) -> ProgramResult {
+    if Account::unpack(&account.try_borrow_data()?)?.mint != store.quote_mint {
+        return Err(ProgramError::InvalidArgument);
+    }
+    Ok(())
+}
+
+// Rejects prices outside the store bounds and changes larger than allowed
+// per update or within the current window, rolling the window over as needed.
+fn check_price_guardrails(store: &mut Store, new_price: u64, now: i64) -> ProgramResult {
+    if new_price < store.min_price || (store.max_price != 0 && new_price > store.max_price) {
+        return Err(ProgramError::Custom(15)); // Price out of bounds
+    }
+
+    if store.max_change_bps != 0 && change_bps(store.price, new_price) > store.max_change_bps as u128 {
+        return Err(ProgramError::Custom(16)); // Price change too large
+    }
+
+    if store.window_change_bps != 0 && store.window_seconds != 0 {
+        let window_end = store.window_start.saturating_add(store.window_seconds as i64);
+        if store.window_start == 0 || now >= window_end {
+            store.window_start = now;
+            store.window_start_price = store.price;
+        }
+        if change_bps(store.window_start_price, new_price) > store.window_change_bps as u128 {
+            return Err(ProgramError::Custom(16)); // Price change too large
+        }
+    }
+
+    Ok(())
+}
+
+// Relative change between two prices in basis points. Moving away from an
+// unset price is never limited.
+fn change_bps(from: u64, to: u64) -> u128 {
+    if from == 0 {
+        return 0;
+    }
+    (from.abs_diff(to) as u128) * 10_000 / from as u128
+}
+
+fn record_observation(program_id: &Pubkey, store: &Store, history_account: &AccountInfo, price: u64, volume: u64) -> ProgramResult {
+    if store.history != *history_account.key || history_account.owner != program_id {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let clock = Clock::get()?;
+    let observation = PriceObservation {
+        price,
+        slot: clock.slot,
+        timestamp: clock.unix_timestamp,
+        volume,
+    };
+    PriceHistory::append(&mut history_account.try_borrow_mut_data()?, &observation)
+}
+
+// Checks that `authority` signed and is the upgrade authority recorded in the
+// program data account of this program.
+fn check_upgrade_authority(program_id: &Pubkey, program_data_account: &AccountInfo, authority: &AccountInfo) -> ProgramResult {
+    let (program_data_key, _) = Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    if program_data_key != *program_data_account.key || *program_data_account.owner != bpf_loader_upgradeable::id() {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let data = program_data_account.try_borrow_data()?;
+    let metadata_len = UpgradeableLoaderState::size_of_programdata_metadata();
+    if data.len() < metadata_len {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let upgrade_authority = match limited_deserialize(&data[..metadata_len], metadata_len as u64) {
+        Ok(UpgradeableLoaderState::ProgramData {
+            upgrade_authority_address,
+            ..
+        }) => upgrade_authority_address,
+        _ => return Err(ProgramError::InvalidAccountData),
+    };
+    if upgrade_authority != Some(*authority.key) {
+        return Err(ProgramError::Custom(1)); // Unauthorized owner
+    }
+    if !authority.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    Ok(())
+}
+
+fn load_config(program_id: &Pubkey, config_account: &AccountInfo) -> Result<Config, ProgramError> {
+    if config_account.owner != program_id || *config_account.key != find_config_address(program_id).0 {
+        return Err(ProgramError::InvalidArgument);
+    }
+    Config::unpack(&config_account.try_borrow_data()?)
+}
+
+fn check_trading_allowed(store: &Store, config: &Config) -> ProgramResult {
+    if config.paused {
+        return Err(ProgramError::Custom(10)); // Trading halted
+    }
+    if store.paused {
+        return Err(ProgramError::Custom(9)); // Store paused
+    }
+    Ok(())
+}
+
+// Marks a voucher or quote nonce of a store as used by creating its marker
+// PDA, nonces that were used before already have one.
+fn consume_nonce<'a>(
+    program_id: &Pubkey,
+    seed: &[u8],
+    store_key: &Pubkey,
+    nonce: u64,
+    nonce_account: &AccountInfo<'a>,
+    payer_account: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+) -> ProgramResult {
+    let nonce_bytes = nonce.to_le_bytes();
+    let (nonce_key, bump) = Pubkey::find_program_address(&[seed, store_key.as_ref(), &nonce_bytes], program_id);
+    if nonce_key != *nonce_account.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+    if nonce_account.owner == program_id {
+        return Err(ProgramError::Custom(18)); // Nonce already used
+    }
+    create_pda_account(
+        program_id,
+        payer_account,
+        nonce_account,
+        system_program,
+        0,
+        &[seed, store_key.as_ref(), &nonce_bytes, &[bump]],
+    )
+}
+
+// Creates the temporary wSOL account of `payer` for a store, a PDA that is its
+// own token authority. It has to be closed within the same instruction.
+fn create_wrap_account<'a>(
+    program_id: &Pubkey,
+    store_key: &Pubkey,
+    payer_account: &AccountInfo<'a>,
+    wrap_account: &AccountInfo<'a>,
+    native_mint: &AccountInfo<'a>,
+    token_program: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+) -> Result<u8, ProgramError> {
+    if *token_program.key != spl_token::id() {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    if *native_mint.key != spl_token::native_mint::id() {
+        return Err(ProgramError::InvalidArgument);
+    }
+    let (wrap_key, bump) = find_wrap_address(program_id, store_key, payer_account.key);
+    if wrap_key != *wrap_account.key {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    create_pda_account(
+        token_program.key,
+        payer_account,
+        wrap_account,
+        system_program,
+        Account::LEN,
+        &[WRAP_SEED, store_key.as_ref(), payer_account.key.as_ref(), &[bump]],
+    )?;
+    invoke(
+        &spl_token::instruction::initialize_account3(
+            token_program.key,
+            wrap_account.key,
+            native_mint.key,
+            wrap_account.key,
+        )?,
+        &[wrap_account.clone(), native_mint.clone(), token_program.clone()],
+    )?;
+
+    Ok(bump)
+}
+
+// Closes a temporary wSOL account, unwrapping its lamports and rent into
+// `destination`.
+fn close_wrap_account<'a>(
+    wrap_account: &AccountInfo<'a>,
+    destination: &AccountInfo<'a>,
+    token_program: &AccountInfo<'a>,
+    wrap_seeds: &[&[u8]],
+) -> ProgramResult {
+    let close_ix = spl_token::instruction::close_account(token_program.key, wrap_account.key, destination.key, wrap_account.key, &[])?;
+    invoke_signed(
+        &close_ix,
+        &[wrap_account.clone(), destination.clone(), wrap_account.clone(), token_program.clone()],
+        &[wrap_seeds],
+    )
+}
+
+// Creates an account at a PDA of the program, owned by `owner`, which is the
+// program itself unless the account is handed over to another program. Anyone
+// can send lamports to a PDA before it is created, so an address that already
+// holds some is topped up to rent exemption and then allocated and assigned.
+fn create_pda_account<'a>(
+    owner: &Pubkey,
+    payer_account: &AccountInfo<'a>,
+    new_account: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    space: usize,
+    seeds: &[&[u8]],
+) -> ProgramResult {
+    let lamports = Rent::get()?.minimum_balance(space);
+    let current_lamports = new_account.lamports();
+    if current_lamports == 0 {
+        return invoke_signed(
+            &system_instruction::create_account(payer_account.key, new_account.key, lamports, space as u64, owner),
+            &[payer_account.clone(), new_account.clone(), system_program.clone()],
+            &[seeds],
+        );
+    }
+
+    let missing_lamports = lamports.saturating_sub(current_lamports);
+    if missing_lamports > 0 {
+        invoke(
+            &system_instruction::transfer(payer_account.key, new_account.key, missing_lamports),
+            &[payer_account.clone(), new_account.clone(), system_program.clone()],
+        )?;
+    }
+    invoke_signed(
+        &system_instruction::allocate(new_account.key, space as u64),
+        &[new_account.clone(), system_program.clone()],
+        &[seeds],
+    )?;
+    invoke_signed(
+        &system_instruction::assign(new_account.key, owner),
+        &[new_account.clone(), system_program.clone()],
+        &[seeds],
+    )
+}
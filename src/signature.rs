@@ -0,0 +1,166 @@
+//! Off-chain signed messages accepted by `buy`, checked against ed25519
+//! program instructions earlier in the same transaction.
+
+use solana_program::{
+    account_info::AccountInfo,
+    ed25519_program,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    sysvar::instructions::{load_current_index_checked, load_instruction_at_checked},
+};
+
+const VOUCHER_DOMAIN: &[u8] = b"sfxdx:voucher";
+const QUOTE_DOMAIN: &[u8] = b"sfxdx:quote";
+
+// Size of the offsets entry of one signature in ed25519 instruction data.
+const SIGNATURE_OFFSETS_LEN: usize = 14;
+
+/// Discount on a purchase, signed by the store owner. The signature is checked
+/// against the owner key as a single ed25519 key, so `buy` rejects vouchers
+/// of stores owned by a multisig.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Voucher {
+    pub store: Pubkey,
+    pub buyer: Pubkey,
+    /// Discount in basis points, below 10000 so a purchase is never free.
+    pub discount_bps: u16,
+    /// Unix timestamp after which the voucher is no longer accepted.
+    pub expiry: i64,
+    /// Single use nonce, scoped to the store.
+    pub nonce: u64,
+}
+
+impl Voucher {
+    /// Length of the voucher terms in `buy` instruction data, store and
+    /// buyer are taken from the accounts.
+    pub const TERMS_LEN: usize = 18;
+
+    pub fn unpack_terms(store: Pubkey, buyer: Pubkey, terms: &[u8]) -> Result<Self, ProgramError> {
+        if terms.len() != Self::TERMS_LEN {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+        Ok(Voucher {
+            store,
+            buyer,
+            discount_bps: u16::from_le_bytes(terms[..2].try_into().unwrap()),
+            expiry: i64::from_le_bytes(terms[2..10].try_into().unwrap()),
+            nonce: u64::from_le_bytes(terms[10..].try_into().unwrap()),
+        })
+    }
+
+    pub fn pack_terms(&self) -> Vec<u8> {
+        let mut terms = Vec::with_capacity(Self::TERMS_LEN);
+        terms.extend_from_slice(&self.discount_bps.to_le_bytes());
+        terms.extend_from_slice(&self.expiry.to_le_bytes());
+        terms.extend_from_slice(&self.nonce.to_le_bytes());
+        terms
+    }
+
+    /// Message the store owner signs.
+    pub fn message(&self) -> Vec<u8> {
+        let mut message = VOUCHER_DOMAIN.to_vec();
+        message.extend_from_slice(self.store.as_ref());
+        message.extend_from_slice(self.buyer.as_ref());
+        message.extend_from_slice(&self.pack_terms());
+        message
+    }
+}
+
+/// Price for up to `quantity` tokens, signed by the store owner or a pricer
+/// in request-for-quote mode. `buy` rejects quotes signed by a multisig owner,
+/// multisig-owned stores quote through a pricer holding an ed25519 key.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Quote {
+    pub store: Pubkey,
+    pub pricer: Pubkey,
+    pub price: u64,
+    pub quantity: u64,
+    /// Unix timestamp after which the quote is no longer accepted.
+    pub expiry: i64,
+    /// Single use nonce, scoped to the store.
+    pub nonce: u64,
+}
+
+impl Quote {
+    /// Length of the quote terms in `buy` instruction data, the store is
+    /// taken from the accounts.
+    pub const TERMS_LEN: usize = 64;
+
+    pub fn unpack_terms(store: Pubkey, terms: &[u8]) -> Result<Self, ProgramError> {
+        if terms.len() != Self::TERMS_LEN {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+        Ok(Quote {
+            store,
+            pricer: Pubkey::new_from_array(terms[..32].try_into().unwrap()),
+            price: u64::from_le_bytes(terms[32..40].try_into().unwrap()),
+            quantity: u64::from_le_bytes(terms[40..48].try_into().unwrap()),
+            expiry: i64::from_le_bytes(terms[48..56].try_into().unwrap()),
+            nonce: u64::from_le_bytes(terms[56..].try_into().unwrap()),
+        })
+    }
+
+    pub fn pack_terms(&self) -> Vec<u8> {
+        let mut terms = Vec::with_capacity(Self::TERMS_LEN);
+        terms.extend_from_slice(self.pricer.as_ref());
+        terms.extend_from_slice(&self.price.to_le_bytes());
+        terms.extend_from_slice(&self.quantity.to_le_bytes());
+        terms.extend_from_slice(&self.expiry.to_le_bytes());
+        terms.extend_from_slice(&self.nonce.to_le_bytes());
+        terms
+    }
+
+    /// Message the pricer signs.
+    pub fn message(&self) -> Vec<u8> {
+        let mut message = QUOTE_DOMAIN.to_vec();
+        message.extend_from_slice(self.store.as_ref());
+        message.extend_from_slice(&self.pack_terms());
+        message
+    }
+}
+
+/// Checks that an ed25519 program instruction before the current one
+/// verified `signer`'s signature over `message`. The precompile fails the
+/// whole transaction on a bad signature, so finding the entry is enough.
+pub fn verify_signed_message(
+    instructions_sysvar: &AccountInfo,
+    signer: &Pubkey,
+    message: &[u8],
+) -> Result<(), ProgramError> {
+    if !solana_program::sysvar::instructions::check_id(instructions_sysvar.key) {
+        return Err(ProgramError::UnsupportedSysvar);
+    }
+
+    let current_index = load_current_index_checked(instructions_sysvar)?;
+    for index in 0..current_index {
+        let instruction = load_instruction_at_checked(index as usize, instructions_sysvar)?;
+        if instruction.program_id == ed25519_program::id()
+            && ed25519_entries(&instruction.data, index).any(|(key, signed)| key == signer.as_ref() && signed == message)
+        {
+            return Ok(());
+        }
+    }
+
+    Err(ProgramError::Custom(20)) // Missing signature
+}
+
+// Public keys and messages of the signatures in ed25519 instruction data that
+// reference the instruction itself.
+fn ed25519_entries(data: &[u8], index: u16) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let count = data.first().copied().unwrap_or(0) as usize;
+    (0..count).filter_map(move |i| {
+        let start = 2 + i * SIGNATURE_OFFSETS_LEN;
+        let offsets = data.get(start..start + SIGNATURE_OFFSETS_LEN)?;
+        let field = |n: usize| u16::from_le_bytes([offsets[2 * n], offsets[2 * n + 1]]);
+        let (public_key_offset, public_key_index) = (field(2) as usize, field(3));
+        let (message_offset, message_size, message_index) = (field(4) as usize, field(5) as usize, field(6));
+        let is_inline = |ix: u16| ix == u16::MAX || ix == index;
+        if !is_inline(public_key_index) || !is_inline(message_index) {
+            return None;
+        }
+        Some((
+            data.get(public_key_offset..public_key_offset + 32)?,
+            data.get(message_offset..message_offset + message_size)?,
+        ))
+    })
+}
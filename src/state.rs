@@ -0,0 +1,1376 @@
+use arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs};
+use solana_program::{
+    program_error::ProgramError,
+    program_pack::{IsInitialized, Pack, Sealed},
+    pubkey::Pubkey,
+};
+
+pub const VAULT_SEED: &[u8] = b"vault";
+pub const ROLES_SEED: &[u8] = b"roles";
+pub const CONFIG_SEED: &[u8] = b"config";
+pub const PROCEEDS_SEED: &[u8] = b"proceeds";
+pub const HISTORY_SEED: &[u8] = b"history";
+pub const WRAP_SEED: &[u8] = b"wrap";
+pub const REGISTRY_SEED: &[u8] = b"registry";
+pub const METADATA_SEED: &[u8] = b"metadata";
+pub const VOUCHER_SEED: &[u8] = b"voucher";
+pub const QUOTE_SEED: &[u8] = b"quote";
+pub const RECEIPT_SEED: &[u8] = b"receipt";
+pub const ESCROW_SEED: &[u8] = b"escrow";
+pub const LISTINGS_SEED: &[u8] = b"listings";
+pub const LEDGER_SEED: &[u8] = b"ledger";
+
+/// Transfer fees come out of the traded quantity and only the quantity that
+/// arrives is paid for.
+pub const TRANSFER_FEE_NET_DOWN: u8 = 0;
+/// The sender covers transfer fees on top of the traded quantity so the
+/// quantity arrives in full.
+pub const TRANSFER_FEE_GROSS_UP: u8 = 1;
+
+/// Store data, owned by the program.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Store {
+    pub is_initialized: bool,
+    pub mint: Pubkey,
+    pub owner: Pubkey,
+    /// Token account holding the inventory, owned by the vault authority PDA.
+    pub vault: Pubkey,
+    pub price: u64,
+    /// Total units ever deposited into the vault.
+    pub deposited: u64,
+    /// Total units bought by customers.
+    pub sold: u64,
+    /// Total units the store bought back from holders.
+    pub bought_back: u64,
+    /// Units currently available for sale.
+    pub supply: u64,
+    /// Owner proposed by `propose_owner`, waiting to accept ownership.
+    pub pending_owner: Pubkey,
+    /// Trading on this store is halted by its owner.
+    pub paused: bool,
+    /// Lifetime amount withdrawn from the proceeds account.
+    pub withdrawn: u64,
+    /// Mint the store is priced in, `Pubkey::default()` for SOL.
+    pub quote_mint: Pubkey,
+    /// Token account of the proceeds PDA holding quote token proceeds.
+    pub quote_vault: Pubkey,
+    /// Lowest price `update_price` accepts.
+    pub min_price: u64,
+    /// Highest price `update_price` accepts, 0 means unbounded.
+    pub max_price: u64,
+    /// Largest change a single update may make, in basis points, 0 disables the check.
+    pub max_change_bps: u16,
+    /// Largest change within one window, in basis points, 0 disables the check.
+    pub window_change_bps: u16,
+    /// Length of the price change window in seconds.
+    pub window_seconds: u64,
+    /// Unix timestamp the current window started at.
+    pub window_start: i64,
+    /// Price at the start of the current window.
+    pub window_start_price: u64,
+    /// Price history account, `Pubkey::default()` when history is not kept.
+    pub history: Pubkey,
+    /// Sum of price times seconds the price was in effect.
+    pub cumulative_price: u128,
+    /// Unix timestamp `cumulative_price` was last brought up to date.
+    pub last_observed: i64,
+    /// Token program of the store mint, Token or Token-2022.
+    pub token_program: Pubkey,
+    /// Decimals of the store mint, needed for checked transfers.
+    pub decimals: u8,
+    /// How `buy` and `sell` settle mint transfer fees, one of the `TRANSFER_FEE_*` modes.
+    pub transfer_fee_mode: u8,
+    /// Number of receipts issued, the next receipt number.
+    pub receipt_count: u64,
+    /// Slots after a purchase during which the buyer may refund it, 0 disables buyer refunds.
+    pub refund_window: u64,
+    /// Seconds a buyer has to confirm delivery of an escrowed purchase, 0 settles payments immediately.
+    /// Only SOL-priced stores escrow payments.
+    pub escrow_timeout: u64,
+    /// Resolves escrow disputes, the default key when disputes are not possible.
+    pub arbiter: Pubkey,
+    /// Inventory is listed by sellers at their own prices, see `ListingBook`.
+    pub consignment: bool,
+    /// Number of escrowed payments not settled yet, the store cannot close while any are open.
+    pub open_escrows: u64,
+    /// The owner is a multisig account, which has no key to sign vouchers or quotes with.
+    pub owner_is_multisig: bool,
+    /// Index of the store in the store registry.
+    pub registry_index: u64,
+}
+
+impl Store {
+    pub fn is_priced_in_sol(&self) -> bool {
+        self.quote_mint == Pubkey::default()
+    }
+
+    /// Adds the current price for the time elapsed since the last update.
+    /// Has to run before every price change.
+    pub fn accumulate(&mut self, now: i64) {
+        let observation = self.observe(now);
+        self.cumulative_price = observation.cumulative_price;
+        self.last_observed = observation.timestamp;
+    }
+
+    /// Accumulator value as of `now`, without modifying the store.
+    pub fn observe(&self, now: i64) -> TwapObservation {
+        let elapsed = now.saturating_sub(self.last_observed).max(0) as u128;
+        TwapObservation {
+            cumulative_price: self.cumulative_price.wrapping_add(self.price as u128 * elapsed),
+            timestamp: now.max(self.last_observed),
+        }
+    }
+}
+
+/// Snapshot of a store price accumulator.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TwapObservation {
+    pub cumulative_price: u128,
+    pub timestamp: i64,
+}
+
+/// Time-weighted average price between two observations of the same store,
+/// `None` if `end` is not later than `start`.
+pub fn twap(start: &TwapObservation, end: &TwapObservation) -> Option<u64> {
+    let elapsed = end.timestamp.checked_sub(start.timestamp).filter(|elapsed| *elapsed > 0)?;
+    let average = end.cumulative_price.wrapping_sub(start.cumulative_price) / elapsed as u128;
+    u64::try_from(average).ok()
+}
+
+impl Sealed for Store {}
+
+impl IsInitialized for Store {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Pack for Store {
+    const LEN: usize = 450;
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src = array_ref![src, 0, 450];
+        let (
+            is_initialized,
+            mint,
+            owner,
+            vault,
+            price,
+            deposited,
+            sold,
+            bought_back,
+            supply,
+            pending_owner,
+            paused,
+            withdrawn,
+            quote_mint,
+            quote_vault,
+            min_price,
+            max_price,
+            max_change_bps,
+            window_change_bps,
+            window_seconds,
+            window_start,
+            window_start_price,
+            history,
+            cumulative_price,
+            last_observed,
+            token_program,
+            decimals,
+            transfer_fee_mode,
+            receipt_count,
+            refund_window,
+            escrow_timeout,
+            arbiter,
+            consignment,
+            open_escrows,
+            owner_is_multisig,
+            registry_index,
+        ) = array_refs![src, 1, 32, 32, 32, 8, 8, 8, 8, 8, 32, 1, 8, 32, 32, 8, 8, 2, 2, 8, 8, 8, 32, 16, 8, 32, 1, 1, 8, 8, 8, 32, 1, 8, 1, 8];
+        let is_initialized = match is_initialized {
+            [0] => false,
+            [1] => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        Ok(Store {
+            is_initialized,
+            mint: Pubkey::new_from_array(*mint),
+            owner: Pubkey::new_from_array(*owner),
+            vault: Pubkey::new_from_array(*vault),
+            price: u64::from_le_bytes(*price),
+            deposited: u64::from_le_bytes(*deposited),
+            sold: u64::from_le_bytes(*sold),
+            bought_back: u64::from_le_bytes(*bought_back),
+            supply: u64::from_le_bytes(*supply),
+            pending_owner: Pubkey::new_from_array(*pending_owner),
+            paused: match paused {
+                [0] => false,
+                [1] => true,
+                _ => return Err(ProgramError::InvalidAccountData),
+            },
+            withdrawn: u64::from_le_bytes(*withdrawn),
+            quote_mint: Pubkey::new_from_array(*quote_mint),
+            quote_vault: Pubkey::new_from_array(*quote_vault),
+            min_price: u64::from_le_bytes(*min_price),
+            max_price: u64::from_le_bytes(*max_price),
+            max_change_bps: u16::from_le_bytes(*max_change_bps),
+            window_change_bps: u16::from_le_bytes(*window_change_bps),
+            window_seconds: u64::from_le_bytes(*window_seconds),
+            window_start: i64::from_le_bytes(*window_start),
+            window_start_price: u64::from_le_bytes(*window_start_price),
+            history: Pubkey::new_from_array(*history),
+            cumulative_price: u128::from_le_bytes(*cumulative_price),
+            last_observed: i64::from_le_bytes(*last_observed),
+            token_program: Pubkey::new_from_array(*token_program),
+            decimals: decimals[0],
+            transfer_fee_mode: transfer_fee_mode[0],
+            receipt_count: u64::from_le_bytes(*receipt_count),
+            refund_window: u64::from_le_bytes(*refund_window),
+            escrow_timeout: u64::from_le_bytes(*escrow_timeout),
+            arbiter: Pubkey::new_from_array(*arbiter),
+            consignment: match consignment {
+                [0] => false,
+                [1] => true,
+                _ => return Err(ProgramError::InvalidAccountData),
+            },
+            open_escrows: u64::from_le_bytes(*open_escrows),
+            owner_is_multisig: match owner_is_multisig {
+                [0] => false,
+                [1] => true,
+                _ => return Err(ProgramError::InvalidAccountData),
+            },
+            registry_index: u64::from_le_bytes(*registry_index),
+        })
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, 450];
+        let (
+            is_initialized,
+            mint,
+            owner,
+            vault,
+            price,
+            deposited,
+            sold,
+            bought_back,
+            supply,
+            pending_owner,
+            paused,
+            withdrawn,
+            quote_mint,
+            quote_vault,
+            min_price,
+            max_price,
+            max_change_bps,
+            window_change_bps,
+            window_seconds,
+            window_start,
+            window_start_price,
+            history,
+            cumulative_price,
+            last_observed,
+            token_program,
+            decimals,
+            transfer_fee_mode,
+            receipt_count,
+            refund_window,
+            escrow_timeout,
+            arbiter,
+            consignment,
+            open_escrows,
+            owner_is_multisig,
+            registry_index,
+        ) = mut_array_refs![dst, 1, 32, 32, 32, 8, 8, 8, 8, 8, 32, 1, 8, 32, 32, 8, 8, 2, 2, 8, 8, 8, 32, 16, 8, 32, 1, 1, 8, 8, 8, 32, 1, 8, 1, 8];
+        is_initialized[0] = self.is_initialized as u8;
+        mint.copy_from_slice(self.mint.as_ref());
+        owner.copy_from_slice(self.owner.as_ref());
+        vault.copy_from_slice(self.vault.as_ref());
+        *price = self.price.to_le_bytes();
+        *deposited = self.deposited.to_le_bytes();
+        *sold = self.sold.to_le_bytes();
+        *bought_back = self.bought_back.to_le_bytes();
+        *supply = self.supply.to_le_bytes();
+        pending_owner.copy_from_slice(self.pending_owner.as_ref());
+        *paused = [self.paused as u8];
+        *withdrawn = self.withdrawn.to_le_bytes();
+        quote_mint.copy_from_slice(self.quote_mint.as_ref());
+        quote_vault.copy_from_slice(self.quote_vault.as_ref());
+        *min_price = self.min_price.to_le_bytes();
+        *max_price = self.max_price.to_le_bytes();
+        *max_change_bps = self.max_change_bps.to_le_bytes();
+        *window_change_bps = self.window_change_bps.to_le_bytes();
+        *window_seconds = self.window_seconds.to_le_bytes();
+        *window_start = self.window_start.to_le_bytes();
+        *window_start_price = self.window_start_price.to_le_bytes();
+        history.copy_from_slice(self.history.as_ref());
+        *cumulative_price = self.cumulative_price.to_le_bytes();
+        *last_observed = self.last_observed.to_le_bytes();
+        token_program.copy_from_slice(self.token_program.as_ref());
+        *decimals = [self.decimals];
+        *transfer_fee_mode = [self.transfer_fee_mode];
+        *receipt_count = self.receipt_count.to_le_bytes();
+        *refund_window = self.refund_window.to_le_bytes();
+        *escrow_timeout = self.escrow_timeout.to_le_bytes();
+        arbiter.copy_from_slice(self.arbiter.as_ref());
+        *consignment = [self.consignment as u8];
+        *open_escrows = self.open_escrows.to_le_bytes();
+        *owner_is_multisig = [self.owner_is_multisig as u8];
+        *registry_index = self.registry_index.to_le_bytes();
+    }
+}
+
+/// One entry of the price history ring buffer.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PriceObservation {
+    pub price: u64,
+    pub slot: u64,
+    pub timestamp: i64,
+    /// Units traded, 0 for price updates.
+    pub volume: u64,
+}
+
+impl Sealed for PriceObservation {}
+
+impl Pack for PriceObservation {
+    const LEN: usize = 32;
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src = array_ref![src, 0, 32];
+        let (price, slot, timestamp, volume) = array_refs![src, 8, 8, 8, 8];
+        Ok(PriceObservation {
+            price: u64::from_le_bytes(*price),
+            slot: u64::from_le_bytes(*slot),
+            timestamp: i64::from_le_bytes(*timestamp),
+            volume: u64::from_le_bytes(*volume),
+        })
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, 32];
+        let (price, slot, timestamp, volume) = mut_array_refs![dst, 8, 8, 8, 8];
+        *price = self.price.to_le_bytes();
+        *slot = self.slot.to_le_bytes();
+        *timestamp = self.timestamp.to_le_bytes();
+        *volume = self.volume.to_le_bytes();
+    }
+}
+
+/// Header of the price history account, followed by `capacity` packed
+/// `PriceObservation`s used as a ring buffer.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PriceHistory {
+    pub is_initialized: bool,
+    pub store: Pubkey,
+    pub capacity: u16,
+    /// Slot the next observation is written to.
+    pub head: u16,
+    /// Number of observations stored so far, at most `capacity`.
+    pub len: u16,
+}
+
+impl PriceHistory {
+    pub fn account_len(capacity: u16) -> usize {
+        Self::LEN + capacity as usize * PriceObservation::LEN
+    }
+
+    /// Writes an observation over the oldest one once the buffer is full.
+    pub fn append(data: &mut [u8], observation: &PriceObservation) -> Result<(), ProgramError> {
+        let mut history = Self::unpack(&data[..Self::LEN])?;
+        if history.capacity == 0 || data.len() < Self::account_len(history.capacity) {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let offset = Self::LEN + history.head as usize * PriceObservation::LEN;
+        observation.pack_into_slice(&mut data[offset..offset + PriceObservation::LEN]);
+        history.head = (history.head + 1) % history.capacity;
+        history.len = history.len.saturating_add(1).min(history.capacity);
+
+        history.pack_into_slice(&mut data[..Self::LEN]);
+        Ok(())
+    }
+
+    /// Returns the stored observations, oldest first.
+    pub fn observations(data: &[u8]) -> Result<Vec<PriceObservation>, ProgramError> {
+        let history = Self::unpack(&data[..Self::LEN.min(data.len())])?;
+        if data.len() < Self::account_len(history.capacity) {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let start = (history.head as usize + history.capacity as usize - history.len as usize) % history.capacity.max(1) as usize;
+        (0..history.len as usize)
+            .map(|i| {
+                let offset = Self::LEN + (start + i) % history.capacity as usize * PriceObservation::LEN;
+                PriceObservation::unpack_from_slice(&data[offset..offset + PriceObservation::LEN])
+            })
+            .collect()
+    }
+}
+
+impl Sealed for PriceHistory {}
+
+impl IsInitialized for PriceHistory {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Pack for PriceHistory {
+    const LEN: usize = 39;
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src = array_ref![src, 0, 39];
+        let (is_initialized, store, capacity, head, len) = array_refs![src, 1, 32, 2, 2, 2];
+        Ok(PriceHistory {
+            is_initialized: match is_initialized {
+                [0] => false,
+                [1] => true,
+                _ => return Err(ProgramError::InvalidAccountData),
+            },
+            store: Pubkey::new_from_array(*store),
+            capacity: u16::from_le_bytes(*capacity),
+            head: u16::from_le_bytes(*head),
+            len: u16::from_le_bytes(*len),
+        })
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, 39];
+        let (is_initialized, store, capacity, head, len) = mut_array_refs![dst, 1, 32, 2, 2, 2];
+        *is_initialized = [self.is_initialized as u8];
+        store.copy_from_slice(self.store.as_ref());
+        *capacity = self.capacity.to_le_bytes();
+        *head = self.head.to_le_bytes();
+        *len = self.len.to_le_bytes();
+    }
+}
+
+pub const MAX_NAME_LEN: usize = 64;
+pub const MAX_DESCRIPTION_LEN: usize = 512;
+pub const MAX_URI_LEN: usize = 200;
+pub const MAX_CATEGORY_LEN: usize = 32;
+
+/// Descriptive store data kept in a companion PDA. The account is resized to
+/// its contents on every update, the strings are stored with a u16 length
+/// prefix after the header.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StoreMetadata {
+    pub is_initialized: bool,
+    pub store: Pubkey,
+    pub name: String,
+    pub description: String,
+    /// Image URI of the store.
+    pub uri: String,
+    pub category: String,
+}
+
+impl StoreMetadata {
+    const HEADER_LEN: usize = 33;
+
+    /// Reads the strings as laid out after the header, which is also the
+    /// `set_store_metadata` instruction data.
+    pub fn unpack_fields(store: Pubkey, src: &[u8]) -> Result<Self, ProgramError> {
+        let mut src = src;
+        let metadata = StoreMetadata {
+            is_initialized: true,
+            store,
+            name: read_string(&mut src)?,
+            description: read_string(&mut src)?,
+            uri: read_string(&mut src)?,
+            category: read_string(&mut src)?,
+        };
+        if !src.is_empty() {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+        Ok(metadata)
+    }
+
+    pub fn pack_fields(&self) -> Vec<u8> {
+        let mut dst = Vec::with_capacity(self.account_len() - Self::HEADER_LEN);
+        for field in [&self.name, &self.description, &self.uri, &self.category] {
+            dst.extend_from_slice(&(field.len() as u16).to_le_bytes());
+            dst.extend_from_slice(field.as_bytes());
+        }
+        dst
+    }
+
+    pub fn unpack(src: &[u8]) -> Result<Self, ProgramError> {
+        if src.len() < Self::HEADER_LEN || src[0] != 1 {
+            return Err(ProgramError::UninitializedAccount);
+        }
+        let store = Pubkey::try_from(&src[1..Self::HEADER_LEN]).unwrap();
+        Self::unpack_fields(store, &src[Self::HEADER_LEN..]).map_err(|_| ProgramError::InvalidAccountData)
+    }
+
+    pub fn pack(&self) -> Vec<u8> {
+        let mut dst = vec![self.is_initialized as u8];
+        dst.extend_from_slice(self.store.as_ref());
+        dst.extend_from_slice(&self.pack_fields());
+        dst
+    }
+
+    pub fn account_len(&self) -> usize {
+        Self::HEADER_LEN + 4 * 2 + self.name.len() + self.description.len() + self.uri.len() + self.category.len()
+    }
+
+    pub fn is_within_limits(&self) -> bool {
+        self.name.len() <= MAX_NAME_LEN
+            && self.description.len() <= MAX_DESCRIPTION_LEN
+            && self.uri.len() <= MAX_URI_LEN
+            && self.category.len() <= MAX_CATEGORY_LEN
+    }
+}
+
+fn read_string(src: &mut &[u8]) -> Result<String, ProgramError> {
+    if src.len() < 2 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+    let (len, rest) = src.split_at(2);
+    let len = u16::from_le_bytes([len[0], len[1]]) as usize;
+    if rest.len() < len {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+    let (bytes, rest) = rest.split_at(len);
+    *src = rest;
+    String::from_utf8(bytes.to_vec()).map_err(|_| ProgramError::InvalidInstructionData)
+}
+
+/// Number of stores listed on one registry page.
+pub const REGISTRY_PAGE_CAPACITY: u16 = 32;
+
+pub const STORE_STATUS_ACTIVE: u8 = 0;
+pub const STORE_STATUS_PAUSED: u8 = 1;
+pub const STORE_STATUS_CLOSED: u8 = 2;
+
+/// One store listed in the registry.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RegistryEntry {
+    pub store: Pubkey,
+    pub mint: Pubkey,
+    pub owner: Pubkey,
+    /// One of the `STORE_STATUS_*` values.
+    pub status: u8,
+}
+
+impl Sealed for RegistryEntry {}
+
+impl Pack for RegistryEntry {
+    const LEN: usize = 97;
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src = array_ref![src, 0, 97];
+        let (store, mint, owner, status) = array_refs![src, 32, 32, 32, 1];
+        Ok(RegistryEntry {
+            store: Pubkey::new_from_array(*store),
+            mint: Pubkey::new_from_array(*mint),
+            owner: Pubkey::new_from_array(*owner),
+            status: status[0],
+        })
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, 97];
+        let (store, mint, owner, status) = mut_array_refs![dst, 32, 32, 32, 1];
+        store.copy_from_slice(self.store.as_ref());
+        mint.copy_from_slice(self.mint.as_ref());
+        owner.copy_from_slice(self.owner.as_ref());
+        *status = [self.status];
+    }
+}
+
+/// Header of a registry page, followed by `REGISTRY_PAGE_CAPACITY` packed
+/// `RegistryEntry`s. Stores are listed in the order they were initialized,
+/// store `index` lives on page `index / REGISTRY_PAGE_CAPACITY`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RegistryPage {
+    pub is_initialized: bool,
+    pub page: u32,
+    /// Number of used entries.
+    pub len: u16,
+}
+
+impl RegistryPage {
+    pub fn account_len() -> usize {
+        Self::LEN + REGISTRY_PAGE_CAPACITY as usize * RegistryEntry::LEN
+    }
+
+    /// Page and slot of the store with the given registry index.
+    pub fn position(index: u64) -> (u32, u16) {
+        let capacity = REGISTRY_PAGE_CAPACITY as u64;
+        ((index / capacity) as u32, (index % capacity) as u16)
+    }
+
+    /// Number of pages needed to list `store_count` stores.
+    pub fn page_count(store_count: u64) -> u32 {
+        store_count.div_ceil(REGISTRY_PAGE_CAPACITY as u64) as u32
+    }
+
+    /// Appends an entry, failing once the page is full.
+    pub fn push(data: &mut [u8], entry: &RegistryEntry) -> Result<(), ProgramError> {
+        let mut page = Self::unpack(&data[..Self::LEN])?;
+        if page.len >= REGISTRY_PAGE_CAPACITY || data.len() < Self::account_len() {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        entry.pack_into_slice(Self::entry_slice(data, page.len));
+        page.len += 1;
+        page.pack_into_slice(&mut data[..Self::LEN]);
+        Ok(())
+    }
+
+    pub fn entry(data: &[u8], slot: u16) -> Result<RegistryEntry, ProgramError> {
+        let page = Self::unpack(&data[..Self::LEN.min(data.len())])?;
+        if slot >= page.len || data.len() < Self::account_len() {
+            return Err(ProgramError::InvalidArgument);
+        }
+        let offset = Self::LEN + slot as usize * RegistryEntry::LEN;
+        RegistryEntry::unpack_from_slice(&data[offset..offset + RegistryEntry::LEN])
+    }
+
+    pub fn set_entry(data: &mut [u8], slot: u16, entry: &RegistryEntry) -> Result<(), ProgramError> {
+        Self::entry(data, slot)?;
+        entry.pack_into_slice(Self::entry_slice(data, slot));
+        Ok(())
+    }
+
+    /// Returns the stores listed on a page, in registry order.
+    pub fn entries(data: &[u8]) -> Result<Vec<RegistryEntry>, ProgramError> {
+        let page = Self::unpack(&data[..Self::LEN.min(data.len())])?;
+        (0..page.len).map(|slot| Self::entry(data, slot)).collect()
+    }
+
+    fn entry_slice(data: &mut [u8], slot: u16) -> &mut [u8] {
+        let offset = Self::LEN + slot as usize * RegistryEntry::LEN;
+        &mut data[offset..offset + RegistryEntry::LEN]
+    }
+}
+
+impl Sealed for RegistryPage {}
+
+impl IsInitialized for RegistryPage {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Pack for RegistryPage {
+    const LEN: usize = 7;
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src = array_ref![src, 0, 7];
+        let (is_initialized, page, len) = array_refs![src, 1, 4, 2];
+        Ok(RegistryPage {
+            is_initialized: match is_initialized {
+                [0] => false,
+                [1] => true,
+                _ => return Err(ProgramError::InvalidAccountData),
+            },
+            page: u32::from_le_bytes(*page),
+            len: u16::from_le_bytes(*len),
+        })
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, 7];
+        let (is_initialized, page, len) = mut_array_refs![dst, 1, 4, 2];
+        *is_initialized = [self.is_initialized as u8];
+        *page = self.page.to_le_bytes();
+        *len = self.len.to_le_bytes();
+    }
+}
+
+/// Record of one purchase, created by `buy` and `buy_listings` at the address
+/// derived from the store and the receipt number.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Receipt {
+    pub is_initialized: bool,
+    pub store: Pubkey,
+    pub buyer: Pubkey,
+    /// Tokens received by the buyer.
+    pub quantity: u64,
+    /// Tokens that left the vault, `quantity` plus the mint transfer fee.
+    pub sent: u64,
+    /// Total paid in the store currency, protocol fee included.
+    pub price_paid: u64,
+    /// Protocol fee included in `price_paid`, kept by the fee recipient.
+    pub fee: u64,
+    pub slot: u64,
+    pub number: u64,
+    /// Set once the purchase was refunded, a receipt is refunded at most once.
+    pub refunded: bool,
+    /// Set when the payment went to a delivery escrow instead of the proceeds.
+    pub escrowed: bool,
+    /// Set when the tokens were bought from consignment listings and the
+    /// payment went to the sellers.
+    pub consigned: bool,
+}
+
+impl Sealed for Receipt {}
+
+impl IsInitialized for Receipt {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Pack for Receipt {
+    const LEN: usize = 116;
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src = array_ref![src, 0, 116];
+        let (is_initialized, store, buyer, quantity, sent, price_paid, fee, slot, number, refunded, escrowed, consigned) =
+            array_refs![src, 1, 32, 32, 8, 8, 8, 8, 8, 8, 1, 1, 1];
+        Ok(Receipt {
+            is_initialized: match is_initialized {
+                [0] => false,
+                [1] => true,
+                _ => return Err(ProgramError::InvalidAccountData),
+            },
+            store: Pubkey::new_from_array(*store),
+            buyer: Pubkey::new_from_array(*buyer),
+            quantity: u64::from_le_bytes(*quantity),
+            sent: u64::from_le_bytes(*sent),
+            price_paid: u64::from_le_bytes(*price_paid),
+            fee: u64::from_le_bytes(*fee),
+            slot: u64::from_le_bytes(*slot),
+            number: u64::from_le_bytes(*number),
+            refunded: match refunded {
+                [0] => false,
+                [1] => true,
+                _ => return Err(ProgramError::InvalidAccountData),
+            },
+            escrowed: match escrowed {
+                [0] => false,
+                [1] => true,
+                _ => return Err(ProgramError::InvalidAccountData),
+            },
+            consigned: match consigned {
+                [0] => false,
+                [1] => true,
+                _ => return Err(ProgramError::InvalidAccountData),
+            },
+        })
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, 116];
+        let (is_initialized, store, buyer, quantity, sent, price_paid, fee, slot, number, refunded, escrowed, consigned) =
+            mut_array_refs![dst, 1, 32, 32, 8, 8, 8, 8, 8, 8, 1, 1, 1];
+        *is_initialized = [self.is_initialized as u8];
+        store.copy_from_slice(self.store.as_ref());
+        buyer.copy_from_slice(self.buyer.as_ref());
+        *quantity = self.quantity.to_le_bytes();
+        *sent = self.sent.to_le_bytes();
+        *price_paid = self.price_paid.to_le_bytes();
+        *fee = self.fee.to_le_bytes();
+        *slot = self.slot.to_le_bytes();
+        *number = self.number.to_le_bytes();
+        *refunded = [self.refunded as u8];
+        *escrowed = [self.escrowed as u8];
+        *consigned = [self.consigned as u8];
+    }
+}
+
+pub const ESCROW_PENDING: u8 = 0;
+pub const ESCROW_DISPUTED: u8 = 1;
+pub const ESCROW_RELEASED: u8 = 2;
+pub const ESCROW_REFUNDED: u8 = 3;
+
+/// Payment of an escrowed purchase, held in the lamports of the escrow PDA
+/// until the buyer confirms delivery, the seller claims it after the
+/// deadline or the store arbiter resolves a dispute.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Escrow {
+    pub is_initialized: bool,
+    pub store: Pubkey,
+    pub buyer: Pubkey,
+    /// Number of the receipt of the purchase.
+    pub receipt: u64,
+    /// Lamports held for the seller, the protocol fee is paid up front.
+    pub amount: u64,
+    /// Unix timestamp from which the seller may claim an undisputed escrow.
+    pub deadline: i64,
+    /// One of the `ESCROW_*` states.
+    pub status: u8,
+}
+
+impl Sealed for Escrow {}
+
+impl IsInitialized for Escrow {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Pack for Escrow {
+    const LEN: usize = 90;
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src = array_ref![src, 0, 90];
+        let (is_initialized, store, buyer, receipt, amount, deadline, status) = array_refs![src, 1, 32, 32, 8, 8, 8, 1];
+        Ok(Escrow {
+            is_initialized: match is_initialized {
+                [0] => false,
+                [1] => true,
+                _ => return Err(ProgramError::InvalidAccountData),
+            },
+            store: Pubkey::new_from_array(*store),
+            buyer: Pubkey::new_from_array(*buyer),
+            receipt: u64::from_le_bytes(*receipt),
+            amount: u64::from_le_bytes(*amount),
+            deadline: i64::from_le_bytes(*deadline),
+            status: status[0],
+        })
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, 90];
+        let (is_initialized, store, buyer, receipt, amount, deadline, status) =
+            mut_array_refs![dst, 1, 32, 32, 8, 8, 8, 1];
+        *is_initialized = [self.is_initialized as u8];
+        store.copy_from_slice(self.store.as_ref());
+        buyer.copy_from_slice(self.buyer.as_ref());
+        *receipt = self.receipt.to_le_bytes();
+        *amount = self.amount.to_le_bytes();
+        *deadline = self.deadline.to_le_bytes();
+        *status = [self.status];
+    }
+}
+
+/// Maximum number of listings a consignment store holds at once.
+pub const LISTING_BOOK_CAPACITY: u16 = 32;
+
+/// Tokens a seller consigned to a store vault, for sale at the seller's price.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Listing {
+    pub seller: Pubkey,
+    pub price: u64,
+    /// Tokens left for sale.
+    pub quantity: u64,
+}
+
+impl Sealed for Listing {}
+
+impl Pack for Listing {
+    const LEN: usize = 48;
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src = array_ref![src, 0, 48];
+        let (seller, price, quantity) = array_refs![src, 32, 8, 8];
+        Ok(Listing {
+            seller: Pubkey::new_from_array(*seller),
+            price: u64::from_le_bytes(*price),
+            quantity: u64::from_le_bytes(*quantity),
+        })
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, 48];
+        let (seller, price, quantity) = mut_array_refs![dst, 32, 8, 8];
+        seller.copy_from_slice(self.seller.as_ref());
+        *price = self.price.to_le_bytes();
+        *quantity = self.quantity.to_le_bytes();
+    }
+}
+
+/// Header of the listing book of a consignment store, followed by
+/// `LISTING_BOOK_CAPACITY` packed `Listing`s. Listings are kept sorted by
+/// price, listings at the same price in the order they were made, so buyers
+/// always fill from the front.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ListingBook {
+    pub is_initialized: bool,
+    pub store: Pubkey,
+    /// Number of used entries.
+    pub len: u16,
+}
+
+impl ListingBook {
+    pub fn account_len() -> usize {
+        Self::LEN + LISTING_BOOK_CAPACITY as usize * Listing::LEN
+    }
+
+    /// Returns the listings, cheapest first.
+    pub fn entries(data: &[u8]) -> Result<Vec<Listing>, ProgramError> {
+        let book = Self::unpack(&data[..Self::LEN.min(data.len())])?;
+        if data.len() < Self::account_len() {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        (0..book.len as usize)
+            .map(|slot| {
+                let offset = Self::LEN + slot * Listing::LEN;
+                Listing::unpack_from_slice(&data[offset..offset + Listing::LEN])
+            })
+            .collect()
+    }
+
+    /// Replaces the listings, which have to be sorted already.
+    pub fn set_entries(data: &mut [u8], entries: &[Listing]) -> Result<(), ProgramError> {
+        let mut book = Self::unpack(&data[..Self::LEN])?;
+        if entries.len() > LISTING_BOOK_CAPACITY as usize || data.len() < Self::account_len() {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        for (slot, entry) in entries.iter().enumerate() {
+            let offset = Self::LEN + slot * Listing::LEN;
+            entry.pack_into_slice(&mut data[offset..offset + Listing::LEN]);
+        }
+        book.len = entries.len() as u16;
+        book.pack_into_slice(&mut data[..Self::LEN]);
+        Ok(())
+    }
+
+    /// Slot a new listing at `price` goes to, after all listings at the same
+    /// or a lower price.
+    pub fn insert_position(entries: &[Listing], price: u64) -> usize {
+        entries.partition_point(|entry| entry.price <= price)
+    }
+}
+
+impl Sealed for ListingBook {}
+
+impl IsInitialized for ListingBook {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Pack for ListingBook {
+    const LEN: usize = 35;
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src = array_ref![src, 0, 35];
+        let (is_initialized, store, len) = array_refs![src, 1, 32, 2];
+        Ok(ListingBook {
+            is_initialized: match is_initialized {
+                [0] => false,
+                [1] => true,
+                _ => return Err(ProgramError::InvalidAccountData),
+            },
+            store: Pubkey::new_from_array(*store),
+            len: u16::from_le_bytes(*len),
+        })
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, 35];
+        let (is_initialized, store, len) = mut_array_refs![dst, 1, 32, 2];
+        *is_initialized = [self.is_initialized as u8];
+        store.copy_from_slice(self.store.as_ref());
+        *len = self.len.to_le_bytes();
+    }
+}
+
+/// Proceeds of one seller in a consignment store. The accrued lamports are
+/// held by the ledger PDA itself until the seller withdraws them.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SellerLedger {
+    pub is_initialized: bool,
+    pub store: Pubkey,
+    pub seller: Pubkey,
+    /// Lamports earned from filled listings, net of the protocol fee.
+    pub accrued: u64,
+    pub withdrawn: u64,
+}
+
+impl SellerLedger {
+    pub fn balance(&self) -> u64 {
+        self.accrued - self.withdrawn
+    }
+}
+
+impl Sealed for SellerLedger {}
+
+impl IsInitialized for SellerLedger {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Pack for SellerLedger {
+    const LEN: usize = 81;
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src = array_ref![src, 0, 81];
+        let (is_initialized, store, seller, accrued, withdrawn) = array_refs![src, 1, 32, 32, 8, 8];
+        Ok(SellerLedger {
+            is_initialized: match is_initialized {
+                [0] => false,
+                [1] => true,
+                _ => return Err(ProgramError::InvalidAccountData),
+            },
+            store: Pubkey::new_from_array(*store),
+            seller: Pubkey::new_from_array(*seller),
+            accrued: u64::from_le_bytes(*accrued),
+            withdrawn: u64::from_le_bytes(*withdrawn),
+        })
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, 81];
+        let (is_initialized, store, seller, accrued, withdrawn) = mut_array_refs![dst, 1, 32, 32, 8, 8];
+        *is_initialized = [self.is_initialized as u8];
+        store.copy_from_slice(self.store.as_ref());
+        seller.copy_from_slice(self.seller.as_ref());
+        *accrued = self.accrued.to_le_bytes();
+        *withdrawn = self.withdrawn.to_le_bytes();
+    }
+}
+
+/// Maximum number of quote mints a config can allow besides SOL.
+pub const MAX_QUOTE_MINTS: usize = 8;
+
+/// Program-wide settings, kept in a singleton PDA. Read by the handlers the
+/// settings apply to: `initialize_store` for the default price, the quote
+/// mints and the registry, and `buy`, `sell` and `buy_listings` for the pause
+/// flag, the fee and the quantity limit. Store management does not depend on
+/// it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Config {
+    pub is_initialized: bool,
+    /// Key (or multisig) allowed to change the config.
+    pub admin: Pubkey,
+    /// Global circuit breaker, halts trading on every store.
+    pub paused: bool,
+    /// Price given to newly initialized stores.
+    pub default_price: u64,
+    /// Protocol fee taken from every purchase, in basis points.
+    pub fee_bps: u16,
+    pub fee_recipient: Pubkey,
+    /// Largest quantity a single purchase may request, 0 means unlimited.
+    pub max_quantity: u64,
+    /// Number of used entries in `quote_mints`.
+    pub quote_mint_count: u8,
+    /// Mints stores may be priced in, SOL is always allowed.
+    pub quote_mints: [Pubkey; MAX_QUOTE_MINTS],
+    /// Number of stores ever initialized, the registry index of the next one.
+    pub store_count: u64,
+}
+
+impl Config {
+    pub fn is_quote_mint_allowed(&self, mint: &Pubkey) -> bool {
+        *mint == Pubkey::default() || self.quote_mints[..self.quote_mint_count as usize].contains(mint)
+    }
+
+    pub fn fee_for(&self, amount: u64) -> u64 {
+        (amount as u128 * self.fee_bps as u128 / 10_000) as u64
+    }
+}
+
+impl Sealed for Config {}
+
+impl IsInitialized for Config {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Pack for Config {
+    const LEN: usize = 349;
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src = array_ref![src, 0, 349];
+        let (
+            is_initialized,
+            admin,
+            paused,
+            default_price,
+            fee_bps,
+            fee_recipient,
+            max_quantity,
+            quote_mint_count,
+            quote_mints_flat,
+            store_count,
+        ) = array_refs![src, 1, 32, 1, 8, 2, 32, 8, 1, 32 * MAX_QUOTE_MINTS, 8];
+        let mut result = Config {
+            is_initialized: match is_initialized {
+                [0] => false,
+                [1] => true,
+                _ => return Err(ProgramError::InvalidAccountData),
+            },
+            admin: Pubkey::new_from_array(*admin),
+            paused: match paused {
+                [0] => false,
+                [1] => true,
+                _ => return Err(ProgramError::InvalidAccountData),
+            },
+            default_price: u64::from_le_bytes(*default_price),
+            fee_bps: u16::from_le_bytes(*fee_bps),
+            fee_recipient: Pubkey::new_from_array(*fee_recipient),
+            max_quantity: u64::from_le_bytes(*max_quantity),
+            quote_mint_count: quote_mint_count[0],
+            quote_mints: [Pubkey::default(); MAX_QUOTE_MINTS],
+            store_count: u64::from_le_bytes(*store_count),
+        };
+        if result.quote_mint_count as usize > MAX_QUOTE_MINTS {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        for (src, dst) in quote_mints_flat.chunks(32).zip(result.quote_mints.iter_mut()) {
+            *dst = Pubkey::try_from(src).map_err(|_| ProgramError::InvalidAccountData)?;
+        }
+        Ok(result)
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, 349];
+        let (
+            is_initialized,
+            admin,
+            paused,
+            default_price,
+            fee_bps,
+            fee_recipient,
+            max_quantity,
+            quote_mint_count,
+            quote_mints_flat,
+            store_count,
+        ) = mut_array_refs![dst, 1, 32, 1, 8, 2, 32, 8, 1, 32 * MAX_QUOTE_MINTS, 8];
+        *is_initialized = [self.is_initialized as u8];
+        admin.copy_from_slice(self.admin.as_ref());
+        *paused = [self.paused as u8];
+        *default_price = self.default_price.to_le_bytes();
+        *fee_bps = self.fee_bps.to_le_bytes();
+        fee_recipient.copy_from_slice(self.fee_recipient.as_ref());
+        *max_quantity = self.max_quantity.to_le_bytes();
+        *quote_mint_count = [self.quote_mint_count];
+        for (i, src) in self.quote_mints.iter().enumerate() {
+            let dst_array = array_mut_ref![quote_mints_flat, 32 * i, 32];
+            dst_array.copy_from_slice(src.as_ref());
+        }
+        *store_count = self.store_count.to_le_bytes();
+    }
+}
+
+/// Maximum number of keys in a store multisig.
+pub const MAX_SIGNERS: usize = 11;
+
+/// M-of-N signer set that can be used as a store owner.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Multisig {
+    /// Number of signers required.
+    pub m: u8,
+    /// Number of valid signers.
+    pub n: u8,
+    pub is_initialized: bool,
+    pub signers: [Pubkey; MAX_SIGNERS],
+}
+
+impl Sealed for Multisig {}
+
+impl IsInitialized for Multisig {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Pack for Multisig {
+    const LEN: usize = 355;
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src = array_ref![src, 0, 355];
+        let (m, n, is_initialized, signers_flat) = array_refs![src, 1, 1, 1, 32 * MAX_SIGNERS];
+        let mut result = Multisig {
+            m: m[0],
+            n: n[0],
+            is_initialized: match is_initialized {
+                [0] => false,
+                [1] => true,
+                _ => return Err(ProgramError::InvalidAccountData),
+            },
+            signers: [Pubkey::default(); MAX_SIGNERS],
+        };
+        for (src, dst) in signers_flat.chunks(32).zip(result.signers.iter_mut()) {
+            *dst = Pubkey::try_from(src).map_err(|_| ProgramError::InvalidAccountData)?;
+        }
+        Ok(result)
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, 355];
+        let (m, n, is_initialized, signers_flat) = mut_array_refs![dst, 1, 1, 1, 32 * MAX_SIGNERS];
+        *m = [self.m];
+        *n = [self.n];
+        *is_initialized = [self.is_initialized as u8];
+        for (i, src) in self.signers.iter().enumerate() {
+            let dst_array = array_mut_ref![signers_flat, 32 * i, 32];
+            dst_array.copy_from_slice(src.as_ref());
+        }
+    }
+}
+
+pub const ROLE_ADMIN: u8 = 1 << 0;
+pub const ROLE_PRICER: u8 = 1 << 1;
+pub const ROLE_INVENTORY_MANAGER: u8 = 1 << 2;
+pub const ROLE_WITHDRAWER: u8 = 1 << 3;
+pub const ALL_ROLES: u8 = ROLE_ADMIN | ROLE_PRICER | ROLE_INVENTORY_MANAGER | ROLE_WITHDRAWER;
+
+/// Maximum number of keys holding roles on a single store.
+pub const MAX_ROLE_HOLDERS: usize = 16;
+
+/// Role assignments of a store, kept in a PDA derived from the store address.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StoreRoles {
+    pub is_initialized: bool,
+    pub store: Pubkey,
+    /// Number of used entries in `holders`.
+    pub count: u8,
+    /// Role holders with their role bit sets.
+    pub holders: [(Pubkey, u8); MAX_ROLE_HOLDERS],
+}
+
+impl StoreRoles {
+    pub fn roles_of(&self, key: &Pubkey) -> u8 {
+        self.holders[..self.count as usize]
+            .iter()
+            .find(|(holder, _)| holder == key)
+            .map_or(0, |(_, roles)| *roles)
+    }
+
+    pub fn grant(&mut self, key: &Pubkey, roles: u8) -> Result<(), ProgramError> {
+        let count = self.count as usize;
+        if let Some(entry) = self.holders[..count].iter_mut().find(|(holder, _)| holder == key) {
+            entry.1 |= roles;
+            return Ok(());
+        }
+        if count == MAX_ROLE_HOLDERS {
+            return Err(ProgramError::Custom(7)); // Too many role holders
+        }
+        self.holders[count] = (*key, roles);
+        self.count += 1;
+        Ok(())
+    }
+
+    pub fn revoke(&mut self, key: &Pubkey, roles: u8) {
+        let count = self.count as usize;
+        if let Some(position) = self.holders[..count].iter().position(|(holder, _)| holder == key) {
+            self.holders[position].1 &= !roles;
+            if self.holders[position].1 == 0 {
+                self.holders[position] = self.holders[count - 1];
+                self.holders[count - 1] = (Pubkey::default(), 0);
+                self.count -= 1;
+            }
+        }
+    }
+
+    /// Revokes every role of every holder.
+    pub fn clear(&mut self) {
+        self.holders[..self.count as usize].fill((Pubkey::default(), 0));
+        self.count = 0;
+    }
+}
+
+impl Sealed for StoreRoles {}
+
+impl IsInitialized for StoreRoles {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Pack for StoreRoles {
+    const LEN: usize = 562;
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let src = array_ref![src, 0, 562];
+        let (is_initialized, store, count, holders_flat) = array_refs![src, 1, 32, 1, 33 * MAX_ROLE_HOLDERS];
+        let mut result = StoreRoles {
+            is_initialized: match is_initialized {
+                [0] => false,
+                [1] => true,
+                _ => return Err(ProgramError::InvalidAccountData),
+            },
+            store: Pubkey::new_from_array(*store),
+            count: count[0],
+            holders: [(Pubkey::default(), 0); MAX_ROLE_HOLDERS],
+        };
+        if result.count as usize > MAX_ROLE_HOLDERS {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        for (src, dst) in holders_flat.chunks(33).zip(result.holders.iter_mut()) {
+            let (holder, roles) = array_refs![array_ref![src, 0, 33], 32, 1];
+            *dst = (Pubkey::new_from_array(*holder), roles[0]);
+        }
+        Ok(result)
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, 562];
+        let (is_initialized, store, count, holders_flat) = mut_array_refs![dst, 1, 32, 1, 33 * MAX_ROLE_HOLDERS];
+        *is_initialized = [self.is_initialized as u8];
+        store.copy_from_slice(self.store.as_ref());
+        *count = [self.count];
+        for (i, (key, roles)) in self.holders.iter().enumerate() {
+            let (holder, role_bits) = mut_array_refs![array_mut_ref![holders_flat, 33 * i, 33], 32, 1];
+            holder.copy_from_slice(key.as_ref());
+            *role_bits = [*roles];
+        }
+    }
+}
+
+/// PDA that owns the store vault and signs transfers out of it.
+pub fn find_vault_authority(program_id: &Pubkey, store: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[VAULT_SEED, store.as_ref()], program_id)
+}
+
+pub fn find_roles_address(program_id: &Pubkey, store: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[ROLES_SEED, store.as_ref()], program_id)
+}
+
+pub fn find_config_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[CONFIG_SEED], program_id)
+}
+
+/// PDA collecting the store proceeds, either as lamports or as the owner of
+/// a quote token account.
+pub fn find_proceeds_address(program_id: &Pubkey, store: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[PROCEEDS_SEED, store.as_ref()], program_id)
+}
+
+pub fn find_history_address(program_id: &Pubkey, store: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[HISTORY_SEED, store.as_ref()], program_id)
+}
+
+pub fn find_registry_page_address(program_id: &Pubkey, page: u32) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[REGISTRY_SEED, &page.to_le_bytes()], program_id)
+}
+
+pub fn find_metadata_address(program_id: &Pubkey, store: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[METADATA_SEED, store.as_ref()], program_id)
+}
+
+/// Marker account of a used voucher nonce.
+pub fn find_voucher_nonce_address(program_id: &Pubkey, store: &Pubkey, nonce: u64) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[VOUCHER_SEED, store.as_ref(), &nonce.to_le_bytes()], program_id)
+}
+
+/// Marker account of a used quote nonce.
+pub fn find_quote_nonce_address(program_id: &Pubkey, store: &Pubkey, nonce: u64) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[QUOTE_SEED, store.as_ref(), &nonce.to_le_bytes()], program_id)
+}
+
+pub fn find_receipt_address(program_id: &Pubkey, store: &Pubkey, number: u64) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[RECEIPT_SEED, store.as_ref(), &number.to_le_bytes()], program_id)
+}
+
+pub fn find_escrow_address(program_id: &Pubkey, store: &Pubkey, receipt: u64) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[ESCROW_SEED, store.as_ref(), &receipt.to_le_bytes()], program_id)
+}
+
+pub fn find_listing_book_address(program_id: &Pubkey, store: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[LISTINGS_SEED, store.as_ref()], program_id)
+}
+
+pub fn find_seller_ledger_address(program_id: &Pubkey, store: &Pubkey, seller: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[LEDGER_SEED, store.as_ref(), seller.as_ref()], program_id)
+}
+
+/// Addresses of all registry pages listing `store_count` stores, in order.
+pub fn registry_page_addresses(program_id: &Pubkey, store_count: u64) -> Vec<Pubkey> {
+    (0..RegistryPage::page_count(store_count))
+        .map(|page| find_registry_page_address(program_id, page).0)
+        .collect()
+}
+
+/// Temporary wSOL account used to wrap or unwrap lamports for `payer`, it only
+/// exists for the duration of a single instruction.
+pub fn find_wrap_address(program_id: &Pubkey, store: &Pubkey, payer: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[WRAP_SEED, store.as_ref(), payer.as_ref()], program_id)
+}
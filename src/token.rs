@@ -0,0 +1,92 @@
+//! Helpers for the store mint, which may belong to either the Token or the
+//! Token-2022 program.
+
+use solana_program::{
+    account_info::AccountInfo, clock::Clock, entrypoint::ProgramResult, program::invoke_signed,
+    program_error::ProgramError, pubkey::Pubkey, sysvar::Sysvar,
+};
+use spl_token_2022::extension::{
+    transfer_fee::{TransferFeeAmount, TransferFeeConfig},
+    BaseStateWithExtensions, StateWithExtensions,
+};
+use spl_token_2022::state::{Account, Mint};
+
+pub fn is_token_program(key: &Pubkey) -> bool {
+    *key == spl_token::id() || *key == spl_token_2022::id()
+}
+
+pub fn unpack_mint(mint_account: &AccountInfo) -> Result<Mint, ProgramError> {
+    if !is_token_program(mint_account.owner) {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    StateWithExtensions::<Mint>::unpack(&mint_account.try_borrow_data()?).map(|mint| mint.base)
+}
+
+pub fn unpack_token_account(token_account: &AccountInfo) -> Result<Account, ProgramError> {
+    if !is_token_program(token_account.owner) {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    StateWithExtensions::<Account>::unpack(&token_account.try_borrow_data()?).map(|account| account.base)
+}
+
+/// Fee withheld by the mint's transfer fee extension when sending `amount`
+/// in the current epoch, 0 for mints without the extension.
+pub fn transfer_fee(mint_account: &AccountInfo, amount: u64) -> Result<u64, ProgramError> {
+    epoch_fee(mint_account, |fee_config, epoch| fee_config.calculate_epoch_fee(epoch, amount))
+}
+
+/// Fee to send on top of `amount` so that `amount` arrives in full.
+pub fn inverse_transfer_fee(mint_account: &AccountInfo, amount: u64) -> Result<u64, ProgramError> {
+    epoch_fee(mint_account, |fee_config, epoch| fee_config.calculate_inverse_epoch_fee(epoch, amount))
+}
+
+fn epoch_fee<F>(mint_account: &AccountInfo, calculate: F) -> Result<u64, ProgramError>
+where
+    F: FnOnce(&TransferFeeConfig, u64) -> Option<u64>,
+{
+    let mint_data = mint_account.try_borrow_data()?;
+    let mint = StateWithExtensions::<Mint>::unpack(&mint_data)?;
+    match mint.get_extension::<TransferFeeConfig>() {
+        Ok(fee_config) => calculate(fee_config, Clock::get()?.epoch).ok_or(ProgramError::ArithmeticOverflow),
+        Err(_) => Ok(0),
+    }
+}
+
+/// Transfer fees withheld in a token account, they have to be harvested
+/// before the account can be closed.
+pub fn withheld_fees(token_account: &AccountInfo) -> Result<u64, ProgramError> {
+    let account_data = token_account.try_borrow_data()?;
+    let account = StateWithExtensions::<Account>::unpack(&account_data)?;
+    Ok(account
+        .get_extension::<TransferFeeAmount>()
+        .map(|fee_amount| u64::from(fee_amount.withheld_amount))
+        .unwrap_or(0))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn transfer_checked<'a>(
+    token_program: &AccountInfo<'a>,
+    source: &AccountInfo<'a>,
+    mint: &AccountInfo<'a>,
+    destination: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    amount: u64,
+    decimals: u8,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let transfer_ix = spl_token_2022::instruction::transfer_checked(
+        token_program.key,
+        source.key,
+        mint.key,
+        destination.key,
+        authority.key,
+        &[],
+        amount,
+        decimals,
+    )?;
+    invoke_signed(
+        &transfer_ix,
+        &[source.clone(), mint.clone(), destination.clone(), authority.clone(), token_program.clone()],
+        signer_seeds,
+    )
+}
@@ -1,490 +1,3022 @@
-use solana_program::account_info::{AccountInfo, next_account_info};
-use solana_program::instruction::Instruction;
+use solana_program::instruction::{AccountMeta, Instruction};
 use solana_program::program_pack::{IsInitialized, Pack};
-use solana_program::system_instruction::create_account;
+use solana_program::pubkey::Pubkey;
+use solana_program::system_instruction::{create_account, transfer};
+use solana_program::bpf_loader_upgradeable::{self, UpgradeableLoaderState};
+use solana_program::{system_program, rent::Rent};
 use solana_program_test::*;
-use solana_sdk::account_info::IntoAccountInfo;
+use solana_sdk::hash::Hash;
 use solana_sdk::signature::{Keypair, Signer};
-use solana_sdk::transaction::Transaction;
+use solana_sdk::transaction::{Transaction, TransactionError};
+use solana_sdk::instruction::InstructionError;
 use spl_token::state::Account;
-use sfxdx::{process_instruction};
+use spl_token_2022::extension::{BaseStateWithExtensions, ExtensionType, StateWithExtensions};
+use sfxdx::events::Event;
+use sfxdx::signature::{Quote, Voucher};
+use sfxdx::{process_instruction, BUY_CREATE_ATA, BUY_QUOTE, BUY_VOUCHER, BUY_WRAP_SOL};
+use spl_associated_token_account::get_associated_token_address_with_program_id;
+use sfxdx::state::{twap, find_config_address, find_escrow_address, find_history_address, find_listing_book_address, find_metadata_address, find_proceeds_address, find_quote_nonce_address, find_receipt_address, find_registry_page_address, find_roles_address, find_seller_ledger_address, find_vault_authority, find_voucher_nonce_address, find_wrap_address, registry_page_addresses, Config, Escrow, Listing, ListingBook, Multisig, PriceHistory, Receipt, RegistryPage, SellerLedger, Store, StoreMetadata, StoreRoles, ESCROW_PENDING, ESCROW_REFUNDED, ESCROW_RELEASED, ROLE_PRICER, STORE_STATUS_ACTIVE, STORE_STATUS_CLOSED, STORE_STATUS_PAUSED, TRANSFER_FEE_GROSS_UP};
+
+// Все аккаунты, необходимые для работы с магазином
+struct StoreFixture {
+    program_id: Pubkey,
+    store_account: Keypair,
+    mint: Keypair,
+    vault: Keypair,
+    owner_account: Keypair,
+    owner_token_account: Keypair,
+    fee_recipient: Pubkey,
+    token_program: Pubkey,
+}
 
-#[tokio::test]
-async fn test_initialize_store() {
-    // Инициализация тестового окружения
-    let (mut banks_client, payer, recent_blockhash) = ProgramTest::new(
-        "your_program_name",
-        id,
-        processor!(process_instruction),
+async fn process(
+    banks_client: &mut BanksClient,
+    payer: &Keypair,
+    recent_blockhash: Hash,
+    instructions: &[Instruction],
+    signers: &[&Keypair],
+) -> Result<(), BanksClientError> {
+    let mut transaction = Transaction::new_with_payer(instructions, Some(&payer.pubkey()));
+    let mut all_signers = vec![payer];
+    all_signers.extend_from_slice(signers);
+    transaction.sign(&all_signers, recent_blockhash);
+    banks_client.process_transaction(transaction).await
+}
+
+async fn create_token_account(
+    banks_client: &mut BanksClient,
+    payer: &Keypair,
+    recent_blockhash: Hash,
+    account: &Keypair,
+    mint: &Pubkey,
+    owner: &Pubkey,
+) {
+    // Программа и размер аккаунта зависят от минта и его расширений
+    let mint_account = banks_client.get_account(*mint).await.unwrap().unwrap();
+    let mint_extensions = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&mint_account.data)
+        .unwrap()
+        .get_extension_types()
+        .unwrap();
+    let account_len = ExtensionType::try_calculate_account_len::<spl_token_2022::state::Account>(
+        &ExtensionType::get_required_init_account_extensions(&mint_extensions),
+    )
+    .unwrap();
+    let token_program = mint_account.owner;
+
+    let rent = Rent::default();
+    let instructions = [
+        create_account(
+            &payer.pubkey(),
+            &account.pubkey(),
+            rent.minimum_balance(account_len),
+            account_len as u64,
+            &token_program,
+        ),
+        spl_token_2022::instruction::initialize_account(&token_program, &account.pubkey(), mint, owner).unwrap(),
+    ];
+    process(banks_client, payer, recent_blockhash, &instructions, &[account]).await.unwrap();
+}
+
+async fn get_store(banks_client: &mut BanksClient, store: &Pubkey) -> Store {
+    let account = banks_client.get_account(*store).await.unwrap().unwrap();
+    Store::unpack(&account.data).unwrap()
+}
+
+async fn get_token_balance(banks_client: &mut BanksClient, token_account: &Pubkey) -> u64 {
+    let account = banks_client.get_account(*token_account).await.unwrap().unwrap();
+    StateWithExtensions::<spl_token_2022::state::Account>::unpack(&account.data).unwrap().base.amount
+}
+
+fn assert_custom_error(result: Result<(), BanksClientError>, code: u32) {
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(code))
+    );
+}
+
+// Создание минта, хранилища, аккаунта магазина и его инициализация
+async fn setup_store() -> (BanksClient, Keypair, Hash, StoreFixture) {
+    setup_store_with_mint(spl_token::id(), None).await
+}
+
+// То же самое для минта выбранной токен-программы, с комиссией за перевод
+// в базисных пунктах, если она задана (только Token-2022)
+async fn setup_store_with_mint(
+    token_program: Pubkey,
+    transfer_fee_bps: Option<u16>,
+) -> (BanksClient, Keypair, Hash, StoreFixture) {
+    let program_id = Pubkey::new_unique();
+    let mut program_test = ProgramTest::new("sfxdx", program_id, processor!(process_instruction));
+
+    // Данные программы с ключом, которому разрешено обновление, как после
+    // развертывания через загрузчик
+    let upgrade_authority = Keypair::new();
+    let (program_data, _) = Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    let program_data_state = UpgradeableLoaderState::ProgramData {
+        slot: 0,
+        upgrade_authority_address: Some(upgrade_authority.pubkey()),
+    };
+    program_test.add_account(
+        program_data,
+        solana_sdk::account::Account::new_data(1_000_000_000, &program_data_state, &bpf_loader_upgradeable::id()).unwrap(),
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let fixture = StoreFixture {
+        program_id,
+        store_account: Keypair::new(),
+        mint: Keypair::new(),
+        vault: Keypair::new(),
+        owner_account: Keypair::new(),
+        owner_token_account: Keypair::new(),
+        fee_recipient: payer.pubkey(),
+        token_program,
+    };
+    let rent = Rent::default();
+
+    // Создание минта и выпуск токенов владельцу магазина
+    let mint_extensions: &[ExtensionType] = if transfer_fee_bps.is_some() {
+        &[ExtensionType::TransferFeeConfig]
+    } else {
+        &[]
+    };
+    let mint_len = ExtensionType::try_calculate_account_len::<spl_token_2022::state::Mint>(mint_extensions).unwrap();
+    let mut create_mint_ixs = vec![create_account(
+        &payer.pubkey(),
+        &fixture.mint.pubkey(),
+        rent.minimum_balance(mint_len),
+        mint_len as u64,
+        &token_program,
+    )];
+    if let Some(fee_bps) = transfer_fee_bps {
+        create_mint_ixs.push(
+            spl_token_2022::extension::transfer_fee::instruction::initialize_transfer_fee_config(
+                &token_program,
+                &fixture.mint.pubkey(),
+                None,
+                None,
+                fee_bps,
+                u64::MAX,
+            )
+            .unwrap(),
+        );
+    }
+    create_mint_ixs.push(
+        spl_token_2022::instruction::initialize_mint(&token_program, &fixture.mint.pubkey(), &payer.pubkey(), None, 0)
+            .unwrap(),
+    );
+    create_mint_ixs.push(transfer(&payer.pubkey(), &fixture.owner_account.pubkey(), 1_000_000_000));
+    process(&mut banks_client, &payer, recent_blockhash, &create_mint_ixs, &[&fixture.mint]).await.unwrap();
+
+    let (vault_authority, _) = find_vault_authority(&program_id, &fixture.store_account.pubkey());
+    create_token_account(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &fixture.vault,
+        &fixture.mint.pubkey(),
+        &vault_authority,
+    )
+    .await;
+    create_token_account(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &fixture.owner_token_account,
+        &fixture.mint.pubkey(),
+        &fixture.owner_account.pubkey(),
     )
-    .start()
     .await;
 
-    // Создание аккаунтов и ключей для магазина и токенов
-    let store_account = Keypair::new();
-    let token_account = Keypair::new();
-    let owner_account = Keypair::new();
-
-    // Инициализация магазина
-    let store_data = Account {
-        mint: token_account.pubkey(),
-        owner: owner_account.pubkey(),
-        amount: 1000,
-        delegate: Default::default(),
-        state: Default::default(),
-        is_native: Default::default(),
-        delegated_amount: 0,
-        close_authority: Default::default(),
-    };
-
-    // Определение данных для инициализации аккаунта магазина
-    let mut data = vec![];
-    data.extend_from_slice(&store_data.is_initialized().to_le_bytes());
-    data.extend_from_slice(&store_data.mint.to_bytes());
-    data.extend_from_slice(&store_data.owner.to_bytes());
-    data.extend_from_slice(&store_data.amount.to_le_bytes());
-
-    // Создание транзакции для инициализации магазина
+    let mint_to_ix = spl_token_2022::instruction::mint_to(
+        &token_program,
+        &fixture.mint.pubkey(),
+        &fixture.owner_token_account.pubkey(),
+        &payer.pubkey(),
+        &[],
+        1_000,
+    )
+    .unwrap();
+
+    // Создание аккаунта магазина, принадлежащего программе
+    let create_store_ix = create_account(
+        &payer.pubkey(),
+        &fixture.store_account.pubkey(),
+        rent.minimum_balance(Store::LEN),
+        Store::LEN as u64,
+        &program_id,
+    );
+
     let init_store_ix = Instruction {
-        program_id: id,
-        accounts: [store_account.pubkey(), token_account.pubkey(), owner_account.pubkey()].iter().map(|ac| ac.as_ref().unwrap()).collect(),
-        data,
+        program_id,
+        accounts: vec![
+            AccountMeta::new(fixture.store_account.pubkey(), false),
+            AccountMeta::new(find_config_address(&program_id).0, false),
+            AccountMeta::new_readonly(fixture.mint.pubkey(), false),
+            AccountMeta::new_readonly(fixture.owner_account.pubkey(), true),
+            AccountMeta::new_readonly(fixture.vault.pubkey(), false),
+            AccountMeta::new(find_proceeds_address(&program_id, &fixture.store_account.pubkey()).0, false),
+            AccountMeta::new(payer.pubkey(), true),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new(find_registry_page_address(&program_id, 0).0, false),
+        ],
+        data: vec![0],
     };
-    // Выполнение и ожидание транзакции
-    let mut transaction = Transaction::new_with_payer(
-        &[init_store_ix],
-        Some(&payer.pubkey()),
-    );
-    transaction.sign(&[&payer, &store_account, &token_account, &owner_account], recent_blockhash);
 
-    let result = banks_client.process_transaction(transaction).await;
-    assert!(result.is_ok(), "Transaction failed: {:?}", result);
+    // Глобальная конфигурация программы создается владельцем права на
+    // обновление, администратор - плательщик
+    let mut data = vec![13];
+    data.extend_from_slice(payer.pubkey().as_ref());
+    let init_config_ix = |authority: &Pubkey| Instruction {
+        program_id,
+        accounts: vec![
+            AccountMeta::new(find_config_address(&program_id).0, false),
+            AccountMeta::new_readonly(*authority, true),
+            AccountMeta::new(payer.pubkey(), true),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(program_data, false),
+        ],
+        data: data.clone(),
+    };
+
+    // Кто-то другой создать конфигурацию не может
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[init_config_ix(&payer.pubkey())], &[]).await;
+    assert_custom_error(result, 1);
+
+    // Адрес страницы реестра пополнен заранее, создание страницы должно
+    // это выдерживать
+    let prefund_page_ix = transfer(&payer.pubkey(), &find_registry_page_address(&program_id, 0).0, 1_000_000);
+
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[mint_to_ix, init_config_ix(&upgrade_authority.pubkey()), prefund_page_ix, create_store_ix, init_store_ix],
+        &[&fixture.store_account, &fixture.owner_account, &upgrade_authority],
+    )
+    .await;
+    assert!(result.is_ok(), "Initialize Store transaction failed: {:?}", result);
+
+    (banks_client, payer, recent_blockhash, fixture)
+}
+
+fn deposit_ix(fixture: &StoreFixture, amount: u64) -> Instruction {
+    let mut data = vec![4];
+    data.extend_from_slice(&amount.to_le_bytes());
+
+    Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new(fixture.store_account.pubkey(), false),
+            AccountMeta::new_readonly(fixture.owner_account.pubkey(), true),
+            AccountMeta::new(fixture.owner_token_account.pubkey(), false),
+            AccountMeta::new(fixture.vault.pubkey(), false),
+            AccountMeta::new_readonly(fixture.mint.pubkey(), false),
+            AccountMeta::new_readonly(fixture.token_program, false),
+        ],
+        data,
+    }
+}
+
+fn buy_ix(
+    fixture: &StoreFixture,
+    buyer: &Pubkey,
+    buyer_token_account: &Pubkey,
+    quantity: u64,
+    max_cost: u64,
+    receipt_number: u64,
+) -> Instruction {
+    let (vault_authority, _) = find_vault_authority(&fixture.program_id, &fixture.store_account.pubkey());
+    let mut data = vec![3];
+    data.extend_from_slice(&quantity.to_le_bytes());
+    data.extend_from_slice(&max_cost.to_le_bytes());
+
+    Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new(fixture.store_account.pubkey(), false),
+            AccountMeta::new_readonly(find_config_address(&fixture.program_id).0, false),
+            AccountMeta::new(fixture.vault.pubkey(), false),
+            AccountMeta::new_readonly(fixture.mint.pubkey(), false),
+            AccountMeta::new_readonly(vault_authority, false),
+            AccountMeta::new(*buyer_token_account, false),
+            AccountMeta::new(*buyer, true),
+            AccountMeta::new(find_proceeds_address(&fixture.program_id, &fixture.store_account.pubkey()).0, false),
+            AccountMeta::new(fixture.fee_recipient, false),
+            AccountMeta::new_readonly(fixture.token_program, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new(find_receipt_address(&fixture.program_id, &fixture.store_account.pubkey(), receipt_number).0, false),
+        ],
+        data,
+    }
+}
+
+fn sell_ix(fixture: &StoreFixture, seller: &Pubkey, seller_token_account: &Pubkey, quantity: u64) -> Instruction {
+    let mut data = vec![2];
+    data.extend_from_slice(&quantity.to_le_bytes());
+
+    Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new(fixture.store_account.pubkey(), false),
+            AccountMeta::new_readonly(find_config_address(&fixture.program_id).0, false),
+            AccountMeta::new(*seller_token_account, false),
+            AccountMeta::new(fixture.vault.pubkey(), false),
+            AccountMeta::new_readonly(fixture.mint.pubkey(), false),
+            AccountMeta::new(*seller, true),
+            AccountMeta::new_readonly(fixture.owner_account.pubkey(), true),
+            AccountMeta::new_readonly(fixture.token_program, false),
+            AccountMeta::new(find_proceeds_address(&fixture.program_id, &fixture.store_account.pubkey()).0, false),
+        ],
+        data,
+    }
+}
+
+// Создание покупателя с SOL и пустым токен-аккаунтом
+async fn setup_buyer(
+    banks_client: &mut BanksClient,
+    payer: &Keypair,
+    recent_blockhash: Hash,
+    fixture: &StoreFixture,
+) -> (Keypair, Keypair) {
+    let buyer = Keypair::new();
+    let buyer_token_account = Keypair::new();
+
+    let fund_ix = transfer(&payer.pubkey(), &buyer.pubkey(), 1_000_000_000);
+    process(banks_client, payer, recent_blockhash, &[fund_ix], &[]).await.unwrap();
+    create_token_account(
+        banks_client,
+        payer,
+        recent_blockhash,
+        &buyer_token_account,
+        &fixture.mint.pubkey(),
+        &buyer.pubkey(),
+    )
+    .await;
+
+    (buyer, buyer_token_account)
+}
+
+#[tokio::test]
+async fn test_initialize_store() {
+    // Инициализация тестового окружения и магазина
+    let (mut banks_client, payer, recent_blockhash, fixture) = setup_store().await;
 
     // Получение аккаунта магазина и проверка его данных
-    let store_account_info = next_account_info(&mut banks_client);
-    let store_data = Account::unpack(&store_account_info.borrow()).unwrap();
+    let store_data = get_store(&mut banks_client, &fixture.store_account.pubkey()).await;
 
     // Проверка, что магазин был успешно инициализирован
-    assert_eq!(store_data.is_initialized(), true);
-    assert_eq!(store_data.mint, token_account.pubkey());
-    assert_eq!(store_data.owner, owner_account.pubkey());
-    assert_eq!(store_data.amount, 1000);
+    assert!(store_data.is_initialized());
+    assert_eq!(store_data.mint, fixture.mint.pubkey());
+    assert_eq!(store_data.owner, fixture.owner_account.pubkey());
+    assert_eq!(store_data.vault, fixture.vault.pubkey());
+    assert_eq!(store_data.token_program, spl_token::id());
+    assert_eq!(store_data.price, 1000);
+    assert_eq!(store_data.supply, 0);
+
+    // Магазин нельзя зарегистрировать на чужой ключ без его подписи
+    let store_account = Keypair::new();
+    let (vault_authority, _) = find_vault_authority(&fixture.program_id, &store_account.pubkey());
+    let vault = Keypair::new();
+    create_token_account(&mut banks_client, &payer, recent_blockhash, &vault, &fixture.mint.pubkey(), &vault_authority).await;
+    let create_store_ix = create_account(
+        &payer.pubkey(),
+        &store_account.pubkey(),
+        Rent::default().minimum_balance(Store::LEN),
+        Store::LEN as u64,
+        &fixture.program_id,
+    );
+    let init_store_ix = Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new(store_account.pubkey(), false),
+            AccountMeta::new(find_config_address(&fixture.program_id).0, false),
+            AccountMeta::new_readonly(fixture.mint.pubkey(), false),
+            AccountMeta::new_readonly(fixture.owner_account.pubkey(), false),
+            AccountMeta::new_readonly(vault.pubkey(), false),
+            AccountMeta::new(find_proceeds_address(&fixture.program_id, &store_account.pubkey()).0, false),
+            AccountMeta::new(payer.pubkey(), true),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new(find_registry_page_address(&fixture.program_id, 0).0, false),
+        ],
+        data: vec![0],
+    };
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[create_store_ix, init_store_ix],
+        &[&store_account],
+    )
+    .await;
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(1, InstructionError::MissingRequiredSignature)
+    );
 }
 
 #[tokio::test]
-async fn test_update_price() {
-    // Инициализация тестового окружения
-    let (mut banks_client, payer, recent_blockhash) = ProgramTest::new(
-        "your_program_name",
-        id,
-        processor!(process_instruction),
+async fn test_store_registry() {
+    // Инициализация тестового окружения и магазина
+    let (mut banks_client, payer, recent_blockhash, fixture) = setup_store().await;
+    let config_account = banks_client.get_account(find_config_address(&fixture.program_id).0).await.unwrap().unwrap();
+    let store_count = Config::unpack(&config_account.data).unwrap().store_count;
+    assert_eq!(store_count, 1);
+
+    // Магазин попадает в первую страницу реестра
+    let pages = registry_page_addresses(&fixture.program_id, store_count);
+    assert_eq!(pages, vec![find_registry_page_address(&fixture.program_id, 0).0]);
+    let page_account = banks_client.get_account(pages[0]).await.unwrap().unwrap();
+    let entries = RegistryPage::entries(&page_account.data).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].store, fixture.store_account.pubkey());
+    assert_eq!(entries[0].mint, fixture.mint.pubkey());
+    assert_eq!(entries[0].owner, fixture.owner_account.pubkey());
+    assert_eq!(entries[0].status, STORE_STATUS_ACTIVE);
+
+    // Пауза сразу обновляет статус записи в реестре
+    let pause_ix = Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new(fixture.store_account.pubkey(), false),
+            AccountMeta::new_readonly(fixture.owner_account.pubkey(), true),
+            AccountMeta::new(pages[0], false),
+        ],
+        data: vec![11],
+    };
+    process(&mut banks_client, &payer, recent_blockhash, &[pause_ix], &[&fixture.owner_account]).await.unwrap();
+    let page_account = banks_client.get_account(pages[0]).await.unwrap().unwrap();
+    assert_eq!(RegistryPage::entries(&page_account.data).unwrap()[0].status, STORE_STATUS_PAUSED);
+
+    // Синхронизация записи сохраняет актуальный статус
+    let mut data = vec![22];
+    data.extend_from_slice(&0u64.to_le_bytes());
+    let sync_ix = Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(fixture.store_account.pubkey(), false),
+            AccountMeta::new(pages[0], false),
+        ],
+        data,
+    };
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[sync_ix],
+        &[],
     )
-    .start()
     .await;
+    assert!(result.is_ok(), "Sync Registry transaction failed: {:?}", result);
 
-    // Создание аккаунтов и ключей для магазина и токенов
-    let store_account = Keypair::new();
-    let token_account = Keypair::new();
-    let owner_account = Keypair::new();
-
-    // Инициализация магазина
-    let store_data = Account {
-        mint: token_account.pubkey(),
-        owner: owner_account.pubkey(),
-        amount: 1000,
-        delegate: Default::default(),
-        state: Default::default(),
-        is_native: Default::default(),
-        delegated_amount: 0,
-        close_authority: Default::default(),
-    };
-
-    // Определение данных для инициализации аккаунта магазина
-    let mut data = vec![];
-    data.extend_from_slice(&store_data.is_initialized().to_le_bytes());
-    data.extend_from_slice(&store_data.mint.to_bytes());
-    data.extend_from_slice(&store_data.owner.to_bytes());
-    data.extend_from_slice(&store_data.amount.to_le_bytes());
-
-    // Создание транзакции для инициализации магазина
-     let init_store_ix = Instruction {
-        program_id: id,
-        accounts: [store_account.pubkey(), token_account.pubkey(), owner_account.pubkey()].iter().map(|ac| ac.as_ref().unwrap()).collect(),
-        data,
+    let page_account = banks_client.get_account(pages[0]).await.unwrap().unwrap();
+    assert_eq!(RegistryPage::entries(&page_account.data).unwrap()[0].status, STORE_STATUS_PAUSED);
+
+    // Позиции в реестре по индексу магазина
+    assert_eq!(RegistryPage::position(33), (1, 1));
+    assert_eq!(RegistryPage::page_count(33), 2);
+}
+
+#[tokio::test]
+async fn test_store_metadata() {
+    // Инициализация тестового окружения и магазина
+    let (mut banks_client, payer, recent_blockhash, fixture) = setup_store().await;
+    let (metadata_account, _) = find_metadata_address(&fixture.program_id, &fixture.store_account.pubkey());
+
+    let set_metadata_ix = |name: &str, description: &str| {
+        let metadata = StoreMetadata {
+            is_initialized: true,
+            store: fixture.store_account.pubkey(),
+            name: name.to_string(),
+            description: description.to_string(),
+            uri: "https://example.com/store.png".to_string(),
+            category: "books".to_string(),
+        };
+        let mut data = vec![23];
+        data.extend_from_slice(&metadata.pack_fields());
+        let instruction = Instruction {
+            program_id: fixture.program_id,
+            accounts: vec![
+                AccountMeta::new_readonly(fixture.store_account.pubkey(), false),
+                AccountMeta::new_readonly(fixture.owner_account.pubkey(), true),
+                AccountMeta::new(metadata_account, false),
+                AccountMeta::new(fixture.owner_account.pubkey(), true),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+            data,
+        };
+        (instruction, metadata)
     };
 
-    // Выполнение и ожидание транзакции и проверка успешной инициализации магазина
-    let mut transaction = Transaction::new_with_payer(
-        &[init_store_ix],
-        Some(&payer.pubkey()),
-    );
-    transaction.sign(&[&payer, &store_account, &token_account, &owner_account], recent_blockhash);
+    // Адрес метаданных пополнен заранее, создание всё равно проходит
+    process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[transfer(&payer.pubkey(), &metadata_account, 1_000_000)],
+        &[],
+    )
+    .await
+    .unwrap();
 
-    let result = banks_client.process_transaction(transaction).await;
-    assert!(result.is_ok(), "Initialize Store transaction failed: {:?}", result);
+    // Создание метаданных
+    let (instruction, metadata) = set_metadata_ix("Book store", "Short");
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[instruction], &[&fixture.owner_account]).await;
+    assert!(result.is_ok(), "Set Metadata transaction failed: {:?}", result);
+    let account = banks_client.get_account(metadata_account).await.unwrap().unwrap();
+    assert_eq!(StoreMetadata::unpack(&account.data).unwrap(), metadata);
+
+    // Более длинное описание увеличивает аккаунт
+    let (instruction, metadata) = set_metadata_ix("Book store", &"Long description ".repeat(20));
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[instruction], &[&fixture.owner_account]).await;
+    assert!(result.is_ok(), "Set Metadata transaction failed: {:?}", result);
+    let account = banks_client.get_account(metadata_account).await.unwrap().unwrap();
+    assert_eq!(account.data.len(), metadata.account_len());
+    assert_eq!(StoreMetadata::unpack(&account.data).unwrap(), metadata);
+    let grown_len = metadata.account_len();
+
+    // При уменьшении излишек ренты возвращается плательщику
+    let owner_balance = banks_client.get_balance(fixture.owner_account.pubkey()).await.unwrap();
+    let (instruction, metadata) = set_metadata_ix("Books", "Short");
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[instruction], &[&fixture.owner_account]).await;
+    assert!(result.is_ok(), "Set Metadata transaction failed: {:?}", result);
+    let account = banks_client.get_account(metadata_account).await.unwrap().unwrap();
+    assert_eq!(StoreMetadata::unpack(&account.data).unwrap(), metadata);
+    let rent = Rent::default();
+    assert_eq!(account.lamports, rent.minimum_balance(metadata.account_len()));
+    assert_eq!(
+        banks_client.get_balance(fixture.owner_account.pubkey()).await.unwrap(),
+        owner_balance + rent.minimum_balance(grown_len) - rent.minimum_balance(metadata.account_len())
+    );
 
-    // Получение аккаунта магазина и проверка его данных
-    let store_account_info = next_account_info(&mut banks_client,);
-    let store_data = Account::unpack(&store_account_info.borrow()).unwrap();
+    // Слишком длинное название отклоняется
+    let (instruction, _) = set_metadata_ix(&"x".repeat(65), "Short");
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[instruction], &[&fixture.owner_account]).await;
+    assert_custom_error(result, 17);
+}
 
-    // Проверка, что магазин был успешно инициализирован
-    assert_eq!(store_data.is_initialized(), true);
-    assert_eq!(store_data.mint, token_account.pubkey());
-    assert_eq!(store_data.owner, owner_account.pubkey());
-    assert_eq!(store_data.amount, 1000);
+#[tokio::test]
+async fn test_update_price() {
+    // Инициализация тестового окружения и магазина
+    let (mut banks_client, payer, recent_blockhash, fixture) = setup_store().await;
 
     // Подготовка новой цены
     let new_price: u64 = 2000;
-    let update_price_data = new_price.to_le_bytes();
-
-    let mut data = vec![];
-    data.extend_from_slice(&update_price_data);
-
-     let update_price_ix = Instruction {
-        program_id: id,
-        accounts: [store_account.pubkey(), owner_account.pubkey()].iter().map(|ac| ac.as_ref().unwrap()).collect(),
+    let mut data = vec![1];
+    data.extend_from_slice(&new_price.to_le_bytes());
+
+    let update_price_ix = Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new(fixture.store_account.pubkey(), false),
+            AccountMeta::new_readonly(fixture.owner_account.pubkey(), true),
+        ],
         data,
     };
 
     // Выполнение и ожидание транзакции
-    let mut transaction = Transaction::new_with_payer(
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
         &[update_price_ix],
-        Some(&payer.pubkey()),
-    );
-    transaction.sign(&[&payer, &store_account, &owner_account], recent_blockhash);
-
-    let result = banks_client.process_transaction(transaction).await;
+        &[&fixture.owner_account],
+    )
+    .await;
     assert!(result.is_ok(), "Update Price transaction failed: {:?}", result);
 
-    // Получение аккаунта магазина и проверка обновленной цены
-    let store_account_info = next_account_info(&mut banks_client);
-    let store_data = Account::unpack(&store_account_info.borrow()).unwrap();
-
     // Проверка, что цена была успешно обновлена
-    assert_eq!(store_data.amount, new_price);
+    let store_data = get_store(&mut banks_client, &fixture.store_account.pubkey()).await;
+    assert_eq!(store_data.price, new_price);
 }
 
+#[tokio::test]
+async fn test_deposit_inventory() {
+    // Инициализация тестового окружения и магазина
+    let (mut banks_client, payer, recent_blockhash, fixture) = setup_store().await;
+
+    // Пополнение хранилища магазина токенами владельца
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[deposit_ix(&fixture, 300)],
+        &[&fixture.owner_account],
+    )
+    .await;
+    assert!(result.is_ok(), "Deposit transaction failed: {:?}", result);
+
+    let store_data = get_store(&mut banks_client, &fixture.store_account.pubkey()).await;
+    assert_eq!(store_data.deposited, 300);
+    assert_eq!(store_data.supply, 300);
+    assert_eq!(get_token_balance(&mut banks_client, &fixture.vault.pubkey()).await, 300);
+}
 
 #[tokio::test]
-async fn test_sell() {
-    // Инициализация тестового окружения
-    let (mut banks_client, payer, recent_blockhash) = ProgramTest::new(
-        "your_program_name",
-        id,
-        processor!(process_instruction()),
+async fn test_token_2022_store() {
+    // Магазин с минтом Token-2022 и комиссией за перевод 1%
+    let (mut banks_client, payer, recent_blockhash, fixture) =
+        setup_store_with_mint(spl_token_2022::id(), Some(100)).await;
+    let (buyer, buyer_token_account) = setup_buyer(&mut banks_client, &payer, recent_blockhash, &fixture).await;
+
+    let store_data = get_store(&mut banks_client, &fixture.store_account.pubkey()).await;
+    assert_eq!(store_data.token_program, spl_token_2022::id());
+    assert_eq!(store_data.decimals, 0);
+
+    // В запасы засчитывается только то, что дошло до хранилища после комиссии
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[deposit_ix(&fixture, 300)],
+        &[&fixture.owner_account],
+    )
+    .await;
+    assert!(result.is_ok(), "Deposit transaction failed: {:?}", result);
+
+    let store_data = get_store(&mut banks_client, &fixture.store_account.pubkey()).await;
+    assert_eq!(store_data.deposited, 297);
+    assert_eq!(store_data.supply, 297);
+    assert_eq!(get_token_balance(&mut banks_client, &fixture.vault.pubkey()).await, 297);
+
+    // Покупка переводит токены через transfer_checked
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[buy_ix(&fixture, &buyer.pubkey(), &buyer_token_account.pubkey(), 100, 100_000, 0)],
+        &[&buyer],
     )
-    .start()
     .await;
+    assert!(result.is_ok(), "Buy transaction failed: {:?}", result);
 
-    // Создание аккаунтов и ключей для магазина, токенов продавца и покупателя
-    let store_account = Keypair::new();
-    let seller_token_account = Keypair::new();
-    let buyer_token_account = Keypair::new();
-    let owner_account = Keypair::new();
-
-    // Инициализация магазина
-    let store_data = Account {
-        mint: seller_token_account.pubkey(),
-        owner: owner_account.pubkey(),
-        amount: 1000,
-        delegate: Default::default(),
-        state: Default::default(),
-        is_native: Default::default(),
-        delegated_amount: 0,
-        close_authority: Default::default(),
-    };
-
-    // Определение данных для инициализации аккаунта магазина
-    let mut data = vec![];
-    data.extend_from_slice(&store_data.is_initialized().to_le_bytes());
-    data.extend_from_slice(&store_data.mint.to_bytes());
-    data.extend_from_slice(&store_data.owner.to_bytes());
-    data.extend_from_slice(&store_data.amount.to_le_bytes());
-
-    // Создание транзакции для инициализации магазина
-     let init_store_ix = Instruction {
-        program_id: id,
-        accounts: [store_account.pubkey(), seller_token_account.pubkey(), owner_account.pubkey()].iter().map(|ac| ac.as_ref().unwrap()).collect(),
-        data,
+    let store_data = get_store(&mut banks_client, &fixture.store_account.pubkey()).await;
+    assert_eq!(store_data.supply, 197);
+    assert_eq!(get_token_balance(&mut banks_client, &buyer_token_account.pubkey()).await, 99);
+
+    // Инструкция с другой токен-программой отклоняется
+    let mut wrong_program_ix = deposit_ix(&fixture, 10);
+    wrong_program_ix.accounts[5] = AccountMeta::new_readonly(spl_token::id(), false);
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[wrong_program_ix],
+        &[&fixture.owner_account],
+    )
+    .await;
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::IncorrectProgramId)
+    );
+}
+
+#[tokio::test]
+async fn test_transfer_fee_gross_up() {
+    // Магазин с минтом Token-2022 и комиссией за перевод 1%
+    let (mut banks_client, payer, recent_blockhash, fixture) =
+        setup_store_with_mint(spl_token_2022::id(), Some(100)).await;
+    let (buyer, buyer_token_account) = setup_buyer(&mut banks_client, &payer, recent_blockhash, &fixture).await;
+
+    // Комиссию оплачивает отправитель сверх количества
+    let set_fee_mode_ix = Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new(fixture.store_account.pubkey(), false),
+            AccountMeta::new_readonly(fixture.owner_account.pubkey(), true),
+        ],
+        data: vec![20, TRANSFER_FEE_GROSS_UP],
     };
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[deposit_ix(&fixture, 300), set_fee_mode_ix],
+        &[&fixture.owner_account],
+    )
+    .await;
+    assert!(result.is_ok(), "Set Transfer Fee Mode transaction failed: {:?}", result);
+
+    // Покупатель получает ровно запрошенное количество, хранилище отправляет 102
+    let receipt_rent = Rent::default().minimum_balance(Receipt::LEN);
+    let buyer_balance = banks_client.get_balance(buyer.pubkey()).await.unwrap();
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[buy_ix(&fixture, &buyer.pubkey(), &buyer_token_account.pubkey(), 100, 100_000, 0)],
+        &[&buyer],
+    )
+    .await;
+    assert!(result.is_ok(), "Buy transaction failed: {:?}", result);
+
+    assert_eq!(get_token_balance(&mut banks_client, &buyer_token_account.pubkey()).await, 100);
+    assert_eq!(banks_client.get_balance(buyer.pubkey()).await.unwrap(), buyer_balance - 100_000 - receipt_rent);
+    let store_data = get_store(&mut banks_client, &fixture.store_account.pubkey()).await;
+    assert_eq!(store_data.supply, 195);
+    assert_eq!(store_data.sold, 102);
+
+    // При обратном выкупе продавец доплачивает комиссию, в хранилище приходит 50
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[sell_ix(&fixture, &buyer.pubkey(), &buyer_token_account.pubkey(), 50)],
+        &[&buyer, &fixture.owner_account],
+    )
+    .await;
+    assert!(result.is_ok(), "Sell transaction failed: {:?}", result);
+
+    assert_eq!(get_token_balance(&mut banks_client, &buyer_token_account.pubkey()).await, 49);
+    let store_data = get_store(&mut banks_client, &fixture.store_account.pubkey()).await;
+    assert_eq!(store_data.supply, 245);
+    assert_eq!(store_data.bought_back, 50);
+}
+
+#[tokio::test]
+async fn test_sell() {
+    // Инициализация тестового окружения и магазина
+    let (mut banks_client, payer, recent_blockhash, fixture) = setup_store().await;
+    let (buyer, buyer_token_account) = setup_buyer(&mut banks_client, &payer, recent_blockhash, &fixture).await;
+
+    // Пополнение хранилища и покупка токенов
+    process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[
+            deposit_ix(&fixture, 100),
+            buy_ix(&fixture, &buyer.pubkey(), &buyer_token_account.pubkey(), 40, 40_000, 0),
+        ],
+        &[&fixture.owner_account, &buyer],
+    )
+    .await
+    .unwrap();
 
-    // Выполнение и ожидание транзакции и проверка успешной инициализации магазина
-    let mut transaction = Transaction::new_with_payer(
-        &[init_store_ix],
-        Some(&payer.pubkey()),
+    // Без одобрения владельца выкуп не проходит
+    let (proceeds_account, _) = find_proceeds_address(&fixture.program_id, &fixture.store_account.pubkey());
+    let mut unapproved_ix = sell_ix(&fixture, &buyer.pubkey(), &buyer_token_account.pubkey(), 15);
+    unapproved_ix.accounts[6] = AccountMeta::new_readonly(fixture.owner_account.pubkey(), false);
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[unapproved_ix], &[&buyer]).await;
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::MissingRequiredSignature)
     );
-    transaction.sign(&[&payer, &store_account, &seller_token_account, &owner_account], recent_blockhash);
 
-    let result = banks_client.process_transaction(transaction).await;
-    assert!(result.is_ok(), "Initialize Store transaction failed: {:?}", result);
+    // Продажа части токенов обратно магазину, оплата из выручки
+    let proceeds_balance = banks_client.get_balance(proceeds_account).await.unwrap();
+    let seller_balance = banks_client.get_balance(buyer.pubkey()).await.unwrap();
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[sell_ix(&fixture, &buyer.pubkey(), &buyer_token_account.pubkey(), 15)],
+        &[&buyer, &fixture.owner_account],
+    )
+    .await;
+    assert!(result.is_ok(), "Sell transaction failed: {:?}", result);
+    assert_eq!(banks_client.get_balance(proceeds_account).await.unwrap(), proceeds_balance - 15_000);
+    assert_eq!(banks_client.get_balance(buyer.pubkey()).await.unwrap(), seller_balance + 15_000);
+
+    // Проверка, что магазин выкупил токены и учёл их в остатке
+    let store_data = get_store(&mut banks_client, &fixture.store_account.pubkey()).await;
+    assert_eq!(store_data.sold, 40);
+    assert_eq!(store_data.bought_back, 15);
+    assert_eq!(store_data.supply, 75);
+    assert_eq!(get_token_balance(&mut banks_client, &buyer_token_account.pubkey()).await, 25);
+}
 
-    // Получение аккаунта магазина и проверка его данных
-    let store_account_info = next_account_info(&mut banks_client);
-    let store_data = Account::unpack(&store_account_info.borrow()).unwrap();
+#[tokio::test]
+async fn test_buy() {
+    // Инициализация тестового окружения и магазина
+    let (mut banks_client, payer, recent_blockhash, fixture) = setup_store().await;
+    let (buyer, buyer_token_account) = setup_buyer(&mut banks_client, &payer, recent_blockhash, &fixture).await;
+
+    process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[deposit_ix(&fixture, 100)],
+        &[&fixture.owner_account],
+    )
+    .await
+    .unwrap();
 
-    // Проверка, что магазин был успешно инициализирован
-    assert_eq!(store_data.is_initialized(), true);
-    assert_eq!(store_data.mint, seller_token_account.pubkey());
-    assert_eq!(store_data.owner, owner_account.pubkey());
-    assert_eq!(store_data.amount, 1000); // Проверьте начальную цену
-
-    // Подготовка токенов для продажи
-    let seller_token_mint = Keypair::new();
-    let seller_token_amount: u64 = 200; // Количество продаваемых токенов
-    let buyer_token_mint = Keypair::new();
-
-    let create_seller_token_mint_ix = spl_token::instruction::initialize_mint(
-        &token_program_id,
-        &seller_token_mint.pubkey(),
-        &store_account.pubkey(),
-        None,
-        0,
+    let (proceeds_account, _) = find_proceeds_address(&fixture.program_id, &fixture.store_account.pubkey());
+    let proceeds_balance = banks_client.get_balance(proceeds_account).await.unwrap();
+
+    // Покупка без подписи покупателя отклоняется
+    let mut unsigned_ix = buy_ix(&fixture, &buyer.pubkey(), &buyer_token_account.pubkey(), 10, 10_000, 0);
+    unsigned_ix.accounts[6] = AccountMeta::new(buyer.pubkey(), false);
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[unsigned_ix], &[]).await;
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::MissingRequiredSignature)
+    );
+
+    // Покупка токенов из хранилища магазина
+    let buy_amount: u64 = 100;
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[buy_ix(&fixture, &buyer.pubkey(), &buyer_token_account.pubkey(), buy_amount, 100_000, 0)],
+        &[&buyer],
+    )
+    .await;
+    assert!(result.is_ok(), "Buy transaction failed: {:?}", result);
+
+    // Проверка, что покупатель получил токены, а оплата поступила в магазин
+    assert_eq!(get_token_balance(&mut banks_client, &buyer_token_account.pubkey()).await, buy_amount);
+    assert_eq!(
+        banks_client.get_balance(proceeds_account).await.unwrap(),
+        proceeds_balance + buy_amount * 1000
+    );
+
+    let store_data = get_store(&mut banks_client, &fixture.store_account.pubkey()).await;
+    assert_eq!(store_data.sold, buy_amount);
+    assert_eq!(store_data.supply, 0);
+    assert_eq!(store_data.receipt_count, 1);
+
+    // Квитанция о покупке
+    let (receipt_account, _) = find_receipt_address(&fixture.program_id, &fixture.store_account.pubkey(), 0);
+    let account = banks_client.get_account(receipt_account).await.unwrap().unwrap();
+    let receipt = Receipt::unpack(&account.data).unwrap();
+    assert_eq!(receipt.store, fixture.store_account.pubkey());
+    assert_eq!(receipt.buyer, buyer.pubkey());
+    assert_eq!(receipt.quantity, buy_amount);
+    assert_eq!(receipt.price_paid, buy_amount * 1000);
+    assert_eq!(receipt.number, 0);
+
+    // Повторная покупка должна завершиться ошибкой "распродано"
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[buy_ix(&fixture, &buyer.pubkey(), &buyer_token_account.pubkey(), 1, 1_000, 1)],
+        &[&buyer],
     )
+    .await;
+    assert_custom_error(result, 4);
+}
+
+#[tokio::test]
+async fn test_buy_prefunded_receipt() {
+    // Инициализация тестового окружения и магазина
+    let (mut banks_client, payer, recent_blockhash, fixture) = setup_store().await;
+    let (buyer, buyer_token_account) = setup_buyer(&mut banks_client, &payer, recent_blockhash, &fixture).await;
+    process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[deposit_ix(&fixture, 100)],
+        &[&fixture.owner_account],
+    )
+    .await
     .unwrap();
 
-    let create_buyer_token_mint_ix = spl_token::instruction::initialize_mint(
-        &token_program_id,
-        &buyer_token_mint.pubkey(),
-        &store_account.pubkey(),
-        None,
-        0,
+    // Кто-то заранее переводит лампорты на адрес следующей квитанции
+    let (receipt_account, _) = find_receipt_address(&fixture.program_id, &fixture.store_account.pubkey(), 0);
+    process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[transfer(&payer.pubkey(), &receipt_account, 1_000_000)],
+        &[],
     )
+    .await
     .unwrap();
 
-    let seller_create_token_account_ix = spl_token::instruction::initialize_account(
-        &token_program_id,
-        &seller_token_mint.pubkey(),
-        &seller_token_account.pubkey(),
-        &store_account.pubkey(),
+    // Покупка всё равно проходит, а квитанция создаётся по этому адресу
+    process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[buy_ix(&fixture, &buyer.pubkey(), &buyer_token_account.pubkey(), 10, 10_000, 0)],
+        &[&buyer],
     )
+    .await
     .unwrap();
 
-    let buyer_create_token_account_ix = spl_token::instruction::initialize_account(
-        &token_program_id,
-        &buyer_token_mint.pubkey(),
-        &buyer_token_account.pubkey(),
-        &store_account.pubkey(),
+    let account = banks_client.get_account(receipt_account).await.unwrap().unwrap();
+    assert_eq!(account.owner, fixture.program_id);
+    assert_eq!(account.lamports, Rent::default().minimum_balance(Receipt::LEN));
+    let receipt = Receipt::unpack(&account.data).unwrap();
+    assert_eq!(receipt.buyer, buyer.pubkey());
+    assert_eq!(receipt.quantity, 10);
+    assert_eq!(get_token_balance(&mut banks_client, &buyer_token_account.pubkey()).await, 10);
+}
+
+#[tokio::test]
+async fn test_buy_many() {
+    // Инициализация тестового окружения и магазина
+    let (mut banks_client, payer, recent_blockhash, fixture) = setup_store().await;
+    let (buyer, buyer_token_account) = setup_buyer(&mut banks_client, &payer, recent_blockhash, &fixture).await;
+    process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[deposit_ix(&fixture, 100)],
+        &[&fixture.owner_account],
     )
+    .await
     .unwrap();
 
-    let mut data = vec![];
-    data.extend_from_slice(&[0u8; 32]); // Заглушка для данных о токенах
+    // Каждая запись - данные и аккаунты обычной покупки, квитанции
+    // нумеруются подряд
+    let buy_many_ix = |first_receipt: u64, entries: &[(u64, u64)]| {
+        let mut data = vec![21, entries.len() as u8];
+        let mut accounts = vec![];
+        for (receipt_number, &(quantity, max_cost)) in (first_receipt..).zip(entries) {
+            let entry_ix =
+                buy_ix(&fixture, &buyer.pubkey(), &buyer_token_account.pubkey(), quantity, max_cost, receipt_number);
+            data.extend_from_slice(&entry_ix.data[1..]);
+            data.push(0);
+            data.push(entry_ix.accounts.len() as u8);
+            accounts.extend(entry_ix.accounts);
+        }
+        Instruction {
+            program_id: fixture.program_id,
+            accounts,
+            data,
+        }
+    };
 
-    let create_seller_token_ix = create_account(
-        &owner_account.pubkey(),
-        &seller_token_account.pubkey(),
-        1,
-        10,
-        &owner_account.pubkey());
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[buy_many_ix(0, &[(10, 10_000), (20, 20_000)])],
+        &[&buyer],
+    )
+    .await;
+    assert!(result.is_ok(), "Buy Many transaction failed: {:?}", result);
+    assert_eq!(get_token_balance(&mut banks_client, &buyer_token_account.pubkey()).await, 30);
+    assert_eq!(get_store(&mut banks_client, &fixture.store_account.pubkey()).await.supply, 70);
+
+    // Ошибка в одной записи откатывает все остальные
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[buy_many_ix(2, &[(5, 5_000), (80, 80_000)])],
+        &[&buyer],
+    )
+    .await;
+    assert_custom_error(result, 5);
+    assert_eq!(get_token_balance(&mut banks_client, &buyer_token_account.pubkey()).await, 30);
+    assert_eq!(get_store(&mut banks_client, &fixture.store_account.pubkey()).await.supply, 70);
+}
 
-    let create_buyer_token_ix = create_account(
-        &owner_account.pubkey(),
-        &buyer_token_account.pubkey(),
-        1,
-        10,
-        &owner_account.pubkey()
+#[tokio::test]
+async fn test_buy_creates_ata() {
+    // Инициализация тестового окружения и магазина
+    let (mut banks_client, payer, recent_blockhash, fixture) = setup_store().await;
+    process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[deposit_ix(&fixture, 100)],
+        &[&fixture.owner_account],
+    )
+    .await
+    .unwrap();
+
+    // Покупатель без токен-аккаунта
+    let buyer = Keypair::new();
+    let fund_ix = transfer(&payer.pubkey(), &buyer.pubkey(), 1_000_000_000);
+    process(&mut banks_client, &payer, recent_blockhash, &[fund_ix], &[]).await.unwrap();
+    let buyer_ata = get_associated_token_address_with_program_id(&buyer.pubkey(), &fixture.mint.pubkey(), &spl_token::id());
+
+    let buy_with_ata_ix = |quantity: u64, receipt_number: u64| {
+        let mut instruction = buy_ix(&fixture, &buyer.pubkey(), &buyer_ata, quantity, quantity * 1_000, receipt_number);
+        instruction.data.push(BUY_CREATE_ATA);
+        instruction
+            .accounts
+            .insert(12, AccountMeta::new_readonly(spl_associated_token_account::id(), false));
+        instruction
+    };
+
+    // Первая покупка создает ассоциированный токен-аккаунт
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[buy_with_ata_ix(10, 0)], &[&buyer]).await;
+    assert!(result.is_ok(), "Buy transaction failed: {:?}", result);
+    assert_eq!(get_token_balance(&mut banks_client, &buyer_ata).await, 10);
+
+    // Повторная покупка с тем же флагом использует существующий аккаунт
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[buy_with_ata_ix(5, 1)], &[&buyer]).await;
+    assert!(result.is_ok(), "Buy transaction failed: {:?}", result);
+    assert_eq!(get_token_balance(&mut banks_client, &buyer_ata).await, 15);
+
+    // Токен-аккаунт не по адресу ATA отклоняется
+    let other_account = Keypair::new();
+    let mut instruction = buy_with_ata_ix(1, 2);
+    instruction.accounts[5] = AccountMeta::new(other_account.pubkey(), false);
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[instruction], &[&buyer]).await;
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::InvalidArgument)
     );
+}
 
-    // Отправка токенов на аккаунты продавца и покупателя
-    let transfer_to_seller_ix = spl_token::instruction::transfer(
-        &token_program_id,
-        &owner_account.pubkey(),
-        &seller_token_account.pubkey(),
-        &store_account.pubkey(),
+// Инструкция ed25519-программы, проверяющая подпись сообщения
+fn ed25519_ix(signer: &Keypair, message: &[u8]) -> Instruction {
+    const PUBKEY_OFFSET: u16 = 16;
+    const SIGNATURE_OFFSET: u16 = PUBKEY_OFFSET + 32;
+    const MESSAGE_OFFSET: u16 = SIGNATURE_OFFSET + 64;
+
+    let mut data = vec![1, 0];
+    for field in [
+        SIGNATURE_OFFSET,
+        u16::MAX,
+        PUBKEY_OFFSET,
+        u16::MAX,
+        MESSAGE_OFFSET,
+        message.len() as u16,
+        u16::MAX,
+    ] {
+        data.extend_from_slice(&field.to_le_bytes());
+    }
+    data.extend_from_slice(signer.pubkey().as_ref());
+    data.extend_from_slice(signer.sign_message(message).as_ref());
+    data.extend_from_slice(message);
+
+    Instruction {
+        program_id: solana_program::ed25519_program::id(),
+        accounts: vec![],
+        data,
+    }
+}
+
+#[tokio::test]
+async fn test_buy_with_voucher() {
+    // Инициализация тестового окружения и магазина
+    let (mut banks_client, payer, recent_blockhash, fixture) = setup_store().await;
+    let (buyer, buyer_token_account) = setup_buyer(&mut banks_client, &payer, recent_blockhash, &fixture).await;
+    process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[deposit_ix(&fixture, 100)],
+        &[&fixture.owner_account],
+    )
+    .await
+    .unwrap();
+
+    let (proceeds_account, _) = find_proceeds_address(&fixture.program_id, &fixture.store_account.pubkey());
+    let voucher = |discount_bps: u16, expiry: i64, nonce: u64| Voucher {
+        store: fixture.store_account.pubkey(),
+        buyer: buyer.pubkey(),
+        discount_bps,
+        expiry,
+        nonce,
+    };
+    // Подписанный ваучер передается перед покупкой в той же транзакции
+    let buy_with_voucher_ixs = |voucher: &Voucher, signer: &Keypair, quantity: u64, max_cost: u64, receipt_number: u64| {
+        let mut instruction =
+            buy_ix(&fixture, &buyer.pubkey(), &buyer_token_account.pubkey(), quantity, max_cost, receipt_number);
+        instruction.data.push(BUY_VOUCHER);
+        instruction.data.extend_from_slice(&voucher.pack_terms());
+        let (nonce_account, _) =
+            find_voucher_nonce_address(&fixture.program_id, &fixture.store_account.pubkey(), voucher.nonce);
+        instruction.accounts.insert(12, AccountMeta::new_readonly(solana_program::sysvar::instructions::id(), false));
+        instruction.accounts.insert(13, AccountMeta::new(nonce_account, false));
+        [ed25519_ix(signer, &voucher.message()), instruction]
+    };
+
+    // Адрес nonce пополнен заранее, это не делает ваучер использованным
+    let discount = voucher(2_500, i64::MAX, 1);
+    let (discount_nonce, _) = find_voucher_nonce_address(&fixture.program_id, &fixture.store_account.pubkey(), 1);
+    process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[transfer(&payer.pubkey(), &discount_nonce, 1_000_000)],
         &[],
-        seller_token_amount,
     )
+    .await
     .unwrap();
 
-    // Создание транзакции для продажи
-    let mut data = vec![];
-    data.extend_from_slice(&seller_token_amount.to_le_bytes()); // Количество продаваемых токенов
+    // Скидка 25% позволяет заплатить меньше полной цены
+    let proceeds_balance = banks_client.get_balance(proceeds_account).await.unwrap();
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &buy_with_voucher_ixs(&discount, &fixture.owner_account, 10, 7_500, 0),
+        &[&buyer],
+    )
+    .await;
+    assert!(result.is_ok(), "Buy transaction failed: {:?}", result);
+    assert_eq!(get_token_balance(&mut banks_client, &buyer_token_account.pubkey()).await, 10);
+    assert_eq!(banks_client.get_balance(proceeds_account).await.unwrap(), proceeds_balance + 7_500);
+
+    // Повторное использование ваучера отклоняется
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &buy_with_voucher_ixs(&discount, &fixture.owner_account, 5, 3_750, 1),
+        &[&buyer],
+    )
+    .await;
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(1, InstructionError::Custom(18))
+    );
 
-    let sell_ix = Instruction {
-        program_id: id,
-        accounts: [store_account.pubkey(), seller_token_account.pubkey(), buyer_token_account.pubkey(), owner_account.pubkey()].iter().map(|ac| ac.as_ref().unwrap()).collect(),
-        data,
-    };
+    // Ваучер с истекшим сроком
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &buy_with_voucher_ixs(&voucher(2_500, 0, 2), &fixture.owner_account, 5, 3_750, 1),
+        &[&buyer],
+    )
+    .await;
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(1, InstructionError::Custom(19))
+    );
 
-    // Выполнение и ожидание транзакции
-    let mut transaction = Transaction::new_with_payer(
-        &[sell_ix, transfer_to_seller_ix],
-        Some(&payer.pubkey()),
+    // Ваучер, подписанный не владельцем магазина
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &buy_with_voucher_ixs(&voucher(2_500, i64::MAX, 3), &buyer, 5, 3_750, 1),
+        &[&buyer],
+    )
+    .await;
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(1, InstructionError::Custom(20))
     );
-    transaction.sign(&[&payer, &store_account, &seller_token_account, &owner_account], recent_blockhash);
 
-    let result = banks_client.process_transaction(transaction).await;
-    assert!(result.is_ok(), "Sell transaction failed: {:?}", result);
+    // Токены по ваучеру уходят только на аккаунт покупателя из ваучера
+    let mut redirected = buy_with_voucher_ixs(&voucher(2_500, i64::MAX, 5), &fixture.owner_account, 5, 3_750, 1);
+    redirected[1].accounts[5] = AccountMeta::new(fixture.owner_token_account.pubkey(), false);
+    let result = process(&mut banks_client, &payer, recent_blockhash, &redirected, &[&buyer]).await;
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(1, InstructionError::InvalidArgument)
+    );
 
-    // Получение аккаунтов и проверка выполнения продажи
-    let seller_account_info = next_account_info(&mut banks_client);
-    let buyer_account_info = next_account_info(&mut banks_client);
+    // Ваучер на полную стоимость не принимается
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &buy_with_voucher_ixs(&voucher(10_000, i64::MAX, 4), &fixture.owner_account, 5, 0, 1),
+        &[&buyer],
+    )
+    .await;
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(1, InstructionError::InvalidInstructionData)
+    );
+}
 
-    // Проверка, что продавец продал токены, а покупатель их получил
-    let seller_token_account_data = spl_token::state::Account::unpack(&seller_account_info.borrow()).unwrap();
-    let buyer_token_account_data = spl_token::state::Account::unpack(&buyer_account_info.borrow()).unwrap();
+#[tokio::test]
+async fn test_buy_with_quote() {
+    // Инициализация тестового окружения и магазина
+    let (mut banks_client, payer, recent_blockhash, fixture) = setup_store().await;
+    let (buyer, buyer_token_account) = setup_buyer(&mut banks_client, &payer, recent_blockhash, &fixture).await;
+    let (roles_account, _) = find_roles_address(&fixture.program_id, &fixture.store_account.pubkey());
+    let pricing_bot = Keypair::new();
+    process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[
+            deposit_ix(&fixture, 100),
+            role_ix(&fixture, 6, &payer.pubkey(), &pricing_bot.pubkey(), ROLE_PRICER),
+        ],
+        &[&fixture.owner_account],
+    )
+    .await
+    .unwrap();
 
-    assert_eq!(seller_token_account_data.amount, 0, "Seller still has tokens");
-    assert_eq!(buyer_token_account_data.amount, seller_token_amount, "Buyer did not receive tokens");
+    let (proceeds_account, _) = find_proceeds_address(&fixture.program_id, &fixture.store_account.pubkey());
+    let quote = |pricer: &Keypair, price: u64, quantity: u64, expiry: i64, nonce: u64| Quote {
+        store: fixture.store_account.pubkey(),
+        pricer: pricer.pubkey(),
+        price,
+        quantity,
+        expiry,
+        nonce,
+    };
+    // Котировка подписывается вне сети и передается вместе с покупкой
+    let buy_with_quote_ixs = |quote: &Quote, signer: &Keypair, quantity: u64, receipt_number: u64| {
+        let max_cost = quantity * quote.price;
+        let mut instruction =
+            buy_ix(&fixture, &buyer.pubkey(), &buyer_token_account.pubkey(), quantity, max_cost, receipt_number);
+        instruction.data.push(BUY_QUOTE);
+        instruction.data.extend_from_slice(&quote.pack_terms());
+        let (nonce_account, _) =
+            find_quote_nonce_address(&fixture.program_id, &fixture.store_account.pubkey(), quote.nonce);
+        instruction.accounts.insert(12, AccountMeta::new_readonly(solana_program::sysvar::instructions::id(), false));
+        instruction.accounts.insert(13, AccountMeta::new(nonce_account, false));
+        if quote.pricer != fixture.owner_account.pubkey() {
+            instruction.accounts.insert(14, AccountMeta::new_readonly(roles_account, false));
+        }
+        [ed25519_ix(signer, &quote.message()), instruction]
+    };
+    let assert_buy_error = |result: Result<(), BanksClientError>, code: u32| {
+        assert_eq!(
+            result.unwrap_err().unwrap(),
+            TransactionError::InstructionError(1, InstructionError::Custom(code))
+        );
+    };
+
+    // Покупка по котировке владельца, а не по цене магазина
+    let owner_quote = quote(&fixture.owner_account, 600, 20, i64::MAX, 1);
+    let proceeds_balance = banks_client.get_balance(proceeds_account).await.unwrap();
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &buy_with_quote_ixs(&owner_quote, &fixture.owner_account, 10, 0),
+        &[&buyer],
+    )
+    .await;
+    assert!(result.is_ok(), "Buy transaction failed: {:?}", result);
+    assert_eq!(get_token_balance(&mut banks_client, &buyer_token_account.pubkey()).await, 10);
+    assert_eq!(banks_client.get_balance(proceeds_account).await.unwrap(), proceeds_balance + 6_000);
+
+    // Котировка исполняется только один раз
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &buy_with_quote_ixs(&owner_quote, &fixture.owner_account, 5, 1),
+        &[&buyer],
+    )
+    .await;
+    assert_buy_error(result, 18);
+
+    // Котировка ценового бота
+    let bot_quote = quote(&pricing_bot, 800, 20, i64::MAX, 2);
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &buy_with_quote_ixs(&bot_quote, &pricing_bot, 20, 1),
+        &[&buyer],
+    )
+    .await;
+    assert!(result.is_ok(), "Buy transaction failed: {:?}", result);
+    assert_eq!(get_token_balance(&mut banks_client, &buyer_token_account.pubkey()).await, 30);
+
+    // Количество сверх котировки
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &buy_with_quote_ixs(&quote(&pricing_bot, 800, 5, i64::MAX, 3), &pricing_bot, 6, 2),
+        &[&buyer],
+    )
+    .await;
+    assert_buy_error(result, 21);
+
+    // Просроченная котировка
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &buy_with_quote_ixs(&quote(&pricing_bot, 800, 5, 0, 4), &pricing_bot, 5, 2),
+        &[&buyer],
+    )
+    .await;
+    assert_buy_error(result, 19);
+
+    // Котировка от имени бота, подписанная покупателем
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &buy_with_quote_ixs(&quote(&pricing_bot, 1, 5, i64::MAX, 5), &buyer, 5, 2),
+        &[&buyer],
+    )
+    .await;
+    assert_buy_error(result, 20);
+
+    // Котировка подписанта без роли
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &buy_with_quote_ixs(&quote(&buyer, 1, 5, i64::MAX, 6), &buyer, 5, 2),
+        &[&buyer],
+    )
+    .await;
+    assert_buy_error(result, 6);
 }
 
+// События попадают в логи строками "Program data:" с base64 от Event::pack
+#[cfg(feature = "client")]
+#[test]
+fn test_events_emitted() {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use sfxdx::events::decode_logs;
+
+    let events = vec![
+        Event::InventoryDeposited {
+            store: Pubkey::new_unique(),
+            amount: 250,
+            supply: 250,
+        },
+        Event::MultisigInitialized {
+            multisig: Pubkey::new_unique(),
+            m: 2,
+            n: 3,
+        },
+    ];
+    // Данные, записанные другой программой, в том числе вызванной из нашей,
+    // не принимаются за события магазина
+    let program_id = Pubkey::new_unique();
+    let forged = Event::InventoryDeposited {
+        store: Pubkey::new_unique(),
+        amount: 1,
+        supply: 1,
+    };
+    let other_program = "11111111111111111111111111111111";
+    let logs = vec![
+        format!("Program {} invoke [1]", other_program),
+        format!("Program data: {}", STANDARD.encode(forged.pack())),
+        format!("Program {} success", other_program),
+        format!("Program {} invoke [1]", program_id),
+        format!("Program data: {}", STANDARD.encode(events[0].pack())),
+        format!("Program {} invoke [2]", other_program),
+        format!("Program data: {}", STANDARD.encode(forged.pack())),
+        format!("Program {} success", other_program),
+        "Program log: Inventory deposited successfully!".to_string(),
+        format!("Program data: {}", STANDARD.encode(events[1].pack())),
+        "Program data: not-an-event".to_string(),
+        format!("Program {} consumed 5000 of 200000 compute units", program_id),
+        format!("Program {} success", program_id),
+        format!("Program data: {}", STANDARD.encode(forged.pack())),
+    ];
+    assert_eq!(decode_logs(&program_id, &logs), events);
+}
+
+#[test]
+fn test_event_roundtrip() {
+    let event = Event::Bought {
+        store: Pubkey::new_unique(),
+        buyer: Pubkey::new_unique(),
+        quantity: 7,
+        cost: 7_000,
+    };
+    let packed = event.pack();
+    assert_eq!(packed[0], sfxdx::events::EVENT_VERSION);
+    assert_eq!(Event::unpack(&packed).unwrap(), event);
+
+    // Неизвестная версия события не декодируется
+    let mut unknown_version = packed.clone();
+    unknown_version[0] = 0;
+    assert!(Event::unpack(&unknown_version).is_err());
+
+    // Раскладка события: версия, вид, затем поля по порядку
+    let multisig = Pubkey::new_unique();
+    let mut expected = vec![sfxdx::events::EVENT_VERSION, 31];
+    expected.extend_from_slice(multisig.as_ref());
+    expected.extend_from_slice(&[2, 3]);
+    assert_eq!(Event::MultisigInitialized { multisig, m: 2, n: 3 }.pack(), expected);
+}
 
 #[tokio::test]
-async fn test_buy() {
-    // Инициализация тестового окружения
-    let (mut banks_client, payer, recent_blockhash) = ProgramTest::new(
-        "your_program_name",
-        id,
-        processor!(process_instruction),
+async fn test_multisig_update_price() {
+    // Инициализация тестового окружения и магазина
+    let (mut banks_client, payer, recent_blockhash, fixture) = setup_store().await;
+
+    // Создание мультиподписи 2 из 3
+    let multisig_account = Keypair::new();
+    let signers = [Keypair::new(), Keypair::new(), Keypair::new()];
+    let create_multisig_ix = create_account(
+        &payer.pubkey(),
+        &multisig_account.pubkey(),
+        Rent::default().minimum_balance(Multisig::LEN),
+        Multisig::LEN as u64,
+        &fixture.program_id,
+    );
+    let mut accounts = vec![AccountMeta::new(multisig_account.pubkey(), false)];
+    accounts.extend(signers.iter().map(|signer| AccountMeta::new_readonly(signer.pubkey(), false)));
+    let init_multisig_ix = Instruction {
+        program_id: fixture.program_id,
+        accounts,
+        data: vec![5, 2],
+    };
+
+    // Один и тот же ключ дважды не может закрыть порог 2 из N
+    let mut duplicate_ix = init_multisig_ix.clone();
+    duplicate_ix.accounts[2] = AccountMeta::new_readonly(signers[0].pubkey(), false);
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[create_multisig_ix.clone(), duplicate_ix],
+        &[&multisig_account],
     )
-    .start()
     .await;
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(1, InstructionError::InvalidArgument)
+    );
 
-    // Создание аккаунтов и ключей для магазина, токенов продавца и покупателя
+    // Магазин, владельцем которого является мультиподпись
     let store_account = Keypair::new();
-    let seller_token_account = Keypair::new();
-    let buyer_token_account = Keypair::new();
-    let owner_account = Keypair::new();
-
-    // Инициализация магазина
-    let store_data = Account {
-        mint: seller_token_account.pubkey(),
-        owner: owner_account.pubkey(),
-        amount: 1000,
-        delegate: Default::default(),
-        state: Default::default(),
-        is_native: Default::default(),
-        delegated_amount: 0,
-        close_authority: Default::default(),
-    };
-
-    // Определение данных для инициализации аккаунта магазина
-    let mut data = vec![];
-    data.extend_from_slice(&store_data.is_initialized().to_le_bytes());
-    data.extend_from_slice(&store_data.mint.to_bytes());
-    data.extend_from_slice(&store_data.owner.to_bytes());
-    data.extend_from_slice(&store_data.amount.to_le_bytes());
-
-    // Создание транзакции для инициализации магазина
-     let init_store_ix = Instruction {
-        program_id: id,
-        accounts: [store_account.pubkey(), seller_token_account.pubkey(), owner_account.pubkey()].iter().map(|ac| ac.as_ref().unwrap()).collect(),
-        data,
+    let (vault_authority, _) = find_vault_authority(&fixture.program_id, &store_account.pubkey());
+    let vault = Keypair::new();
+    create_token_account(&mut banks_client, &payer, recent_blockhash, &vault, &fixture.mint.pubkey(), &vault_authority).await;
+    let create_store_ix = create_account(
+        &payer.pubkey(),
+        &store_account.pubkey(),
+        Rent::default().minimum_balance(Store::LEN),
+        Store::LEN as u64,
+        &fixture.program_id,
+    );
+    let init_store_ix = Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new(store_account.pubkey(), false),
+            AccountMeta::new(find_config_address(&fixture.program_id).0, false),
+            AccountMeta::new_readonly(fixture.mint.pubkey(), false),
+            AccountMeta::new_readonly(multisig_account.pubkey(), false),
+            AccountMeta::new_readonly(vault.pubkey(), false),
+            AccountMeta::new(find_proceeds_address(&fixture.program_id, &store_account.pubkey()).0, false),
+            AccountMeta::new(payer.pubkey(), true),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new(find_registry_page_address(&fixture.program_id, 0).0, false),
+            AccountMeta::new_readonly(signers[0].pubkey(), true),
+            AccountMeta::new_readonly(signers[1].pubkey(), true),
+        ],
+        data: vec![0],
     };
-    // Выполнение и ожидание транзакции и проверка успешной инициализации магазина
-    let mut transaction = Transaction::new_with_payer(
-        &[init_store_ix],
-        Some(&payer.pubkey()),
+    process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[create_multisig_ix, init_multisig_ix, create_store_ix, init_store_ix],
+        &[&multisig_account, &store_account, &signers[0], &signers[1]],
+    )
+    .await
+    .unwrap();
+
+    let update_price_ix = |price: u64, signers: &[&Keypair]| {
+        let mut data = vec![1];
+        data.extend_from_slice(&price.to_le_bytes());
+        let mut accounts = vec![
+            AccountMeta::new(store_account.pubkey(), false),
+            AccountMeta::new_readonly(multisig_account.pubkey(), false),
+        ];
+        accounts.extend(signers.iter().map(|signer| AccountMeta::new_readonly(signer.pubkey(), true)));
+        Instruction {
+            program_id: fixture.program_id,
+            accounts,
+            data,
+        }
+    };
+
+    // Одной подписи недостаточно
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[update_price_ix(1500, &[&signers[0]])],
+        &[&signers[0]],
+    )
+    .await;
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::MissingRequiredSignature)
     );
-    transaction.sign(&[&payer, &store_account, &seller_token_account, &owner_account], recent_blockhash);
 
-    let result = banks_client.process_transaction(transaction).await;
-    assert!(result.is_ok(), "Initialize Store transaction failed: {:?}", result);
+    // Две подписи из трёх позволяют изменить цену
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[update_price_ix(1500, &[&signers[0], &signers[2]])],
+        &[&signers[0], &signers[2]],
+    )
+    .await;
+    assert!(result.is_ok(), "Multisig Update Price transaction failed: {:?}", result);
+
+    let store_data = get_store(&mut banks_client, &store_account.pubkey()).await;
+    assert_eq!(store_data.owner, multisig_account.pubkey());
+    assert!(store_data.owner_is_multisig);
+    assert_eq!(store_data.price, 1500);
+
+    // Ключ аккаунта мультиподписи не может подписать ваучер в обход порога
+    let multisig_fixture = StoreFixture {
+        program_id: fixture.program_id,
+        store_account: Keypair::from_bytes(&store_account.to_bytes()).unwrap(),
+        mint: Keypair::from_bytes(&fixture.mint.to_bytes()).unwrap(),
+        vault: Keypair::from_bytes(&vault.to_bytes()).unwrap(),
+        owner_account: Keypair::from_bytes(&multisig_account.to_bytes()).unwrap(),
+        owner_token_account: Keypair::from_bytes(&fixture.owner_token_account.to_bytes()).unwrap(),
+        fee_recipient: payer.pubkey(),
+        token_program: spl_token::id(),
+    };
+    let (buyer, buyer_token_account) = setup_buyer(&mut banks_client, &payer, recent_blockhash, &multisig_fixture).await;
+    let voucher = Voucher {
+        store: store_account.pubkey(),
+        buyer: buyer.pubkey(),
+        discount_bps: 9_999,
+        expiry: i64::MAX,
+        nonce: 1,
+    };
+    let mut buy_with_voucher_ix = buy_ix(&multisig_fixture, &buyer.pubkey(), &buyer_token_account.pubkey(), 10, 10, 0);
+    buy_with_voucher_ix.data.push(BUY_VOUCHER);
+    buy_with_voucher_ix.data.extend_from_slice(&voucher.pack_terms());
+    buy_with_voucher_ix.accounts.push(AccountMeta::new_readonly(solana_program::sysvar::instructions::id(), false));
+    buy_with_voucher_ix.accounts.push(AccountMeta::new(
+        find_voucher_nonce_address(&fixture.program_id, &store_account.pubkey(), 1).0,
+        false,
+    ));
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[ed25519_ix(&multisig_account, &voucher.message()), buy_with_voucher_ix],
+        &[&buyer],
+    )
+    .await;
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(1, InstructionError::Custom(31))
+    );
 
-    // Получение аккаунтов и проверка успешной инициализации магазина
-    let store_account_info = next_account_info(&mut banks_client);
-    let store_data = Account::unpack(&store_account_info.borrow()).unwrap();
+    // Котировку по минимальной цене тем же ключом тоже не подписать
+    let quote = Quote {
+        store: store_account.pubkey(),
+        pricer: multisig_account.pubkey(),
+        price: 1,
+        quantity: 10,
+        expiry: i64::MAX,
+        nonce: 1,
+    };
+    let mut buy_with_quote_ix = buy_ix(&multisig_fixture, &buyer.pubkey(), &buyer_token_account.pubkey(), 10, 10, 0);
+    buy_with_quote_ix.data.push(BUY_QUOTE);
+    buy_with_quote_ix.data.extend_from_slice(&quote.pack_terms());
+    buy_with_quote_ix.accounts.push(AccountMeta::new_readonly(solana_program::sysvar::instructions::id(), false));
+    buy_with_quote_ix.accounts.push(AccountMeta::new(
+        find_quote_nonce_address(&fixture.program_id, &store_account.pubkey(), 1).0,
+        false,
+    ));
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[ed25519_ix(&multisig_account, &quote.message()), buy_with_quote_ix],
+        &[&buyer],
+    )
+    .await;
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(1, InstructionError::Custom(31))
+    );
+}
 
-    // Проверка, что магазин был успешно инициализирован
-    assert_eq!(store_data.is_initialized(), true);
-    assert_eq!(store_data.mint, seller_token_account.pubkey());
-    assert_eq!(store_data.owner, owner_account.pubkey());
-    assert_eq!(store_data.amount, 1000);
-
-    // Подготовка токенов для покупки
-    let seller_token_mint = Keypair::new();
-    let seller_token_amount: u64 = 100;
-    let buyer_token_mint = Keypair::new();
-
-    let create_seller_token_mint_ix = spl_token::instruction::initialize_mint(
-        &token_program_id,
-        &seller_token_mint.pubkey(),
-        &store_account.pubkey(),
-        None,
-        0,
+fn role_ix(fixture: &StoreFixture, tag: u8, payer: &Pubkey, holder: &Pubkey, role: u8) -> Instruction {
+    let (roles_account, _) = find_roles_address(&fixture.program_id, &fixture.store_account.pubkey());
+    let mut data = vec![tag, role];
+    data.extend_from_slice(holder.as_ref());
+
+    let mut accounts = vec![
+        AccountMeta::new_readonly(fixture.store_account.pubkey(), false),
+        AccountMeta::new_readonly(fixture.owner_account.pubkey(), true),
+        AccountMeta::new(roles_account, false),
+    ];
+    if tag == 6 {
+        accounts.push(AccountMeta::new(*payer, true));
+        accounts.push(AccountMeta::new_readonly(system_program::id(), false));
+    }
+
+    Instruction {
+        program_id: fixture.program_id,
+        accounts,
+        data,
+    }
+}
+
+#[tokio::test]
+async fn test_pricer_role() {
+    // Инициализация тестового окружения и магазина
+    let (mut banks_client, payer, recent_blockhash, fixture) = setup_store().await;
+    let (roles_account, _) = find_roles_address(&fixture.program_id, &fixture.store_account.pubkey());
+
+    // Выдача роли ценовому боту
+    let pricing_bot = Keypair::new();
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[role_ix(&fixture, 6, &payer.pubkey(), &pricing_bot.pubkey(), ROLE_PRICER)],
+        &[&fixture.owner_account],
+    )
+    .await;
+    assert!(result.is_ok(), "Grant Role transaction failed: {:?}", result);
+
+    let account = banks_client.get_account(roles_account).await.unwrap().unwrap();
+    let roles = StoreRoles::unpack(&account.data).unwrap();
+    assert_eq!(roles.roles_of(&pricing_bot.pubkey()), ROLE_PRICER);
+
+    let update_price_ix = |price: u64| {
+        let mut data = vec![1];
+        data.extend_from_slice(&price.to_le_bytes());
+        Instruction {
+            program_id: fixture.program_id,
+            accounts: vec![
+                AccountMeta::new(fixture.store_account.pubkey(), false),
+                AccountMeta::new_readonly(pricing_bot.pubkey(), true),
+                AccountMeta::new_readonly(roles_account, false),
+            ],
+            data,
+        }
+    };
+
+    // Бот может менять цену
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[update_price_ix(1200)], &[&pricing_bot]).await;
+    assert!(result.is_ok(), "Update Price transaction failed: {:?}", result);
+    assert_eq!(get_store(&mut banks_client, &fixture.store_account.pubkey()).await.price, 1200);
+
+    // После отзыва роли изменение цены запрещено
+    process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[role_ix(&fixture, 7, &payer.pubkey(), &pricing_bot.pubkey(), ROLE_PRICER)],
+        &[&fixture.owner_account],
     )
+    .await
     .unwrap();
 
-    let create_buyer_token_mint_ix = spl_token::instruction::initialize_mint(
-        &token_program_id,
-        &buyer_token_mint.pubkey(),
-        &store_account.pubkey(),
-        None,
-        0,
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[update_price_ix(1300)], &[&pricing_bot]).await;
+    assert_custom_error(result, 6);
+}
+
+#[tokio::test]
+async fn test_ownership_transfer() {
+    // Инициализация тестового окружения и магазина
+    let (mut banks_client, payer, recent_blockhash, fixture) = setup_store().await;
+    let new_owner = Keypair::new();
+
+    // Предложение нового владельца
+    let mut data = vec![8];
+    data.extend_from_slice(new_owner.pubkey().as_ref());
+    let propose_ix = Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new(fixture.store_account.pubkey(), false),
+            AccountMeta::new_readonly(fixture.owner_account.pubkey(), true),
+        ],
+        data,
+    };
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[propose_ix], &[&fixture.owner_account]).await;
+    assert!(result.is_ok(), "Propose Owner transaction failed: {:?}", result);
+
+    let store_data = get_store(&mut banks_client, &fixture.store_account.pubkey()).await;
+    assert_eq!(store_data.owner, fixture.owner_account.pubkey());
+    assert_eq!(store_data.pending_owner, new_owner.pubkey());
+
+    // Роль, выданная прежним владельцем
+    let pricing_bot = Keypair::new();
+    process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[role_ix(&fixture, 6, &payer.pubkey(), &pricing_bot.pubkey(), ROLE_PRICER)],
+        &[&fixture.owner_account],
     )
+    .await
     .unwrap();
 
-    let seller_create_token_account_ix = spl_token::instruction::initialize_account(
-        &token_program_id,
-        &seller_token_account.pubkey(),
-        &seller_token_mint.pubkey(),
-        &store_account.pubkey(),
+    let (roles_account, _) = find_roles_address(&fixture.program_id, &fixture.store_account.pubkey());
+    let accept_ix = |signer: &Pubkey| Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new(fixture.store_account.pubkey(), false),
+            AccountMeta::new_readonly(*signer, true),
+            AccountMeta::new(roles_account, false),
+        ],
+        data: vec![9],
+    };
+
+    // Посторонний ключ не может принять владение
+    let stranger = Keypair::new();
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[accept_ix(&stranger.pubkey())], &[&stranger]).await;
+    assert_custom_error(result, 1);
+
+    // Новый владелец принимает магазин
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[accept_ix(&new_owner.pubkey())], &[&new_owner]).await;
+    assert!(result.is_ok(), "Accept Ownership transaction failed: {:?}", result);
+
+    let store_data = get_store(&mut banks_client, &fixture.store_account.pubkey()).await;
+    assert_eq!(store_data.owner, new_owner.pubkey());
+    assert_eq!(store_data.pending_owner, Pubkey::default());
+
+    // Роли прежнего владельца отозваны
+    let account = banks_client.get_account(roles_account).await.unwrap().unwrap();
+    assert_eq!(StoreRoles::unpack(&account.data).unwrap().roles_of(&pricing_bot.pubkey()), 0);
+}
+
+#[tokio::test]
+async fn test_pause_store() {
+    // Инициализация тестового окружения и магазина
+    let (mut banks_client, payer, recent_blockhash, fixture) = setup_store().await;
+    let (buyer, buyer_token_account) = setup_buyer(&mut banks_client, &payer, recent_blockhash, &fixture).await;
+    process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[deposit_ix(&fixture, 100)],
+        &[&fixture.owner_account],
     )
+    .await
     .unwrap();
 
-    let buyer_create_token_account_ix = spl_token::instruction::initialize_account(
-        &token_program_id,
-        &buyer_token_account.pubkey(),
-        &buyer_token_mint.pubkey(),
-        &store_account.pubkey(),
+    let pause_ix = |tag: u8| Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new(fixture.store_account.pubkey(), false),
+            AccountMeta::new_readonly(fixture.owner_account.pubkey(), true),
+            AccountMeta::new(find_registry_page_address(&fixture.program_id, 0).0, false),
+        ],
+        data: vec![tag],
+    };
+
+    // Приостановленный магазин не принимает покупки
+    process(&mut banks_client, &payer, recent_blockhash, &[pause_ix(11)], &[&fixture.owner_account]).await.unwrap();
+    assert!(get_store(&mut banks_client, &fixture.store_account.pubkey()).await.paused);
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[buy_ix(&fixture, &buyer.pubkey(), &buyer_token_account.pubkey(), 1, 1_000, 0)],
+        &[&buyer],
+    )
+    .await;
+    assert_custom_error(result, 9);
+
+    // Глобальная остановка торговли действует даже на активный магазин
+    process(&mut banks_client, &payer, recent_blockhash, &[pause_ix(12)], &[&fixture.owner_account]).await.unwrap();
+    let global_pause_ix = Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new(find_config_address(&fixture.program_id).0, false),
+            AccountMeta::new_readonly(payer.pubkey(), true),
+        ],
+        data: vec![14, 1],
+    };
+    process(&mut banks_client, &payer, recent_blockhash, &[global_pause_ix], &[]).await.unwrap();
+
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[buy_ix(&fixture, &buyer.pubkey(), &buyer_token_account.pubkey(), 2, 2_000, 0)],
+        &[&buyer],
     )
+    .await;
+    assert_custom_error(result, 10);
+}
+
+#[tokio::test]
+async fn test_close_store() {
+    // Инициализация тестового окружения и магазина
+    let (mut banks_client, payer, recent_blockhash, fixture) = setup_store().await;
+    let (vault_authority, _) = find_vault_authority(&fixture.program_id, &fixture.store_account.pubkey());
+    let destination = Pubkey::new_unique();
+
+    process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[deposit_ix(&fixture, 100)],
+        &[&fixture.owner_account],
+    )
+    .await
     .unwrap();
 
-    let mut data = vec![];
-    data.extend_from_slice(&[0u8; 32]); // Заглушка для данных о токенах
+    // Метаданные магазина закрываются вместе с ним
+    let (metadata_account, _) = find_metadata_address(&fixture.program_id, &fixture.store_account.pubkey());
+    let metadata = StoreMetadata {
+        is_initialized: true,
+        store: fixture.store_account.pubkey(),
+        name: "Book store".to_string(),
+        description: "Short".to_string(),
+        uri: "https://example.com/store.png".to_string(),
+        category: "books".to_string(),
+    };
+    let mut data = vec![23];
+    data.extend_from_slice(&metadata.pack_fields());
+    let set_metadata_ix = Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(fixture.store_account.pubkey(), false),
+            AccountMeta::new_readonly(fixture.owner_account.pubkey(), true),
+            AccountMeta::new(metadata_account, false),
+            AccountMeta::new(fixture.owner_account.pubkey(), true),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+        data,
+    };
+    process(&mut banks_client, &payer, recent_blockhash, &[set_metadata_ix], &[&fixture.owner_account])
+        .await
+        .unwrap();
+    let metadata_rent = banks_client.get_balance(metadata_account).await.unwrap();
+
+    let close_ix = |return_inventory: u8| Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new(fixture.store_account.pubkey(), false),
+            AccountMeta::new_readonly(fixture.owner_account.pubkey(), true),
+            AccountMeta::new(fixture.vault.pubkey(), false),
+            AccountMeta::new(fixture.mint.pubkey(), false),
+            AccountMeta::new_readonly(vault_authority, false),
+            AccountMeta::new(find_proceeds_address(&fixture.program_id, &fixture.store_account.pubkey()).0, false),
+            AccountMeta::new(fixture.owner_token_account.pubkey(), false),
+            AccountMeta::new(destination, false),
+            AccountMeta::new_readonly(fixture.token_program, false),
+            AccountMeta::new(find_roles_address(&fixture.program_id, &fixture.store_account.pubkey()).0, false),
+            AccountMeta::new(find_metadata_address(&fixture.program_id, &fixture.store_account.pubkey()).0, false),
+            AccountMeta::new(find_listing_book_address(&fixture.program_id, &fixture.store_account.pubkey()).0, false),
+            AccountMeta::new(find_registry_page_address(&fixture.program_id, 0).0, false),
+        ],
+        data: vec![15, return_inventory],
+    };
 
-    let create_seller_token_ix = create_account(
-        &owner_account.pubkey(),
-        &seller_token_account.pubkey(),
+    // Закрытие магазина с непустым хранилищем без возврата запрещено
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[close_ix(0)], &[&fixture.owner_account]).await;
+    assert_custom_error(result, 11);
+
+    // Закрытие с возвратом остатков владельцу
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[close_ix(1)], &[&fixture.owner_account]).await;
+    assert!(result.is_ok(), "Close Store transaction failed: {:?}", result);
+
+    assert!(banks_client.get_account(fixture.store_account.pubkey()).await.unwrap().is_none());
+    assert!(banks_client.get_account(fixture.vault.pubkey()).await.unwrap().is_none());
+    assert!(banks_client.get_account(metadata_account).await.unwrap().is_none());
+    assert_eq!(get_token_balance(&mut banks_client, &fixture.owner_token_account.pubkey()).await, 1_000);
+    let page_account = banks_client
+        .get_account(find_registry_page_address(&fixture.program_id, 0).0)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(RegistryPage::entries(&page_account.data).unwrap()[0].status, STORE_STATUS_CLOSED);
+    let rent = Rent::default();
+    assert_eq!(
+        banks_client.get_balance(destination).await.unwrap(),
+        rent.minimum_balance(Store::LEN) + rent.minimum_balance(0) + rent.minimum_balance(Account::LEN) + metadata_rent
+    );
+}
+
+#[tokio::test]
+async fn test_withdraw_proceeds() {
+    // Инициализация тестового окружения и магазина
+    let (mut banks_client, payer, recent_blockhash, fixture) = setup_store().await;
+    let (buyer, buyer_token_account) = setup_buyer(&mut banks_client, &payer, recent_blockhash, &fixture).await;
+    let (proceeds_account, _) = find_proceeds_address(&fixture.program_id, &fixture.store_account.pubkey());
+    let destination = Keypair::new().pubkey();
+    process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[transfer(&payer.pubkey(), &destination, 1_000_000_000)],
+        &[],
+    )
+    .await
+    .unwrap();
+
+    // Покупка на 50 000 лампортов
+    process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[
+            deposit_ix(&fixture, 100),
+            buy_ix(&fixture, &buyer.pubkey(), &buyer_token_account.pubkey(), 50, 50_000, 0),
+        ],
+        &[&fixture.owner_account, &buyer],
+    )
+    .await
+    .unwrap();
+
+    let withdraw_ix = |amount: u64| {
+        let mut data = vec![16, 0];
+        data.extend_from_slice(&amount.to_le_bytes());
+        Instruction {
+            program_id: fixture.program_id,
+            accounts: vec![
+                AccountMeta::new(fixture.store_account.pubkey(), false),
+                AccountMeta::new_readonly(fixture.owner_account.pubkey(), true),
+                AccountMeta::new(proceeds_account, false),
+                AccountMeta::new(destination, false),
+                AccountMeta::new_readonly(spl_token::id(), false),
+            ],
+            data,
+        }
+    };
+
+    // Частичный вывод выручки
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[withdraw_ix(20_000)], &[&fixture.owner_account]).await;
+    assert!(result.is_ok(), "Withdraw transaction failed: {:?}", result);
+    assert_eq!(banks_client.get_balance(destination).await.unwrap(), 1_000_020_000);
+    assert_eq!(get_store(&mut banks_client, &fixture.store_account.pubkey()).await.withdrawn, 20_000);
+
+    // Нельзя вывести больше, чем осталось сверх ренты
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[withdraw_ix(30_001)], &[&fixture.owner_account]).await;
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::InsufficientFunds)
+    );
+}
+
+#[tokio::test]
+async fn test_refund() {
+    // Инициализация тестового окружения и магазина, комиссия 5%, две покупки
+    // по 10 токенов
+    let (mut banks_client, payer, recent_blockhash, fixture) = setup_store().await;
+    let (buyer, buyer_token_account) = setup_buyer(&mut banks_client, &payer, recent_blockhash, &fixture).await;
+    let mut data = vec![17];
+    data.extend_from_slice(payer.pubkey().as_ref());
+    data.extend_from_slice(&1000u64.to_le_bytes());
+    data.extend_from_slice(&500u16.to_le_bytes());
+    data.extend_from_slice(payer.pubkey().as_ref());
+    data.extend_from_slice(&0u64.to_le_bytes());
+    data.push(0);
+    data.push(0);
+    let update_config_ix = Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new(find_config_address(&fixture.program_id).0, false),
+            AccountMeta::new_readonly(payer.pubkey(), true),
+        ],
+        data,
+    };
+    process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[
+            update_config_ix,
+            deposit_ix(&fixture, 100),
+            buy_ix(&fixture, &buyer.pubkey(), &buyer_token_account.pubkey(), 10, 10_000, 0),
+            buy_ix(&fixture, &buyer.pubkey(), &buyer_token_account.pubkey(), 10, 10_000, 1),
+        ],
+        &[&fixture.owner_account, &buyer],
+    )
+    .await
+    .unwrap();
+
+    // Токены всегда возвращает покупатель, при возврате без окна его
+    // одобряет владелец или выводящий выручку
+    let refund_ix = |receipt_number: u64, authority: &Pubkey| Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new(fixture.store_account.pubkey(), false),
+            AccountMeta::new(find_receipt_address(&fixture.program_id, &fixture.store_account.pubkey(), receipt_number).0, false),
+            AccountMeta::new_readonly(*authority, true),
+            AccountMeta::new(buyer.pubkey(), true),
+            AccountMeta::new(buyer_token_account.pubkey(), false),
+            AccountMeta::new(fixture.vault.pubkey(), false),
+            AccountMeta::new_readonly(fixture.mint.pubkey(), false),
+            AccountMeta::new_readonly(fixture.token_program, false),
+            AccountMeta::new(find_proceeds_address(&fixture.program_id, &fixture.store_account.pubkey()).0, false),
+            AccountMeta::new(buyer.pubkey(), false),
+        ],
+        data: vec![25],
+    };
+    let buyer_refund_ix = |receipt_number: u64| refund_ix(receipt_number, &buyer.pubkey());
+
+    // Без окна возврата покупатель не может вернуть покупку
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[buyer_refund_ix(1)], &[&buyer]).await;
+    assert_custom_error(result, 23);
+
+    let mut data = vec![24];
+    data.extend_from_slice(&1_000u64.to_le_bytes());
+    let set_refund_window_ix = Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new(fixture.store_account.pubkey(), false),
+            AccountMeta::new_readonly(fixture.owner_account.pubkey(), true),
+        ],
+        data,
+    };
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[set_refund_window_ix], &[&fixture.owner_account]).await;
+    assert!(result.is_ok(), "Set Refund Window transaction failed: {:?}", result);
+    assert_eq!(get_store(&mut banks_client, &fixture.store_account.pubkey()).await.refund_window, 1_000);
+
+    // Покупатель возвращает токены и получает оплату без комиссии протокола
+    let buyer_balance = banks_client.get_balance(buyer.pubkey()).await.unwrap();
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[buyer_refund_ix(0)], &[&buyer]).await;
+    assert!(result.is_ok(), "Refund transaction failed: {:?}", result);
+    assert_eq!(get_token_balance(&mut banks_client, &buyer_token_account.pubkey()).await, 10);
+    assert_eq!(banks_client.get_balance(buyer.pubkey()).await.unwrap(), buyer_balance + 9_500);
+    let store_data = get_store(&mut banks_client, &fixture.store_account.pubkey()).await;
+    assert_eq!(store_data.supply, 90);
+    assert_eq!(store_data.sold, 10);
+
+    let (receipt_account, _) = find_receipt_address(&fixture.program_id, &fixture.store_account.pubkey(), 0);
+    let account = banks_client.get_account(receipt_account).await.unwrap().unwrap();
+    let receipt = Receipt::unpack(&account.data).unwrap();
+    assert!(receipt.refunded);
+    assert_eq!(receipt.price_paid, 10_000);
+    assert_eq!(receipt.fee, 500);
+
+    // Повторный возврат по той же квитанции запрещен (транзакция от имени
+    // покупателя, чтобы не совпасть с предыдущей)
+    let result = process(&mut banks_client, &buyer, recent_blockhash, &[buyer_refund_ix(0)], &[]).await;
+    assert_custom_error(result, 22);
+
+    // Владелец не может вернуть покупку без подписи покупателя
+    let mut owner_refund_ix = refund_ix(1, &fixture.owner_account.pubkey());
+    owner_refund_ix.accounts[3].is_signer = false;
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[owner_refund_ix], &[&fixture.owner_account]).await;
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::MissingRequiredSignature)
+    );
+
+    // С одобрения владельца покупатель возвращает вторую покупку вне окна
+    let owner_refund_ix = refund_ix(1, &fixture.owner_account.pubkey());
+    let buyer_balance = banks_client.get_balance(buyer.pubkey()).await.unwrap();
+    let result = process(&mut banks_client, &buyer, recent_blockhash, &[owner_refund_ix], &[&fixture.owner_account]).await;
+    assert!(result.is_ok(), "Refund transaction failed: {:?}", result);
+    assert_eq!(get_token_balance(&mut banks_client, &buyer_token_account.pubkey()).await, 0);
+    // Покупатель платит и сетевую комиссию за две подписи
+    assert_eq!(banks_client.get_balance(buyer.pubkey()).await.unwrap(), buyer_balance - 10_000 + 9_500);
+    let store_data = get_store(&mut banks_client, &fixture.store_account.pubkey()).await;
+    assert_eq!(store_data.supply, 100);
+    assert_eq!(store_data.sold, 0);
+}
+
+#[tokio::test]
+async fn test_escrowed_delivery() {
+    // Инициализация тестового окружения и магазина
+    let (mut banks_client, payer, recent_blockhash, fixture) = setup_store().await;
+    let (buyer, buyer_token_account) = setup_buyer(&mut banks_client, &payer, recent_blockhash, &fixture).await;
+    let (proceeds_account, _) = find_proceeds_address(&fixture.program_id, &fixture.store_account.pubkey());
+    let arbiter = Keypair::new();
+
+    // Оплата покупок удерживается сутки, споры решает арбитр
+    let mut data = vec![26];
+    data.extend_from_slice(&86_400u64.to_le_bytes());
+    data.extend_from_slice(arbiter.pubkey().as_ref());
+    let set_escrow_ix = Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new(fixture.store_account.pubkey(), false),
+            AccountMeta::new_readonly(fixture.owner_account.pubkey(), true),
+        ],
+        data,
+    };
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[deposit_ix(&fixture, 100), set_escrow_ix],
+        &[&fixture.owner_account],
+    )
+    .await;
+    assert!(result.is_ok(), "Set Escrow Settings transaction failed: {:?}", result);
+
+    let escrow_address =
+        |receipt_number: u64| find_escrow_address(&fixture.program_id, &fixture.store_account.pubkey(), receipt_number).0;
+    let buy_escrowed_ix = |receipt_number: u64| {
+        let mut instruction = buy_ix(&fixture, &buyer.pubkey(), &buyer_token_account.pubkey(), 10, 10_000, receipt_number);
+        instruction.accounts.push(AccountMeta::new(escrow_address(receipt_number), false));
+        instruction
+    };
+    let escrow_ix = |tag: u8, receipt_number: u64, authority: &Pubkey, extra: Vec<AccountMeta>, data: &[u8]| {
+        let mut accounts = vec![
+            AccountMeta::new(fixture.store_account.pubkey(), false),
+            AccountMeta::new(escrow_address(receipt_number), false),
+            AccountMeta::new(*authority, true),
+        ];
+        accounts.extend(extra);
+        let mut ix_data = vec![tag];
+        ix_data.extend_from_slice(data);
+        Instruction {
+            program_id: fixture.program_id,
+            accounts,
+            data: ix_data,
+        }
+    };
+    let get_escrow = |account: Option<solana_sdk::account::Account>| Escrow::unpack(&account.unwrap().data).unwrap();
+
+    // Оплата поступает в эскроу, а не в выручку
+    let proceeds_balance = banks_client.get_balance(proceeds_account).await.unwrap();
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[buy_escrowed_ix(0)], &[&buyer]).await;
+    assert!(result.is_ok(), "Buy transaction failed: {:?}", result);
+    assert_eq!(get_token_balance(&mut banks_client, &buyer_token_account.pubkey()).await, 10);
+    assert_eq!(banks_client.get_balance(proceeds_account).await.unwrap(), proceeds_balance);
+    let escrow = get_escrow(banks_client.get_account(escrow_address(0)).await.unwrap());
+    assert_eq!(escrow.amount, 10_000);
+    assert_eq!(escrow.status, ESCROW_PENDING);
+    assert_eq!(get_store(&mut banks_client, &fixture.store_account.pubkey()).await.open_escrows, 1);
+
+    // До истечения срока продавец не может забрать оплату
+    let claim_ix = escrow_ix(
+        28,
+        0,
+        &fixture.owner_account.pubkey(),
+        vec![AccountMeta::new(proceeds_account, false)],
+        &[],
+    );
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[claim_ix], &[&fixture.owner_account]).await;
+    assert_custom_error(result, 24);
+
+    // Подтверждение доставки переводит оплату в выручку
+    let confirm_ix = escrow_ix(27, 0, &buyer.pubkey(), vec![AccountMeta::new(proceeds_account, false)], &[]);
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[confirm_ix], &[&buyer]).await;
+    assert!(result.is_ok(), "Confirm Delivery transaction failed: {:?}", result);
+    assert_eq!(banks_client.get_balance(proceeds_account).await.unwrap(), proceeds_balance + 10_000);
+    let escrow = get_escrow(banks_client.get_account(escrow_address(0)).await.unwrap());
+    assert_eq!(escrow.status, ESCROW_RELEASED);
+    assert_eq!(get_store(&mut banks_client, &fixture.store_account.pubkey()).await.open_escrows, 0);
+
+    // Вторая покупка оспаривается, продавец больше не может ее забрать
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[buy_escrowed_ix(1)], &[&buyer]).await;
+    assert!(result.is_ok(), "Buy transaction failed: {:?}", result);
+    let dispute_ix = escrow_ix(29, 1, &buyer.pubkey(), vec![], &[]);
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[dispute_ix], &[&buyer]).await;
+    assert!(result.is_ok(), "Dispute Escrow transaction failed: {:?}", result);
+    let claim_ix = escrow_ix(
+        28,
+        1,
+        &fixture.owner_account.pubkey(),
+        vec![AccountMeta::new(proceeds_account, false)],
+        &[],
+    );
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[claim_ix], &[&fixture.owner_account]).await;
+    assert_custom_error(result, 27);
+
+    // Метаданные магазина того же размера, что и эскроу, подобраны так, чтобы
+    // читаться как ожидающий эскроу на 0 лампортов. Выдать их за эскроу нельзя.
+    let (metadata_account, _) = find_metadata_address(&fixture.program_id, &fixture.store_account.pubkey());
+    let forged = StoreMetadata {
+        is_initialized: true,
+        store: fixture.store_account.pubkey(),
+        name: "a".repeat(30),
+        description: String::new(),
+        uri: String::new(),
+        category: "\0".repeat(19),
+    };
+    assert_eq!(forged.account_len(), Escrow::LEN);
+    assert_eq!(Escrow::unpack(&forged.pack()).unwrap().status, ESCROW_PENDING);
+    let mut data = vec![23];
+    data.extend_from_slice(&forged.pack_fields());
+    let set_metadata_ix = Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(fixture.store_account.pubkey(), false),
+            AccountMeta::new_readonly(fixture.owner_account.pubkey(), true),
+            AccountMeta::new(metadata_account, false),
+            AccountMeta::new(fixture.owner_account.pubkey(), true),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+        data,
+    };
+    let mut forged_claim_ix = escrow_ix(
+        28,
         1,
-        10,
-        &owner_account.pubkey());
-
-    let create_buyer_token_ix = create_account(&owner_account.pubkey(),
-                                               &buyer_token_account.pubkey(),
-                                               1,
-                                               10,
-                                               &owner_account.pubkey());
-
-    // Отправка токенов на аккаунты продавца и покупателя
-    let transfer_to_seller_ix = spl_token::instruction::transfer(
-        &token_program_id,
-        &owner_account.pubkey(),
+        &fixture.owner_account.pubkey(),
+        vec![AccountMeta::new(proceeds_account, false)],
+        &[],
+    );
+    forged_claim_ix.accounts[1] = AccountMeta::new(metadata_account, false);
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[set_metadata_ix, forged_claim_ix],
+        &[&fixture.owner_account],
+    )
+    .await;
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(1, InstructionError::InvalidArgument)
+    );
+
+    // Эскроу-покупки не возвращаются через refund
+    let refund_ix = Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new(fixture.store_account.pubkey(), false),
+            AccountMeta::new(find_receipt_address(&fixture.program_id, &fixture.store_account.pubkey(), 1).0, false),
+            AccountMeta::new_readonly(fixture.owner_account.pubkey(), true),
+            AccountMeta::new(buyer.pubkey(), true),
+            AccountMeta::new(buyer_token_account.pubkey(), false),
+            AccountMeta::new(fixture.vault.pubkey(), false),
+            AccountMeta::new_readonly(fixture.mint.pubkey(), false),
+            AccountMeta::new_readonly(fixture.token_program, false),
+            AccountMeta::new(proceeds_account, false),
+            AccountMeta::new(buyer.pubkey(), false),
+        ],
+        data: vec![25],
+    };
+    let result =
+        process(&mut banks_client, &payer, recent_blockhash, &[refund_ix], &[&fixture.owner_account, &buyer]).await;
+    assert_custom_error(result, 26);
+
+    // Магазин с неурегулированной оплатой закрыть нельзя
+    let close_ix = Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new(fixture.store_account.pubkey(), false),
+            AccountMeta::new_readonly(fixture.owner_account.pubkey(), true),
+            AccountMeta::new(fixture.vault.pubkey(), false),
+            AccountMeta::new(fixture.mint.pubkey(), false),
+            AccountMeta::new_readonly(find_vault_authority(&fixture.program_id, &fixture.store_account.pubkey()).0, false),
+            AccountMeta::new(proceeds_account, false),
+            AccountMeta::new(fixture.owner_token_account.pubkey(), false),
+            AccountMeta::new(fixture.owner_account.pubkey(), false),
+            AccountMeta::new_readonly(fixture.token_program, false),
+            AccountMeta::new(find_roles_address(&fixture.program_id, &fixture.store_account.pubkey()).0, false),
+            AccountMeta::new(find_metadata_address(&fixture.program_id, &fixture.store_account.pubkey()).0, false),
+            AccountMeta::new(find_listing_book_address(&fixture.program_id, &fixture.store_account.pubkey()).0, false),
+            AccountMeta::new(find_registry_page_address(&fixture.program_id, 0).0, false),
+        ],
+        data: vec![15, 1],
+    };
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[close_ix], &[&fixture.owner_account]).await;
+    assert_custom_error(result, 30);
+
+    // Арбитр возвращает оплату покупателю, а покупатель возвращает токены
+    let resolve_ix = |buyer_signs: bool| {
+        escrow_ix(
+            30,
+            1,
+            &arbiter.pubkey(),
+            vec![
+                AccountMeta::new(proceeds_account, false),
+                AccountMeta::new(buyer.pubkey(), buyer_signs),
+                AccountMeta::new(find_receipt_address(&fixture.program_id, &fixture.store_account.pubkey(), 1).0, false),
+                AccountMeta::new(buyer_token_account.pubkey(), false),
+                AccountMeta::new(fixture.vault.pubkey(), false),
+                AccountMeta::new_readonly(fixture.mint.pubkey(), false),
+                AccountMeta::new_readonly(fixture.token_program, false),
+            ],
+            &[1],
+        )
+    };
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[resolve_ix(false)], &[&arbiter]).await;
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::MissingRequiredSignature)
+    );
+
+    let buyer_balance = banks_client.get_balance(buyer.pubkey()).await.unwrap();
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[resolve_ix(true)], &[&arbiter, &buyer]).await;
+    assert!(result.is_ok(), "Resolve Dispute transaction failed: {:?}", result);
+    assert_eq!(banks_client.get_balance(buyer.pubkey()).await.unwrap(), buyer_balance + 10_000);
+    assert_eq!(get_token_balance(&mut banks_client, &buyer_token_account.pubkey()).await, 10);
+    let escrow = get_escrow(banks_client.get_account(escrow_address(1)).await.unwrap());
+    assert_eq!(escrow.status, ESCROW_REFUNDED);
+    let store_data = get_store(&mut banks_client, &fixture.store_account.pubkey()).await;
+    assert_eq!(store_data.open_escrows, 0);
+    assert_eq!(store_data.sold, 10);
+    assert_eq!(store_data.supply, 90);
+    let receipt_account = banks_client
+        .get_account(find_receipt_address(&fixture.program_id, &fixture.store_account.pubkey(), 1).0)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(Receipt::unpack(&receipt_account.data).unwrap().refunded);
+}
+
+#[tokio::test]
+async fn test_consignment_store() {
+    // Инициализация тестового окружения и магазина, два продавца с токенами
+    let (mut banks_client, payer, recent_blockhash, fixture) = setup_store().await;
+    let (buyer, buyer_token_account) = setup_buyer(&mut banks_client, &payer, recent_blockhash, &fixture).await;
+    let (seller_a, seller_a_token_account) = setup_buyer(&mut banks_client, &payer, recent_blockhash, &fixture).await;
+    let (seller_b, seller_b_token_account) = setup_buyer(&mut banks_client, &payer, recent_blockhash, &fixture).await;
+    let fund_sellers_ixs = [&seller_a_token_account, &seller_b_token_account].map(|token_account| {
+        spl_token::instruction::transfer(
+            &spl_token::id(),
+            &fixture.owner_token_account.pubkey(),
+            &token_account.pubkey(),
+            &fixture.owner_account.pubkey(),
+            &[],
+            20,
+        )
+        .unwrap()
+    });
+    process(&mut banks_client, &payer, recent_blockhash, &fund_sellers_ixs, &[&fixture.owner_account]).await.unwrap();
+
+    // Включение режима консигнации создает книгу заявок, в том числе по
+    // адресу, пополненному заранее
+    let (book_account, _) = find_listing_book_address(&fixture.program_id, &fixture.store_account.pubkey());
+    process(&mut banks_client, &payer, recent_blockhash, &[transfer(&payer.pubkey(), &book_account, 1_000_000)], &[])
+        .await
+        .unwrap();
+    let set_consignment_ix = Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new(fixture.store_account.pubkey(), false),
+            AccountMeta::new_readonly(fixture.owner_account.pubkey(), true),
+            AccountMeta::new(book_account, false),
+            AccountMeta::new(payer.pubkey(), true),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+        data: vec![31, 1],
+    };
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[set_consignment_ix], &[&fixture.owner_account]).await;
+    assert!(result.is_ok(), "Set Consignment transaction failed: {:?}", result);
+    assert!(get_store(&mut banks_client, &fixture.store_account.pubkey()).await.consignment);
+
+    let ledger_address =
+        |seller: &Keypair| find_seller_ledger_address(&fixture.program_id, &fixture.store_account.pubkey(), &seller.pubkey()).0;
+    let list_ix = |seller: &Keypair, seller_token_account: &Keypair, price: u64, quantity: u64| {
+        let mut data = vec![32];
+        data.extend_from_slice(&price.to_le_bytes());
+        data.extend_from_slice(&quantity.to_le_bytes());
+        Instruction {
+            program_id: fixture.program_id,
+            accounts: vec![
+                AccountMeta::new(fixture.store_account.pubkey(), false),
+                AccountMeta::new(book_account, false),
+                AccountMeta::new(seller.pubkey(), true),
+                AccountMeta::new(seller_token_account.pubkey(), false),
+                AccountMeta::new(fixture.vault.pubkey(), false),
+                AccountMeta::new_readonly(fixture.mint.pubkey(), false),
+                AccountMeta::new_readonly(fixture.token_program, false),
+                AccountMeta::new(ledger_address(seller), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+            data,
+        }
+    };
+
+    // Заявки сортируются по цене, при равной цене - в порядке подачи
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[
+            list_ix(&seller_a, &seller_a_token_account, 1_500, 10),
+            list_ix(&seller_b, &seller_b_token_account, 1_000, 10),
+            list_ix(&seller_a, &seller_a_token_account, 1_000, 5),
+        ],
+        &[&seller_a, &seller_b],
+    )
+    .await;
+    assert!(result.is_ok(), "List Tokens transaction failed: {:?}", result);
+    let account = banks_client.get_account(book_account).await.unwrap().unwrap();
+    let listings: Vec<_> = ListingBook::entries(&account.data)
+        .unwrap()
+        .iter()
+        .map(|listing| (listing.seller, listing.price, listing.quantity))
+        .collect();
+    assert_eq!(
+        listings,
+        vec![
+            (seller_b.pubkey(), 1_000, 10),
+            (seller_a.pubkey(), 1_000, 5),
+            (seller_a.pubkey(), 1_500, 10),
+        ]
+    );
+    assert_eq!(get_store(&mut banks_client, &fixture.store_account.pubkey()).await.supply, 25);
+
+    // Обычные пополнение и покупка в магазине консигнации недоступны
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[deposit_ix(&fixture, 10)], &[&fixture.owner_account]).await;
+    assert_custom_error(result, 28);
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[buy_ix(&fixture, &buyer.pubkey(), &buyer_token_account.pubkey(), 1, 1_000, 0)],
+        &[&buyer],
+    )
+    .await;
+    assert_custom_error(result, 28);
+
+    let (vault_authority, _) = find_vault_authority(&fixture.program_id, &fixture.store_account.pubkey());
+    let buy_listings_ix = |quantity: u64, max_cost: u64, receipt_number: u64, ledgers: &[Pubkey]| {
+        let mut data = vec![34];
+        data.extend_from_slice(&quantity.to_le_bytes());
+        data.extend_from_slice(&max_cost.to_le_bytes());
+        let mut accounts = vec![
+            AccountMeta::new(fixture.store_account.pubkey(), false),
+            AccountMeta::new_readonly(find_config_address(&fixture.program_id).0, false),
+            AccountMeta::new(book_account, false),
+            AccountMeta::new(fixture.vault.pubkey(), false),
+            AccountMeta::new_readonly(fixture.mint.pubkey(), false),
+            AccountMeta::new_readonly(vault_authority, false),
+            AccountMeta::new(buyer_token_account.pubkey(), false),
+            AccountMeta::new(buyer.pubkey(), true),
+            AccountMeta::new(fixture.fee_recipient, false),
+            AccountMeta::new_readonly(fixture.token_program, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new(find_receipt_address(&fixture.program_id, &fixture.store_account.pubkey(), receipt_number).0, false),
+        ];
+        accounts.extend(ledgers.iter().map(|ledger| AccountMeta::new(*ledger, false)));
+        Instruction {
+            program_id: fixture.program_id,
+            accounts,
+            data,
+        }
+    };
+
+    // Покупка исполняет самые дешевые заявки, оплата поступает в реестры продавцов
+    let ledger_balance = banks_client.get_balance(ledger_address(&seller_a)).await.unwrap();
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[buy_listings_ix(15, 15_000, 0, &[ledger_address(&seller_b), ledger_address(&seller_a)])],
+        &[&buyer],
+    )
+    .await;
+    assert!(result.is_ok(), "Buy Listings transaction failed: {:?}", result);
+    assert_eq!(get_token_balance(&mut banks_client, &buyer_token_account.pubkey()).await, 15);
+    assert_eq!(banks_client.get_balance(ledger_address(&seller_a)).await.unwrap(), ledger_balance + 5_000);
+    let account = banks_client.get_account(ledger_address(&seller_b)).await.unwrap().unwrap();
+    assert_eq!(SellerLedger::unpack(&account.data).unwrap().accrued, 10_000);
+    let account = banks_client.get_account(book_account).await.unwrap().unwrap();
+    assert_eq!(
+        ListingBook::entries(&account.data).unwrap(),
+        vec![Listing {
+            seller: seller_a.pubkey(),
+            price: 1_500,
+            quantity: 10,
+        }]
+    );
+
+    // Покупка учитывается в магазине и получает квитанцию
+    let store_data = get_store(&mut banks_client, &fixture.store_account.pubkey()).await;
+    assert_eq!(store_data.sold, 15);
+    assert_eq!(store_data.supply, 10);
+    assert_eq!(store_data.receipt_count, 1);
+    let (receipt_account, _) = find_receipt_address(&fixture.program_id, &fixture.store_account.pubkey(), 0);
+    let receipt = Receipt::unpack(&banks_client.get_account(receipt_account).await.unwrap().unwrap().data).unwrap();
+    assert_eq!(receipt.buyer, buyer.pubkey());
+    assert_eq!(receipt.quantity, 15);
+    assert_eq!(receipt.price_paid, 15_000);
+    assert!(receipt.consigned);
+
+    // Заявок не хватает на всю покупку
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[buy_listings_ix(11, 16_500, 1, &[ledger_address(&seller_a)])],
+        &[&buyer],
+    )
+    .await;
+    assert_custom_error(result, 5);
+
+    // Продавец выводит выручку из своего реестра
+    let mut data = vec![35];
+    data.extend_from_slice(&5_000u64.to_le_bytes());
+    let withdraw_ix = Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(fixture.store_account.pubkey(), false),
+            AccountMeta::new(ledger_address(&seller_a), false),
+            AccountMeta::new(seller_a.pubkey(), true),
+            AccountMeta::new(seller_a.pubkey(), false),
+        ],
+        data,
+    };
+    let seller_balance = banks_client.get_balance(seller_a.pubkey()).await.unwrap();
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[withdraw_ix], &[&seller_a]).await;
+    assert!(result.is_ok(), "Withdraw Seller Proceeds transaction failed: {:?}", result);
+    assert_eq!(banks_client.get_balance(seller_a.pubkey()).await.unwrap(), seller_balance + 5_000);
+
+    // Продавец снимает оставшуюся заявку и получает токены обратно
+    let delist_ix = Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new(fixture.store_account.pubkey(), false),
+            AccountMeta::new(book_account, false),
+            AccountMeta::new_readonly(seller_a.pubkey(), true),
+            AccountMeta::new(seller_a_token_account.pubkey(), false),
+            AccountMeta::new(fixture.vault.pubkey(), false),
+            AccountMeta::new_readonly(fixture.mint.pubkey(), false),
+            AccountMeta::new_readonly(vault_authority, false),
+            AccountMeta::new_readonly(fixture.token_program, false),
+        ],
+        data: vec![33, 0, 0],
+    };
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[delist_ix], &[&seller_a]).await;
+    assert!(result.is_ok(), "Delist Tokens transaction failed: {:?}", result);
+    assert_eq!(get_token_balance(&mut banks_client, &seller_a_token_account.pubkey()).await, 15);
+    assert_eq!(get_store(&mut banks_client, &fixture.store_account.pubkey()).await.supply, 0);
+}
+
+#[tokio::test]
+async fn test_consignment_transfer_fee() {
+    // Магазин консигнации с минтом Token-2022 и комиссией за перевод 1%
+    let (mut banks_client, payer, recent_blockhash, fixture) =
+        setup_store_with_mint(spl_token_2022::id(), Some(100)).await;
+    let (buyer, buyer_token_account) = setup_buyer(&mut banks_client, &payer, recent_blockhash, &fixture).await;
+    let (seller, seller_token_account) = setup_buyer(&mut banks_client, &payer, recent_blockhash, &fixture).await;
+    let fund_seller_ix = spl_token_2022::instruction::transfer_checked(
+        &spl_token_2022::id(),
+        &fixture.owner_token_account.pubkey(),
+        &fixture.mint.pubkey(),
         &seller_token_account.pubkey(),
-        &owner_account.pubkey(),
+        &fixture.owner_account.pubkey(),
         &[],
-        seller_token_amount,
+        200,
+        0,
     )
     .unwrap();
 
-    // Создание транзакции для покупки
-    let buy_amount: u64 = 100; // Количество покупаемых токенов
-    let mut data = vec![];
-    data.extend_from_slice(&buy_amount.to_le_bytes()); // Количество покупаемых токенов
+    let (book_account, _) = find_listing_book_address(&fixture.program_id, &fixture.store_account.pubkey());
+    let (ledger_account, _) =
+        find_seller_ledger_address(&fixture.program_id, &fixture.store_account.pubkey(), &seller.pubkey());
+    let set_consignment_ix = Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new(fixture.store_account.pubkey(), false),
+            AccountMeta::new_readonly(fixture.owner_account.pubkey(), true),
+            AccountMeta::new(book_account, false),
+            AccountMeta::new(payer.pubkey(), true),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+        data: vec![31, 1],
+    };
+    let list_ix = |price: u64, quantity: u64| {
+        let mut data = vec![32];
+        data.extend_from_slice(&price.to_le_bytes());
+        data.extend_from_slice(&quantity.to_le_bytes());
+        Instruction {
+            program_id: fixture.program_id,
+            accounts: vec![
+                AccountMeta::new(fixture.store_account.pubkey(), false),
+                AccountMeta::new(book_account, false),
+                AccountMeta::new(seller.pubkey(), true),
+                AccountMeta::new(seller_token_account.pubkey(), false),
+                AccountMeta::new(fixture.vault.pubkey(), false),
+                AccountMeta::new_readonly(fixture.mint.pubkey(), false),
+                AccountMeta::new_readonly(fixture.token_program, false),
+                AccountMeta::new(ledger_account, false),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+            data,
+        }
+    };
+
+    // В заявки попадает то, что дошло до хранилища: по 49 из 50
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[fund_seller_ix, set_consignment_ix, list_ix(1_000, 50), list_ix(2_000, 50)],
+        &[&fixture.owner_account, &seller],
+    )
+    .await;
+    assert!(result.is_ok(), "List Tokens transaction failed: {:?}", result);
+    assert_eq!(get_store(&mut banks_client, &fixture.store_account.pubkey()).await.supply, 98);
+
+    // Из хранилища уходит 60, доходит 59. Покупатель платит только за
+    // дошедшие токены, комиссию несут продавцы пропорционально исполнению.
+    let mut data = vec![34];
+    data.extend_from_slice(&60u64.to_le_bytes());
+    data.extend_from_slice(&69_000u64.to_le_bytes());
+    let buy_listings_ix = Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new(fixture.store_account.pubkey(), false),
+            AccountMeta::new_readonly(find_config_address(&fixture.program_id).0, false),
+            AccountMeta::new(book_account, false),
+            AccountMeta::new(fixture.vault.pubkey(), false),
+            AccountMeta::new_readonly(fixture.mint.pubkey(), false),
+            AccountMeta::new_readonly(find_vault_authority(&fixture.program_id, &fixture.store_account.pubkey()).0, false),
+            AccountMeta::new(buyer_token_account.pubkey(), false),
+            AccountMeta::new(buyer.pubkey(), true),
+            AccountMeta::new(fixture.fee_recipient, false),
+            AccountMeta::new_readonly(fixture.token_program, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new(find_receipt_address(&fixture.program_id, &fixture.store_account.pubkey(), 0).0, false),
+            AccountMeta::new(ledger_account, false),
+            AccountMeta::new(ledger_account, false),
+        ],
+        data,
+    };
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[buy_listings_ix], &[&buyer]).await;
+    assert!(result.is_ok(), "Buy Listings transaction failed: {:?}", result);
+
+    assert_eq!(get_token_balance(&mut banks_client, &buyer_token_account.pubkey()).await, 59);
+    let account = banks_client.get_account(ledger_account).await.unwrap().unwrap();
+    assert_eq!(SellerLedger::unpack(&account.data).unwrap().accrued, 49 * 1_000 + 10 * 2_000);
+    let (receipt_account, _) = find_receipt_address(&fixture.program_id, &fixture.store_account.pubkey(), 0);
+    let receipt = Receipt::unpack(&banks_client.get_account(receipt_account).await.unwrap().unwrap().data).unwrap();
+    assert_eq!(receipt.quantity, 59);
+    assert_eq!(receipt.sent, 60);
+    assert_eq!(receipt.price_paid, 69_000);
+    let store_data = get_store(&mut banks_client, &fixture.store_account.pubkey()).await;
+    assert_eq!(store_data.sold, 60);
+    assert_eq!(store_data.supply, 38);
+}
 
-     let buy_ix = Instruction {
-        program_id: id,
-        accounts: [store_account.pubkey(), seller_token_account.pubkey(), buyer_token_account.pubkey(), owner_account.pubkey()].iter().map(|ac| ac.as_ref().unwrap()).collect(),
+#[tokio::test]
+async fn test_wrapped_sol_store() {
+    // Инициализация тестового окружения и магазина
+    let (mut banks_client, payer, recent_blockhash, fixture) = setup_store().await;
+    let native_mint = spl_token::native_mint::id();
+
+    // Разрешение wSOL в качестве котируемого минта
+    let mut data = vec![17];
+    data.extend_from_slice(payer.pubkey().as_ref());
+    data.extend_from_slice(&1000u64.to_le_bytes());
+    data.extend_from_slice(&0u16.to_le_bytes());
+    data.extend_from_slice(payer.pubkey().as_ref());
+    data.extend_from_slice(&0u64.to_le_bytes());
+    data.push(0);
+    data.push(1);
+    data.extend_from_slice(native_mint.as_ref());
+    let update_config_ix = Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new(find_config_address(&fixture.program_id).0, false),
+            AccountMeta::new_readonly(payer.pubkey(), true),
+        ],
         data,
     };
-    // Выполнение и ожидание транзакции
-    let mut transaction = Transaction::new_with_payer(
-        &[buy_ix, transfer_to_seller_ix],
-        Some(&payer.pubkey()),
+    process(&mut banks_client, &payer, recent_blockhash, &[update_config_ix], &[]).await.unwrap();
+
+    // Магазин того же минта, выручка которого хранится в wSOL
+    let wsol_fixture = StoreFixture {
+        program_id: fixture.program_id,
+        store_account: Keypair::new(),
+        mint: Keypair::from_bytes(&fixture.mint.to_bytes()).unwrap(),
+        vault: Keypair::new(),
+        owner_account: Keypair::from_bytes(&fixture.owner_account.to_bytes()).unwrap(),
+        owner_token_account: Keypair::from_bytes(&fixture.owner_token_account.to_bytes()).unwrap(),
+        fee_recipient: payer.pubkey(),
+        token_program: spl_token::id(),
+    };
+    let store_key = wsol_fixture.store_account.pubkey();
+    let (vault_authority, _) = find_vault_authority(&fixture.program_id, &store_key);
+    let (proceeds_account, _) = find_proceeds_address(&fixture.program_id, &store_key);
+    let quote_vault = Keypair::new();
+    create_token_account(&mut banks_client, &payer, recent_blockhash, &wsol_fixture.vault, &fixture.mint.pubkey(), &vault_authority).await;
+    create_token_account(&mut banks_client, &payer, recent_blockhash, &quote_vault, &native_mint, &proceeds_account).await;
+
+    let create_store_ix = create_account(
+        &payer.pubkey(),
+        &store_key,
+        Rent::default().minimum_balance(Store::LEN),
+        Store::LEN as u64,
+        &fixture.program_id,
     );
-    transaction.sign(&[&payer, &store_account, &seller_token_account, &owner_account], recent_blockhash);
+    let mut data = vec![0];
+    data.extend_from_slice(native_mint.as_ref());
+    let init_store_ix = Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new(store_key, false),
+            AccountMeta::new(find_config_address(&fixture.program_id).0, false),
+            AccountMeta::new_readonly(fixture.mint.pubkey(), false),
+            AccountMeta::new_readonly(fixture.owner_account.pubkey(), true),
+            AccountMeta::new_readonly(wsol_fixture.vault.pubkey(), false),
+            AccountMeta::new(proceeds_account, false),
+            AccountMeta::new(payer.pubkey(), true),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new(find_registry_page_address(&fixture.program_id, 0).0, false),
+            AccountMeta::new_readonly(quote_vault.pubkey(), false),
+        ],
+        data,
+    };
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[create_store_ix, init_store_ix, deposit_ix(&wsol_fixture, 100)],
+        &[&wsol_fixture.store_account, &fixture.owner_account],
+    )
+    .await;
+    assert!(result.is_ok(), "Initialize Store transaction failed: {:?}", result);
 
-    let result = banks_client.process_transaction(transaction).await;
+    // Покупка за лампорты, которые оборачиваются во временный wSOL-аккаунт
+    let (buyer, buyer_token_account) = setup_buyer(&mut banks_client, &payer, recent_blockhash, &wsol_fixture).await;
+
+    // Оплата котируемым токеном только через программу токенов, а не лампортами
+    let mut lamports_ix = buy_ix(&wsol_fixture, &buyer.pubkey(), &buyer_token_account.pubkey(), 10, 10_000, 0);
+    lamports_ix.accounts[7] = AccountMeta::new(quote_vault.pubkey(), false);
+    lamports_ix.accounts.push(AccountMeta::new_readonly(system_program::id(), false));
+    lamports_ix.accounts.push(AccountMeta::new(buyer.pubkey(), true));
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[lamports_ix], &[&buyer]).await;
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::IncorrectProgramId)
+    );
+    let (buyer_wrap_account, _) = find_wrap_address(&fixture.program_id, &store_key, &buyer.pubkey());
+    let mut buy_wrapped_ix = buy_ix(&wsol_fixture, &buyer.pubkey(), &buyer_token_account.pubkey(), 10, 10_000, 0);
+    buy_wrapped_ix.data.push(BUY_WRAP_SOL);
+    buy_wrapped_ix.accounts[7] = AccountMeta::new(quote_vault.pubkey(), false);
+    buy_wrapped_ix.accounts.push(AccountMeta::new_readonly(spl_token::id(), false));
+    buy_wrapped_ix.accounts.push(AccountMeta::new(buyer_wrap_account, false));
+    buy_wrapped_ix.accounts.push(AccountMeta::new_readonly(native_mint, false));
+
+    let receipt_rent = Rent::default().minimum_balance(Receipt::LEN);
+    let buyer_balance = banks_client.get_balance(buyer.pubkey()).await.unwrap();
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[buy_wrapped_ix], &[&buyer]).await;
     assert!(result.is_ok(), "Buy transaction failed: {:?}", result);
 
-    // Получение аккаунтов и проверка выполнения покупки
-    let seller_account_info = next_account_info(&mut banks_client);
-    let buyer_account_info = next_account_info(&mut banks_client);
+    assert_eq!(get_token_balance(&mut banks_client, &buyer_token_account.pubkey()).await, 10);
+    assert_eq!(get_token_balance(&mut banks_client, &quote_vault.pubkey()).await, 10_000);
+    assert_eq!(banks_client.get_balance(buyer.pubkey()).await.unwrap(), buyer_balance - 10_000 - receipt_rent);
+    assert!(banks_client.get_account(buyer_wrap_account).await.unwrap().is_none());
+
+    // Вывод выручки с разворачиванием wSOL в лампорты
+    let destination = Keypair::new().pubkey();
+    process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[transfer(&payer.pubkey(), &destination, 1_000_000_000)],
+        &[],
+    )
+    .await
+    .unwrap();
+    let mut data = vec![16, 2];
+    data.extend_from_slice(&4_000u64.to_le_bytes());
+    let unwrap_ix = Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new(store_key, false),
+            AccountMeta::new_readonly(fixture.owner_account.pubkey(), true),
+            AccountMeta::new(proceeds_account, false),
+            AccountMeta::new(destination, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new(quote_vault.pubkey(), false),
+            AccountMeta::new(find_wrap_address(&fixture.program_id, &store_key, &payer.pubkey()).0, false),
+            AccountMeta::new(payer.pubkey(), true),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(native_mint, false),
+        ],
+        data,
+    };
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[unwrap_ix], &[&fixture.owner_account]).await;
+    assert!(result.is_ok(), "Withdraw transaction failed: {:?}", result);
+
+    assert_eq!(banks_client.get_balance(destination).await.unwrap(), 1_000_004_000);
+    assert_eq!(get_token_balance(&mut banks_client, &quote_vault.pubkey()).await, 6_000);
+    assert_eq!(get_store(&mut banks_client, &store_key).await.withdrawn, 4_000);
+
+    // При закрытии магазина остаток выручки уходит владельцу, а котируемое
+    // хранилище закрывается
+    let owner_quote_account = Keypair::new();
+    create_token_account(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &owner_quote_account,
+        &native_mint,
+        &fixture.owner_account.pubkey(),
+    )
+    .await;
+    let close_ix = Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new(store_key, false),
+            AccountMeta::new_readonly(fixture.owner_account.pubkey(), true),
+            AccountMeta::new(wsol_fixture.vault.pubkey(), false),
+            AccountMeta::new(fixture.mint.pubkey(), false),
+            AccountMeta::new_readonly(vault_authority, false),
+            AccountMeta::new(proceeds_account, false),
+            AccountMeta::new(fixture.owner_token_account.pubkey(), false),
+            AccountMeta::new(destination, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new(quote_vault.pubkey(), false),
+            AccountMeta::new(owner_quote_account.pubkey(), false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new(find_roles_address(&fixture.program_id, &store_key).0, false),
+            AccountMeta::new(find_metadata_address(&fixture.program_id, &store_key).0, false),
+            AccountMeta::new(find_listing_book_address(&fixture.program_id, &store_key).0, false),
+            AccountMeta::new(find_registry_page_address(&fixture.program_id, 0).0, false),
+        ],
+        data: vec![15, 1],
+    };
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[close_ix], &[&fixture.owner_account]).await;
+    assert!(result.is_ok(), "Close Store transaction failed: {:?}", result);
 
-    // Проверка, что продавец продал токены, а покупатель их получил
-    let seller_token_account_data = spl_token::state::Account::unpack(&seller_account_info.borrow()).unwrap();
-    let buyer_token_account_data = spl_token::state::Account::unpack(&buyer_account_info.borrow()).unwrap();
+    assert_eq!(get_token_balance(&mut banks_client, &owner_quote_account.pubkey()).await, 6_000);
+    assert!(banks_client.get_account(quote_vault.pubkey()).await.unwrap().is_none());
+    assert!(banks_client.get_account(store_key).await.unwrap().is_none());
+}
 
-    assert_eq!(seller_token_account_data.amount, seller_token_amount - buy_amount, "Seller did not sell tokens");
-    assert_eq!(buyer_token_account_data.amount, buy_amount, "Buyer did not receive tokens");
-}
\ No newline at end of file
+#[tokio::test]
+async fn test_update_config() {
+    // Инициализация тестового окружения и магазина
+    let (mut banks_client, payer, recent_blockhash, mut fixture) = setup_store().await;
+    let (buyer, buyer_token_account) = setup_buyer(&mut banks_client, &payer, recent_blockhash, &fixture).await;
+    let (proceeds_account, _) = find_proceeds_address(&fixture.program_id, &fixture.store_account.pubkey());
+
+    // Получатель комиссии с собственным балансом
+    let fee_recipient = Keypair::new().pubkey();
+    process(&mut banks_client, &payer, recent_blockhash, &[transfer(&payer.pubkey(), &fee_recipient, 1_000_000_000)], &[])
+        .await
+        .unwrap();
+    fixture.fee_recipient = fee_recipient;
+
+    // Комиссия 5% и лимит в 10 единиц на покупку
+    let mut data = vec![17];
+    data.extend_from_slice(payer.pubkey().as_ref());
+    data.extend_from_slice(&2000u64.to_le_bytes());
+    data.extend_from_slice(&500u16.to_le_bytes());
+    data.extend_from_slice(fee_recipient.as_ref());
+    data.extend_from_slice(&10u64.to_le_bytes());
+    data.push(0);
+    data.push(0);
+    let update_config_ix = Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new(find_config_address(&fixture.program_id).0, false),
+            AccountMeta::new_readonly(payer.pubkey(), true),
+        ],
+        data,
+    };
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[update_config_ix, deposit_ix(&fixture, 100)],
+        &[&fixture.owner_account],
+    )
+    .await;
+    assert!(result.is_ok(), "Update Config transaction failed: {:?}", result);
+
+    // Покупка сверх лимита отклоняется
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[buy_ix(&fixture, &buyer.pubkey(), &buyer_token_account.pubkey(), 11, 11_000, 0)],
+        &[&buyer],
+    )
+    .await;
+    assert_custom_error(result, 13);
+
+    // Комиссия удерживается из оплаты покупки
+    let proceeds_balance = banks_client.get_balance(proceeds_account).await.unwrap();
+    process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[buy_ix(&fixture, &buyer.pubkey(), &buyer_token_account.pubkey(), 10, 10_000, 0)],
+        &[&buyer],
+    )
+    .await
+    .unwrap();
+    assert_eq!(banks_client.get_balance(proceeds_account).await.unwrap(), proceeds_balance + 9_500);
+    assert_eq!(banks_client.get_balance(fee_recipient).await.unwrap(), 1_000_000_500);
+}
+
+#[tokio::test]
+async fn test_price_guardrails() {
+    // Инициализация тестового окружения и магазина
+    let (mut banks_client, payer, recent_blockhash, fixture) = setup_store().await;
+
+    // Цена от 500 до 5000, не более 20% за одно изменение
+    let mut data = vec![18];
+    data.extend_from_slice(&500u64.to_le_bytes());
+    data.extend_from_slice(&5000u64.to_le_bytes());
+    data.extend_from_slice(&2000u16.to_le_bytes());
+    data.extend_from_slice(&0u16.to_le_bytes());
+    data.extend_from_slice(&0u64.to_le_bytes());
+    let guardrails_ix = Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new(fixture.store_account.pubkey(), false),
+            AccountMeta::new_readonly(fixture.owner_account.pubkey(), true),
+        ],
+        data,
+    };
+    process(&mut banks_client, &payer, recent_blockhash, &[guardrails_ix], &[&fixture.owner_account]).await.unwrap();
+
+    let update_price_ix = |price: u64| {
+        let mut data = vec![1];
+        data.extend_from_slice(&price.to_le_bytes());
+        Instruction {
+            program_id: fixture.program_id,
+            accounts: vec![
+                AccountMeta::new(fixture.store_account.pubkey(), false),
+                AccountMeta::new_readonly(fixture.owner_account.pubkey(), true),
+            ],
+            data,
+        }
+    };
+
+    // Ошибочная цена в 1000 раз больше отклоняется
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[update_price_ix(1_000_000)], &[&fixture.owner_account]).await;
+    assert_custom_error(result, 15);
+
+    // Скачок на 50% в пределах диапазона также отклоняется
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[update_price_ix(1500)], &[&fixture.owner_account]).await;
+    assert_custom_error(result, 16);
+
+    // Изменение на 10% разрешено
+    let result = process(&mut banks_client, &payer, recent_blockhash, &[update_price_ix(1100)], &[&fixture.owner_account]).await;
+    assert!(result.is_ok(), "Update Price transaction failed: {:?}", result);
+    assert_eq!(get_store(&mut banks_client, &fixture.store_account.pubkey()).await.price, 1100);
+}
+
+#[tokio::test]
+async fn test_price_history() {
+    // Инициализация тестового окружения и магазина
+    let (mut banks_client, payer, recent_blockhash, fixture) = setup_store().await;
+    let (history_account, _) = find_history_address(&fixture.program_id, &fixture.store_account.pubkey());
+
+    // История на две записи
+    let init_history_ix = Instruction {
+        program_id: fixture.program_id,
+        accounts: vec![
+            AccountMeta::new(fixture.store_account.pubkey(), false),
+            AccountMeta::new_readonly(fixture.owner_account.pubkey(), true),
+            AccountMeta::new(history_account, false),
+            AccountMeta::new(payer.pubkey(), true),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+        data: vec![19, 2, 0],
+    };
+    process(&mut banks_client, &payer, recent_blockhash, &[init_history_ix], &[&fixture.owner_account]).await.unwrap();
+    assert_eq!(get_store(&mut banks_client, &fixture.store_account.pubkey()).await.history, history_account);
+
+    let update_price_ix = |price: u64| {
+        let mut data = vec![1];
+        data.extend_from_slice(&price.to_le_bytes());
+        Instruction {
+            program_id: fixture.program_id,
+            accounts: vec![
+                AccountMeta::new(fixture.store_account.pubkey(), false),
+                AccountMeta::new_readonly(fixture.owner_account.pubkey(), true),
+                AccountMeta::new(history_account, false),
+            ],
+            data,
+        }
+    };
+
+    // Три изменения цены, старейшее вытесняется из буфера
+    for price in [1100, 1200, 1300] {
+        process(&mut banks_client, &payer, recent_blockhash, &[update_price_ix(price)], &[&fixture.owner_account])
+            .await
+            .unwrap();
+    }
+
+    let account = banks_client.get_account(history_account).await.unwrap().unwrap();
+    let observations = PriceHistory::observations(&account.data).unwrap();
+    assert_eq!(observations.iter().map(|o| o.price).collect::<Vec<_>>(), vec![1200, 1300]);
+    assert!(observations.iter().all(|o| o.volume == 0));
+
+    // Покупка по котировке попадает в историю по цене котировки
+    let (buyer, buyer_token_account) = setup_buyer(&mut banks_client, &payer, recent_blockhash, &fixture).await;
+    let quote = Quote {
+        store: fixture.store_account.pubkey(),
+        pricer: fixture.owner_account.pubkey(),
+        price: 900,
+        quantity: 10,
+        expiry: i64::MAX,
+        nonce: 1,
+    };
+    let mut buy_with_quote_ix = buy_ix(&fixture, &buyer.pubkey(), &buyer_token_account.pubkey(), 10, 9_000, 0);
+    buy_with_quote_ix.data.push(BUY_QUOTE);
+    buy_with_quote_ix.data.extend_from_slice(&quote.pack_terms());
+    buy_with_quote_ix.accounts.push(AccountMeta::new_readonly(solana_program::sysvar::instructions::id(), false));
+    buy_with_quote_ix.accounts.push(AccountMeta::new(
+        find_quote_nonce_address(&fixture.program_id, &fixture.store_account.pubkey(), 1).0,
+        false,
+    ));
+    buy_with_quote_ix.accounts.push(AccountMeta::new(history_account, false));
+    let result = process(
+        &mut banks_client,
+        &payer,
+        recent_blockhash,
+        &[deposit_ix(&fixture, 10), ed25519_ix(&fixture.owner_account, &quote.message()), buy_with_quote_ix],
+        &[&fixture.owner_account, &buyer],
+    )
+    .await;
+    assert!(result.is_ok(), "Buy transaction failed: {:?}", result);
+
+    let account = banks_client.get_account(history_account).await.unwrap().unwrap();
+    let observations = PriceHistory::observations(&account.data).unwrap();
+    assert_eq!(observations.iter().map(|o| (o.price, o.volume)).collect::<Vec<_>>(), vec![(1300, 0), (900, 10)]);
+}
+
+#[test]
+fn test_twap() {
+    // Цена 1000 в течение 30 секунд, затем 2000 в течение 10 секунд
+    let mut store = Store {
+        price: 1000,
+        last_observed: 100,
+        ..Store::default()
+    };
+    let start = store.observe(100);
+    store.accumulate(130);
+    store.price = 2000;
+    let end = store.observe(140);
+
+    assert_eq!(end.cumulative_price, 1000 * 30 + 2000 * 10);
+    assert_eq!(twap(&start, &end), Some(1250));
+
+    // Наблюдения должны идти по возрастанию времени
+    assert_eq!(twap(&end, &start), None);
+    assert_eq!(twap(&end, &end), None);
+}